@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users ALTER COLUMN pass TYPE VARCHAR(64);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE users ALTER COLUMN pass TYPE VARCHAR;
\ No newline at end of file
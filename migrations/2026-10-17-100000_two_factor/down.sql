@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE recovery_codes;
+
+ALTER TABLE users DROP COLUMN totp_enabled;
+ALTER TABLE users DROP COLUMN totp_secret;
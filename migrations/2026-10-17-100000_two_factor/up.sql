@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN totp_secret VARCHAR;
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN DEFAULT FALSE NOT NULL;
+
+CREATE TABLE recovery_codes(
+    id VARCHAR(36) PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4 (),
+    user_id VARCHAR REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    code_hash VARCHAR NOT NULL
+);
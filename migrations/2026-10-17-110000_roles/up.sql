@@ -0,0 +1,59 @@
+-- Your SQL goes here
+CREATE TABLE roles(
+    name VARCHAR(32) PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE permissions(
+    name VARCHAR(64) PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE role_permissions(
+    role VARCHAR(32) REFERENCES roles(name) ON DELETE CASCADE NOT NULL,
+    permission VARCHAR(64) REFERENCES permissions(name) ON DELETE CASCADE NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO roles (name) VALUES ('reader'), ('author'), ('moderator'), ('admin');
+
+INSERT INTO permissions (name) VALUES
+    ('blogs.create'),
+    ('blogs.edit_own'),
+    ('blogs.edit_any'),
+    ('blogs.delete_own'),
+    ('blogs.delete_any'),
+    ('blogs.like'),
+    ('comments.create'),
+    ('comments.delete_own'),
+    ('comments.delete_any'),
+    ('users.delete_own'),
+    ('users.manage');
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('reader', 'blogs.like'),
+    ('reader', 'comments.create'),
+    ('reader', 'comments.delete_own'),
+    ('reader', 'users.delete_own'),
+    ('author', 'blogs.create'),
+    ('author', 'blogs.edit_own'),
+    ('author', 'blogs.delete_own'),
+    ('author', 'blogs.like'),
+    ('author', 'comments.create'),
+    ('author', 'comments.delete_own'),
+    ('author', 'users.delete_own'),
+    ('moderator', 'blogs.create'),
+    ('moderator', 'blogs.edit_own'),
+    ('moderator', 'blogs.delete_own'),
+    ('moderator', 'blogs.delete_any'),
+    ('moderator', 'blogs.like'),
+    ('moderator', 'comments.create'),
+    ('moderator', 'comments.delete_own'),
+    ('moderator', 'comments.delete_any'),
+    ('moderator', 'users.delete_own');
+
+INSERT INTO role_permissions (role, permission) SELECT 'admin', name FROM permissions;
+
+-- Every existing account could publish blogs, so they keep doing so as authors.
+-- `is_admin` was set on every account ever created, so it doesn't tell who administers the site.
+-- Nobody is an administrator after this migration, promote the real ones with `blogsite admin promote <username>`
+ALTER TABLE users ADD COLUMN role VARCHAR(32) REFERENCES roles(name) DEFAULT 'author' NOT NULL;
+ALTER TABLE users DROP COLUMN is_admin;
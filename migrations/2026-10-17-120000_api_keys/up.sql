@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE api_keys(
+    id VARCHAR(36) PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4 (),
+    user_id VARCHAR REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    name VARCHAR NOT NULL,
+    prefix VARCHAR(16) NOT NULL,
+    key_hash VARCHAR(64) UNIQUE NOT NULL,
+    scopes TEXT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ
+);
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE oidc_identities(
+    id VARCHAR(36) PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4 (),
+    user_id VARCHAR REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    issuer VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (issuer, subject)
+);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE auth_events(
+    id VARCHAR(36) PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4 (),
+    user_id VARCHAR,
+    username VARCHAR,
+    event VARCHAR(32) NOT NULL,
+    ip VARCHAR,
+    user_agent VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX auth_events_user_id_created_at ON auth_events(user_id, created_at);
+CREATE INDEX auth_events_created_at ON auth_events(created_at);
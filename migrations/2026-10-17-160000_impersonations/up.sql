@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE impersonations(
+    id VARCHAR(36) PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4 (),
+    admin_id VARCHAR REFERENCES users(id) ON DELETE SET NULL,
+    admin_username VARCHAR NOT NULL,
+    user_id VARCHAR REFERENCES users(id) ON DELETE SET NULL,
+    username VARCHAR NOT NULL,
+    reason TEXT,
+    ip VARCHAR,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ended_at TIMESTAMPTZ
+);
+CREATE INDEX impersonations_started_at ON impersonations(started_at);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE remembered_devices(
+    id VARCHAR(36) PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4 (),
+    user_id VARCHAR REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    selector VARCHAR(24) UNIQUE NOT NULL,
+    validator_hash VARCHAR(64) NOT NULL,
+    ip VARCHAR,
+    user_agent VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX remembered_devices_user_id ON remembered_devices(user_id);
@@ -0,0 +1,457 @@
+use dotenv::dotenv;
+
+use crate::auth::cookie::CookieConfig;
+use rand::distributions::{Alphanumeric, DistString};
+use std::{env, path::PathBuf, str::FromStr};
+
+/// Reads an enviroment variable and parses it, falling back to `default` if it's not set.
+/// Panics if the variable is set but can't be parsed, a typo in the config shouldn't go unnoticed
+pub(crate) fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<T>()
+            .unwrap_or_else(|_| panic!("Enviroment variable: '{}' has an invalid value", name)),
+        Err(_) => default,
+    }
+}
+
+/// Reads a secret which has to stay the same across restarts and server instances, panics if it's not set.
+/// Tests get a random secret instead
+fn required_secret(name: &str) -> String {
+    match env::var(name) {
+        Ok(value) => value,
+        Err(_) if cfg!(test) => Alphanumeric.sample_string(&mut rand::thread_rng(), 48),
+        Err(_) => panic!("Enviroment variable: '{}' must be set", name),
+    }
+}
+
+/** Kind of login token issued by `login` */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionMode {
+    /// Random tokens looked up in the token store on every request
+    Opaque,
+    /// Signed tokens carrying the user id, role and expiry, verified without the token store
+    Signed,
+}
+
+impl FromStr for SessionMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "opaque" => Ok(SessionMode::Opaque),
+            "signed" => Ok(SessionMode::Signed),
+            _ => Err(()),
+        }
+    }
+}
+
+/** Settings controlling how long login sessions live */
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Seconds a session stays valid after it was created or last refreshed (`SESSION_TTL`)
+    pub ttl: i64,
+    /// Seconds after creation after which a login token can't be refreshed anymore (`SESSION_MAX_AGE`).
+    /// Past it the client needs the refresh token, whose family lives for `REFRESH_TOKEN_TTL`, or has to log in again
+    pub max_age: i64,
+    /// Whether every request authenticated with the `token` cookie refreshes the session (`SESSION_SLIDING`).
+    /// Login tokens sent in the `Authorization` header are only replaced through their refresh token
+    pub sliding: bool,
+    /// Seconds a refresh token family lives after login (`REFRESH_TOKEN_TTL`)
+    pub refresh_ttl: i64,
+    /// Seconds a device stays remembered after a login with `remember_me` (`REMEMBER_ME_TTL`)
+    pub remember_ttl: i64,
+    /// Most seconds an expiring API key may be valid for (`API_KEY_MAX_TTL`), at most 100 years
+    pub api_key_max_ttl: i64,
+    /// Kind of login token issued on login and refresh (`SESSION_MODE`)
+    pub mode: SessionMode,
+    /// Secret used to sign and verify signed tokens (`SESSION_SIGNING_KEY`), required in signed mode.
+    /// Signed tokens are accepted whenever a key is set, so switching modes doesn't log everyone out
+    pub signing_key: Option<String>,
+    /// Seconds between reloads of the signed token denylist (`SESSION_DENYLIST_SYNC`)
+    pub denylist_sync: u64,
+    /// Secret of the keyed hash login and refresh tokens are stored under (`TOKEN_HASH_KEY`), it has to be set.
+    /// Changing it logs everyone out
+    pub token_hash_key: String,
+    /// Attributes of the login cookies
+    pub cookie: CookieConfig,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl: 1800,
+            max_age: 60 * 60 * 24 * 7,
+            sliding: true,
+            refresh_ttl: 60 * 60 * 24 * 30,
+            remember_ttl: 60 * 60 * 24 * 90,
+            api_key_max_ttl: 60 * 60 * 24 * 365,
+            mode: SessionMode::Opaque,
+            signing_key: None,
+            denylist_sync: 5,
+            token_hash_key: Alphanumeric.sample_string(&mut rand::thread_rng(), 48),
+            cookie: CookieConfig::default(),
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Loads the session settings from the enviroment, unset variables use the [default](SessionConfig::default) values
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let default = SessionConfig::default();
+
+        let config = SessionConfig {
+            ttl: env_or("SESSION_TTL", default.ttl),
+            max_age: env_or("SESSION_MAX_AGE", default.max_age),
+            sliding: env_or("SESSION_SLIDING", default.sliding),
+            refresh_ttl: env_or("REFRESH_TOKEN_TTL", default.refresh_ttl),
+            remember_ttl: env_or("REMEMBER_ME_TTL", default.remember_ttl),
+            api_key_max_ttl: env_or("API_KEY_MAX_TTL", default.api_key_max_ttl),
+            mode: env_or("SESSION_MODE", default.mode),
+            signing_key: env::var("SESSION_SIGNING_KEY").ok(),
+            denylist_sync: env_or("SESSION_DENYLIST_SYNC", default.denylist_sync),
+            token_hash_key: required_secret("TOKEN_HASH_KEY"),
+            cookie: CookieConfig::from_env(),
+        };
+        if config.ttl <= 0 || config.max_age < config.ttl {
+            panic!("'SESSION_TTL' must be positive and not larger than 'SESSION_MAX_AGE'");
+        }
+        if config.refresh_ttl <= 0 {
+            panic!("'REFRESH_TOKEN_TTL' must be positive");
+        }
+        if config.remember_ttl <= 0 {
+            panic!("'REMEMBER_ME_TTL' must be positive");
+        }
+        if config.api_key_max_ttl <= 0 || config.api_key_max_ttl > 60 * 60 * 24 * 365 * 100 {
+            panic!("'API_KEY_MAX_TTL' must be positive and at most 100 years");
+        }
+        if config.token_hash_key.len() < 32 {
+            panic!("'TOKEN_HASH_KEY' must be at least 32 characters long");
+        }
+        match &config.signing_key {
+            Some(key) if key.len() < 32 => {
+                panic!("'SESSION_SIGNING_KEY' must be at least 32 characters long")
+            }
+            None if config.mode == SessionMode::Signed => {
+                panic!("'SESSION_SIGNING_KEY' must be set when 'SESSION_MODE' is 'signed'")
+            }
+            _ => {}
+        }
+
+        config
+    }
+
+    /** Returns for how many seconds a session created at `created_at` (unix timestamp) may still live
+     * after being refreshed at `now`, `0` means the session reached its maximum age.
+     * The stored session and the `token` cookie both expire after it, so the cookie never outlives the session
+     */
+    pub fn remaining_ttl(&self, created_at: i64, now: i64) -> i64 {
+        let until_max_age = created_at + self.max_age - now;
+
+        self.ttl.min(until_max_age).max(0)
+    }
+}
+
+/** Settings of the login brute-force protection */
+#[derive(Debug, Clone)]
+pub struct ThrottleConfig {
+    /// Failed logins of an username after which it's locked (`LOGIN_MAX_FAILURES`)
+    pub max_failures: i64,
+    /// Failed logins from an IP address after which it's locked (`LOGIN_MAX_IP_FAILURES`)
+    pub max_ip_failures: i64,
+    /// Seconds of the first lockout, every further failure doubles it (`LOGIN_LOCKOUT`)
+    pub lockout: i64,
+    /// Upper limit of a lockout in seconds (`LOGIN_MAX_LOCKOUT`)
+    pub max_lockout: i64,
+    /// Seconds without a failure after which the failures are forgotten (`LOGIN_FAILURE_WINDOW`)
+    pub window: i64,
+    /// Whether the server runs behind a reverse proxy whose `Forwarded`/`X-Forwarded-For` headers
+    /// tell the client address (`TRUSTED_PROXY`). Only enable it if clients can't reach the server directly
+    pub trusted_proxy: bool,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            max_failures: 5,
+            max_ip_failures: 20,
+            lockout: 30,
+            max_lockout: 60 * 60,
+            window: 60 * 15,
+            trusted_proxy: false,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    /// Loads the throttling settings from the enviroment, unset variables use the [default](ThrottleConfig::default) values
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let default = ThrottleConfig::default();
+
+        let config = ThrottleConfig {
+            max_failures: env_or("LOGIN_MAX_FAILURES", default.max_failures),
+            max_ip_failures: env_or("LOGIN_MAX_IP_FAILURES", default.max_ip_failures),
+            lockout: env_or("LOGIN_LOCKOUT", default.lockout),
+            max_lockout: env_or("LOGIN_MAX_LOCKOUT", default.max_lockout),
+            window: env_or("LOGIN_FAILURE_WINDOW", default.window),
+            trusted_proxy: env_or("TRUSTED_PROXY", default.trusted_proxy),
+        };
+        if config.max_failures <= 0 || config.max_ip_failures <= 0 {
+            panic!("'LOGIN_MAX_FAILURES' and 'LOGIN_MAX_IP_FAILURES' must be positive");
+        }
+        if config.lockout <= 0 || config.max_lockout < config.lockout || config.window <= 0 {
+            panic!("'LOGIN_LOCKOUT' and 'LOGIN_FAILURE_WINDOW' must be positive and 'LOGIN_MAX_LOCKOUT' not smaller than 'LOGIN_LOCKOUT'");
+        }
+
+        config
+    }
+
+    /** Returns how many seconds a key is locked for after `failures` failed attempts, `0` if it isn't locked */
+    pub fn lockout_for(&self, failures: i64, max_failures: i64) -> i64 {
+        if failures < max_failures {
+            return 0;
+        }
+        //Capping the exponent first keeps the shift from overflowing
+        let doublings = (failures - max_failures).min(32);
+
+        self.lockout
+            .saturating_mul(1i64 << doublings)
+            .min(self.max_lockout)
+    }
+}
+
+/** Rules new passwords have to follow, see [check_password](crate::auth::password_policy::check_password) */
+#[derive(Debug, Clone)]
+pub struct PasswordConfig {
+    /// Fewest characters of a password, surrounding whitespace doesn't count (`PASSWORD_MIN_LENGTH`)
+    pub min_length: usize,
+    /// Most characters of a password, hashing very long passwords is expensive (`PASSWORD_MAX_LENGTH`)
+    pub max_length: usize,
+    /// Reject passwords containing the username, ignoring case and usernames shorter than 4 characters (`PASSWORD_DISALLOW_USERNAME`)
+    pub disallow_username: bool,
+    /// Breached password list in the k-anonymity format of Pwned Passwords, not checked if unset (`PASSWORD_BREACHED_LIST`).
+    /// A directory holding a file per 5 character SHA1 prefix, named after the prefix and listing `SUFFIX:COUNT` lines,
+    /// so a check only reads one small file
+    pub breached_list: Option<PathBuf>,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        PasswordConfig {
+            min_length: 10,
+            max_length: 128,
+            disallow_username: true,
+            breached_list: None,
+        }
+    }
+}
+
+impl PasswordConfig {
+    /// Loads the password rules from the enviroment, unset variables use the [default](PasswordConfig::default) values
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let default = PasswordConfig::default();
+
+        let config = PasswordConfig {
+            min_length: env_or("PASSWORD_MIN_LENGTH", default.min_length),
+            max_length: env_or("PASSWORD_MAX_LENGTH", default.max_length),
+            disallow_username: env_or("PASSWORD_DISALLOW_USERNAME", default.disallow_username),
+            breached_list: env::var("PASSWORD_BREACHED_LIST").ok().map(PathBuf::from),
+        };
+        if config.min_length == 0 || config.max_length < config.min_length {
+            panic!(
+                "'PASSWORD_MIN_LENGTH' must be positive and not larger than 'PASSWORD_MAX_LENGTH'"
+            );
+        }
+        if let Some(path) = &config.breached_list {
+            if !path.is_dir() {
+                panic!("'PASSWORD_BREACHED_LIST' must be a directory of hash prefix files");
+            }
+        }
+
+        config
+    }
+}
+
+/** Settings of two-factor authentication */
+#[derive(Clone)]
+pub struct TwoFactorConfig {
+    /// Secret the TOTP secrets of the users are encrypted with in the database (`TOTP_ENCRYPTION_KEY`).
+    /// Changing it makes the stored secrets unusable, users can then only log in with their recovery codes
+    pub encryption_key: String,
+}
+
+impl std::fmt::Debug for TwoFactorConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TwoFactorConfig").finish_non_exhaustive()
+    }
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig {
+            encryption_key: Alphanumeric.sample_string(&mut rand::thread_rng(), 48),
+        }
+    }
+}
+
+impl TwoFactorConfig {
+    /// Loads the two-factor settings from the enviroment, panics if the key isn't set outside of tests
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let config = TwoFactorConfig {
+            encryption_key: required_secret("TOTP_ENCRYPTION_KEY"),
+        };
+        if config.encryption_key.len() < 32 {
+            panic!("'TOTP_ENCRYPTION_KEY' must be at least 32 characters long");
+        }
+
+        config
+    }
+}
+
+/** Settings of the login through an external OpenID Connect identity provider */
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// Issuer url of the provider, its metadata is discovered below `/.well-known/openid-configuration` (`OIDC_ISSUER`)
+    pub issuer: String,
+    /// Id of this site at the provider (`OIDC_CLIENT_ID`)
+    pub client_id: String,
+    /// Secret of this site at the provider (`OIDC_CLIENT_SECRET`)
+    pub client_secret: String,
+    /// Url of the [callback](crate::routes::oidc::oidc_callback) route registered at the provider (`OIDC_REDIRECT_URI`)
+    pub redirect_uri: String,
+    /// Whether ID tokens signed with the client secret (HS256) are accepted, otherwise only RS256 tokens
+    /// signed with a key of the provider are (`OIDC_ALLOW_HS256`)
+    pub allow_hs256: bool,
+}
+
+impl std::fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("allow_hs256", &self.allow_hs256)
+            .finish()
+    }
+}
+
+impl OidcConfig {
+    /// Loads the provider settings from the enviroment, returns `None` if `OIDC_ISSUER` isn't set.
+    /// Panics if the issuer is set without the other variables
+    pub fn from_env() -> Option<Self> {
+        dotenv().ok();
+        let issuer = env::var("OIDC_ISSUER").ok()?;
+        let required = |name: &str| {
+            env::var(name).unwrap_or_else(|_| {
+                panic!(
+                    "Enviroment variable: '{}' must be set when 'OIDC_ISSUER' is set",
+                    name
+                )
+            })
+        };
+
+        Some(OidcConfig {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id: required("OIDC_CLIENT_ID"),
+            client_secret: required("OIDC_CLIENT_SECRET"),
+            redirect_uri: required("OIDC_REDIRECT_URI"),
+            allow_hs256: env_or("OIDC_ALLOW_HS256", false),
+        })
+    }
+}
+
+/** Settings of email address verification */
+#[derive(Clone)]
+pub struct EmailConfig {
+    /// Secret signing the verification links (`EMAIL_SIGNING_KEY`), it has to be set.
+    /// Changing it invalidates the links sent before
+    pub signing_key: String,
+    /// Url the site is reached at, the verification links point there (`APP_BASE_URL`)
+    pub base_url: String,
+    /// Seconds a verification link is valid for (`EMAIL_LINK_TTL`)
+    pub link_ttl: i64,
+    /// Whether users have to verify their email address before publishing blogs or comments (`REQUIRE_VERIFIED_EMAIL`)
+    pub require_verified: bool,
+}
+
+impl std::fmt::Debug for EmailConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmailConfig")
+            .field("base_url", &self.base_url)
+            .field("link_ttl", &self.link_ttl)
+            .field("require_verified", &self.require_verified)
+            .finish()
+    }
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        EmailConfig {
+            signing_key: Alphanumeric.sample_string(&mut rand::thread_rng(), 48),
+            base_url: "http://127.0.0.1:8080".to_string(),
+            link_ttl: 60 * 60 * 24,
+            require_verified: false,
+        }
+    }
+}
+
+impl EmailConfig {
+    /// Loads the email settings from the enviroment, unset variables use the [default](EmailConfig::default) values
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let default = EmailConfig::default();
+
+        let config = EmailConfig {
+            signing_key: required_secret("EMAIL_SIGNING_KEY"),
+            base_url: env_or("APP_BASE_URL", default.base_url)
+                .trim_end_matches('/')
+                .to_string(),
+            link_ttl: env_or("EMAIL_LINK_TTL", default.link_ttl),
+            require_verified: env_or("REQUIRE_VERIFIED_EMAIL", default.require_verified),
+        };
+        if config.signing_key.len() < 32 {
+            panic!("'EMAIL_SIGNING_KEY' must be at least 32 characters long");
+        }
+        if config.link_ttl <= 0 {
+            panic!("'EMAIL_LINK_TTL' must be positive");
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_ttl_capped_by_max_age() {
+        let config = SessionConfig {
+            ttl: 600,
+            max_age: 1000,
+            sliding: true,
+            refresh_ttl: 5000,
+            ..SessionConfig::default()
+        };
+
+        debug_assert!(config.remaining_ttl(0, 0) == 600);
+        debug_assert!(config.remaining_ttl(0, 700) == 300);
+        debug_assert!(config.remaining_ttl(0, 1200) == 0);
+    }
+
+    #[test]
+    fn test_lockout_backoff() {
+        let config = ThrottleConfig::default();
+
+        debug_assert!(config.lockout_for(4, 5) == 0);
+        debug_assert!(config.lockout_for(5, 5) == 30);
+        debug_assert!(config.lockout_for(7, 5) == 120);
+        debug_assert!(config.lockout_for(500, 5) == 3600);
+    }
+}
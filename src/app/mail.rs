@@ -0,0 +1,133 @@
+use chrono::Utc;
+use dotenv::dotenv;
+use rand::distributions::{Alphanumeric, DistString};
+use std::{env, fs, path::PathBuf, sync::Arc};
+
+use crate::app::AppError;
+
+/** A message sent to an user */
+#[derive(Debug, Clone)]
+pub struct Mail {
+    /// Address of the recipient
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Transport delivering [mails](Mail) to users
+pub trait Mailer: Send + Sync + std::fmt::Debug {
+    fn send(&self, mail: &Mail) -> Result<(), AppError>;
+}
+
+/// [Mailer] writing every message into its own file inside a directory, for local development.
+/// Messages carry login and password reset links, so they are never printed to the server output
+#[derive(Debug)]
+pub struct OutboxMailer {
+    dir: PathBuf,
+}
+
+impl OutboxMailer {
+    /** Creates the mailer, the directory is created if it doesn't exist */
+    pub fn new(dir: PathBuf) -> Result<Self, AppError> {
+        fs::create_dir_all(&dir)?;
+        Ok(OutboxMailer { dir })
+    }
+}
+
+impl Mailer for OutboxMailer {
+    fn send(&self, mail: &Mail) -> Result<(), AppError> {
+        let name = format!(
+            "{}-{}.eml",
+            Utc::now().timestamp_millis(),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        );
+        fs::write(
+            self.dir.join(name),
+            format!(
+                "To: {}\nSubject: {}\n\n{}\n",
+                mail.to, mail.subject, mail.body
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// [Mailer] keeping every message in memory so tests can read them
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryMailer {
+    pub sent: std::sync::Mutex<Vec<Mail>>,
+}
+
+#[cfg(test)]
+impl Mailer for MemoryMailer {
+    fn send(&self, mail: &Mail) -> Result<(), AppError> {
+        self.sent
+            .lock()
+            .map_err(|_| AppError::InternalServerError)?
+            .push(mail.clone());
+        Ok(())
+    }
+}
+
+/// Return the mailer selected by the `MAIL_TRANSPORT` variable in enviroment.
+/// `outbox` (the default) writes the messages into the `MAIL_OUTBOX_DIR` directory (`outbox` by default).
+/// Tests keep them in memory unless a transport is set
+/// # Example
+/// ```
+/// let mailer: Arc<dyn Mailer> = mailer_connect();
+/// ```
+pub fn mailer_connect() -> Arc<dyn Mailer> {
+    dotenv().ok();
+
+    let transport = env::var("MAIL_TRANSPORT");
+    #[cfg(test)]
+    if transport.is_err() {
+        return Arc::new(MemoryMailer::default());
+    }
+
+    match transport.as_deref() {
+        Ok("outbox") | Err(_) => {
+            let dir = env::var("MAIL_OUTBOX_DIR").unwrap_or_else(|_| "outbox".to_string());
+            Arc::new(
+                OutboxMailer::new(PathBuf::from(&dir)).unwrap_or_else(|_| {
+                    panic!("Couldn't create the mail outbox directory '{}'", dir)
+                }),
+            )
+        }
+        Ok(other) => panic!(
+            "Enviroment variable: 'MAIL_TRANSPORT' has unknown value '{}'",
+            other
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbox_mailer() {
+        let dir = env::temp_dir().join(format!(
+            "blogsite-outbox-{}",
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        ));
+        let mailer = OutboxMailer::new(dir.clone()).unwrap();
+
+        mailer
+            .send(&Mail {
+                to: "test_user".to_string(),
+                subject: "Test subject".to_string(),
+                body: "Test body".to_string(),
+            })
+            .unwrap();
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        debug_assert!(files.len() == 1);
+        let content = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        debug_assert!(content.contains("Subject: Test subject"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
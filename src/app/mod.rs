@@ -1,24 +1,52 @@
+pub mod config;
+pub mod mail;
+
 use actix_web::{HttpResponse, ResponseError};
 use diesel::{
     r2d2::{ConnectionManager, Pool},
     PgConnection,
 };
-use r2d2_redis::{redis::RedisError, RedisConnectionManager};
+use r2d2_redis::redis::RedisError;
 use std::{fmt::Display, num::ParseIntError, sync::Arc};
 
-use crate::database::db_utils::{psql_connect_to_db, redis_connect_to_db};
+use crate::{
+    auth::{password_policy::PasswordRejection, signed::Denylist, store::TokenStore},
+    database::db_utils::{psql_connect_to_db, token_store_connect},
+};
+use config::{
+    EmailConfig, OidcConfig, PasswordConfig, SessionConfig, ThrottleConfig, TwoFactorConfig,
+};
+use mail::{mailer_connect, Mailer};
 
 /** Used for storing the database connections when handling requests */
 pub struct AppState {
     pub psql_pool: Arc<Pool<ConnectionManager<PgConnection>>>,
-    pub redis_pool: Arc<Pool<RedisConnectionManager>>,
+    pub token_store: Arc<dyn TokenStore>,
+    pub mailer: Arc<dyn Mailer>,
+    pub session_config: SessionConfig,
+    pub throttle_config: ThrottleConfig,
+    pub password_config: PasswordConfig,
+    pub two_factor_config: TwoFactorConfig,
+    /// Revoked signed tokens, shared by all workers
+    pub denylist: Arc<Denylist>,
+    /// External identity provider, login through it is disabled if `None`
+    pub oidc_config: Option<OidcConfig>,
+    pub email_config: EmailConfig,
 }
 
 impl Clone for AppState {
     fn clone(&self) -> Self {
         Self {
             psql_pool: self.psql_pool.clone(),
-            redis_pool: self.redis_pool.clone(),
+            token_store: self.token_store.clone(),
+            mailer: self.mailer.clone(),
+            session_config: self.session_config.clone(),
+            throttle_config: self.throttle_config.clone(),
+            password_config: self.password_config.clone(),
+            two_factor_config: self.two_factor_config.clone(),
+            denylist: self.denylist.clone(),
+            oidc_config: self.oidc_config.clone(),
+            email_config: self.email_config.clone(),
         }
     }
 }
@@ -26,16 +54,39 @@ impl std::fmt::Debug for AppState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AppState")
             .field("psql_pool", &self.psql_pool.state())
-            .field("redis_pool", &self.redis_pool.state())
+            .field("token_store", &self.token_store)
+            .field("mailer", &self.mailer)
+            .field("session_config", &self.session_config)
+            .field("throttle_config", &self.throttle_config)
+            .field("password_config", &self.password_config)
+            .field("two_factor_config", &self.two_factor_config)
+            .field("denylist", &self.denylist)
+            .field("oidc_config", &self.oidc_config)
+            .field("email_config", &self.email_config)
             .finish()
     }
 }
 
 impl AppState {
     pub fn new(cons: Option<u32>) -> Self {
+        AppState::with_token_store(cons, token_store_connect(cons))
+    }
+
+    /** Creates the state with the given token store instead of the one configured in the enviroment */
+    pub fn with_token_store(cons: Option<u32>, token_store: Arc<dyn TokenStore>) -> Self {
+        let session_config = SessionConfig::from_env();
+
         AppState {
             psql_pool: psql_connect_to_db(cons),
-            redis_pool: redis_connect_to_db(cons),
+            token_store,
+            mailer: mailer_connect(),
+            denylist: Arc::new(Denylist::new(session_config.denylist_sync)),
+            session_config,
+            throttle_config: ThrottleConfig::from_env(),
+            password_config: PasswordConfig::from_env(),
+            two_factor_config: TwoFactorConfig::from_env(),
+            oidc_config: OidcConfig::from_env(),
+            email_config: EmailConfig::from_env(),
         }
     }
 }
@@ -48,6 +99,10 @@ pub enum AppError {
     InternalServerError,
     BadRequest,
     Forbidden,
+    /// Too many attempts, holds the seconds after which the client may retry
+    TooManyRequests(i64),
+    /// A new password broke the [password policy](crate::auth::password_policy), holds every broken rule
+    PasswordRejected(Vec<PasswordRejection>),
 }
 
 impl Display for AppError {
@@ -57,6 +112,8 @@ impl Display for AppError {
             AppError::InternalServerError => f.write_str("Internal server error"),
             AppError::BadRequest => f.write_str("Bad request"),
             AppError::Forbidden => f.write_str("Forbidden"),
+            AppError::TooManyRequests(_) => f.write_str("Too many requests"),
+            AppError::PasswordRejected(_) => f.write_str("Password rejected"),
         }
     }
 }
@@ -68,11 +125,27 @@ impl ResponseError for AppError {
             AppError::InternalServerError => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::BadRequest => actix_web::http::StatusCode::BAD_REQUEST,
             AppError::Forbidden => actix_web::http::StatusCode::FORBIDDEN,
+            AppError::TooManyRequests(_) => actix_web::http::StatusCode::TOO_MANY_REQUESTS,
+            AppError::PasswordRejected(_) => actix_web::http::StatusCode::BAD_REQUEST,
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse {
-        HttpResponse::new(self.status_code())
+        match self {
+            AppError::TooManyRequests(retry_after) => HttpResponse::build(self.status_code())
+                .insert_header((
+                    actix_web::http::header::RETRY_AFTER,
+                    retry_after.max(&1).to_string(),
+                ))
+                .finish(),
+            AppError::PasswordRejected(rejections) => {
+                HttpResponse::build(self.status_code()).json(serde_json::json!({
+                    "error": "password_rejected",
+                    "reasons": rejections,
+                }))
+            }
+            _ => HttpResponse::new(self.status_code()),
+        }
     }
 }
 impl From<diesel::result::Error> for AppError {
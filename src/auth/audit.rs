@@ -0,0 +1,39 @@
+use actix_web::{http::header, HttpRequest};
+
+use crate::{
+    app::AppState,
+    auth::throttle::LoginThrottle,
+    database::models::auth_event::{AuthEvent, AuthEventInsert, AuthEventKind},
+};
+
+/// Longest user agent stored, clients control the header
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/** Returns the `User-Agent` header of the request, cut to a sane length */
+pub fn user_agent(req: &HttpRequest) -> Option<String> {
+    let agent = req.headers().get(header::USER_AGENT)?.to_str().ok()?;
+    Some(agent.chars().take(MAX_USER_AGENT_LENGTH).collect())
+}
+
+/// Records an authentication event with the address and user agent of the request,
+/// events without a request, like the ones of the admin command, have neither.
+/// The event is only a record, failing to store it doesn't fail the action it describes
+pub fn record(
+    app_state: &AppState,
+    req: Option<&HttpRequest>,
+    kind: AuthEventKind,
+    user_id: Option<&String>,
+    username: Option<&String>,
+) {
+    let record = AuthEventInsert {
+        user_id: user_id.cloned(),
+        username: username.cloned(),
+        event: kind.as_str().to_string(),
+        ip: req.map(LoginThrottle::client_ip),
+        user_agent: req.and_then(user_agent),
+    };
+
+    if let Ok(conn) = app_state.psql_pool.get() {
+        let _res = AuthEvent::new(&conn, &record);
+    }
+}
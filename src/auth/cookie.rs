@@ -0,0 +1,197 @@
+use actix_web::cookie::{
+    time::{Duration, OffsetDateTime},
+    Cookie, CookieBuilder, Expiration, SameSite,
+};
+use chrono::Utc;
+use rand::distributions::{Alphanumeric, DistString};
+use std::env;
+
+use crate::{app::config::env_or, auth::csrf::CSRF_COOKIE};
+
+/// Name of the refresh token cookie, it's only sent to the `/api` routes
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Name of the cookie holding the credential of a [remembered device](crate::auth::remember::RememberMe)
+pub const REMEMBER_COOKIE: &str = "remember_me";
+/// Name of the cookie tying an [external login](crate::routes::oidc::oidc_login) to the browser it was started in
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+/** Attributes of the cookies set on login, shared by every route setting or clearing them */
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    /// Name of the login token cookie without prefix (`SESSION_COOKIE_NAME`)
+    pub name: String,
+    /// Domain the cookies are sent to, only the current host if unset (`SESSION_COOKIE_DOMAIN`)
+    pub domain: Option<String>,
+    /// Only send the cookies over https (`SESSION_COOKIE_SECURE`)
+    pub secure: bool,
+    /// Hide the login token cookie from scripts (`SESSION_COOKIE_HTTP_ONLY`)
+    pub http_only: bool,
+    /// `strict`, `lax` or `none` (`SESSION_COOKIE_SAME_SITE`)
+    pub same_site: SameSite,
+    /// Prefix the login token cookie with `__Host-`, so it can't be set by subdomains (`SESSION_COOKIE_HOST_PREFIX`)
+    pub host_prefix: bool,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            name: "token".to_string(),
+            domain: None,
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+            host_prefix: false,
+        }
+    }
+}
+
+impl CookieConfig {
+    /// Loads the cookie settings from the enviroment, unset variables use the [default](CookieConfig::default) values
+    pub fn from_env() -> Self {
+        let default = CookieConfig::default();
+
+        let same_site = match env_or("SESSION_COOKIE_SAME_SITE", "lax".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            _ => panic!("Enviroment variable: 'SESSION_COOKIE_SAME_SITE' has an invalid value"),
+        };
+        let config = CookieConfig {
+            name: env_or("SESSION_COOKIE_NAME", default.name),
+            domain: env::var("SESSION_COOKIE_DOMAIN").ok(),
+            secure: env_or("SESSION_COOKIE_SECURE", default.secure),
+            http_only: env_or("SESSION_COOKIE_HTTP_ONLY", default.http_only),
+            same_site,
+            host_prefix: env_or("SESSION_COOKIE_HOST_PREFIX", default.host_prefix),
+        };
+        if config.host_prefix && (!config.secure || config.domain.is_some()) {
+            panic!("'SESSION_COOKIE_HOST_PREFIX' requires 'SESSION_COOKIE_SECURE' and no 'SESSION_COOKIE_DOMAIN'");
+        }
+        if config.same_site == SameSite::None && !config.secure {
+            panic!("'SESSION_COOKIE_SAME_SITE' 'none' requires 'SESSION_COOKIE_SECURE'");
+        }
+
+        config
+    }
+
+    /** Returns the name of the login token cookie, including the prefix */
+    pub fn name(&self) -> String {
+        match self.host_prefix {
+            true => format!("__Host-{}", self.name),
+            false => self.name.clone(),
+        }
+    }
+
+    fn build(&self, name: String, value: String, path: &'static str) -> CookieBuilder<'static> {
+        let mut builder = Cookie::build(name, value)
+            .path(path)
+            .secure(self.secure)
+            .same_site(self.same_site);
+        if let Some(domain) = &self.domain {
+            builder = builder.domain(domain.clone());
+        }
+
+        builder
+    }
+
+    /** Builds the login token cookie, `ttl` is the amount of seconds the client should keep it for */
+    pub fn session_cookie(&self, token: String, ttl: i64) -> Cookie<'static> {
+        self.build(self.name(), token, "/")
+            .http_only(self.http_only)
+            .max_age(Duration::seconds(ttl))
+            .expires(Expiration::DateTime(
+                OffsetDateTime::from_unix_timestamp(Utc::now().timestamp() + ttl).unwrap(),
+            ))
+            .finish()
+    }
+
+    /** Builds the refresh token cookie, it's only sent to the refresh and deauth routes */
+    pub fn refresh_cookie(&self, token: String, ttl: i64) -> Cookie<'static> {
+        self.build(REFRESH_COOKIE.to_string(), token, "/api")
+            .http_only(true)
+            .max_age(Duration::seconds(ttl))
+            .finish()
+    }
+
+    /** Builds the remembered device cookie, it's sent to every route so an expired session can be restored anywhere */
+    pub fn remember_cookie(&self, credential: String, ttl: i64) -> Cookie<'static> {
+        self.build(REMEMBER_COOKIE.to_string(), credential, "/")
+            .http_only(true)
+            .max_age(Duration::seconds(ttl))
+            .finish()
+    }
+
+    /** Builds the cookie holding the state of an external login, only sent to the login routes of the provider.
+     * It has to be `Lax`, the provider redirects the browser back from another site
+     */
+    pub fn oidc_state_cookie(&self, state: String, ttl: i64) -> Cookie<'static> {
+        self.build(OIDC_STATE_COOKIE.to_string(), state, "/user/oidc")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::seconds(ttl))
+            .finish()
+    }
+
+    /** Builds the CSRF cookie with a new random token, scripts have to be able to read it */
+    pub fn csrf_cookie(&self) -> Cookie<'static> {
+        self.build(
+            CSRF_COOKIE.to_string(),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+            "/",
+        )
+        .same_site(SameSite::Strict)
+        .finish()
+    }
+
+    /** Builds cookies clearing the login token, refresh token, CSRF and remembered device cookies.
+     * Browsers only clear a cookie if path and domain match the ones it was set with
+     */
+    pub fn removal_cookies(&self) -> Vec<Cookie<'static>> {
+        let mut cookies = vec![
+            self.session_cookie(String::new(), 0),
+            self.refresh_cookie(String::new(), 0),
+            self.csrf_cookie(),
+            self.remember_cookie(String::new(), 0),
+        ];
+        for cookie in cookies.iter_mut() {
+            cookie.make_removal();
+        }
+
+        cookies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_cookie_attributes() {
+        let config = CookieConfig {
+            host_prefix: true,
+            ..CookieConfig::default()
+        };
+
+        let header = config.session_cookie("abc".to_string(), 60).to_string();
+        debug_assert!(header.starts_with("__Host-token=abc"));
+        debug_assert!(header.contains("HttpOnly"));
+        debug_assert!(header.contains("Secure"));
+        debug_assert!(header.contains("SameSite=Lax"));
+        debug_assert!(header.contains("Path=/"));
+        debug_assert!(header.contains("Max-Age=60"));
+        debug_assert!(!header.contains("Domain"));
+
+        let config = CookieConfig {
+            domain: Some("example.com".to_string()),
+            ..CookieConfig::default()
+        };
+        let removal = config.removal_cookies()[0].to_string();
+        debug_assert!(removal.starts_with("token=;"));
+        debug_assert!(removal.contains("Domain=example.com"));
+        debug_assert!(removal.contains("Path=/"));
+        debug_assert!(removal.contains("Max-Age=0"));
+    }
+}
@@ -0,0 +1,187 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    web::Data,
+    Error, HttpRequest, ResponseError,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::{
+    app::{AppError, AppState},
+    auth::{
+        cookie::{CookieConfig, REFRESH_COOKIE, REMEMBER_COOKIE},
+        password::constant_time_eq,
+        token::Token,
+    },
+};
+
+/// Cookie holding the CSRF token, readable by scripts of the site so they can echo it
+pub const CSRF_COOKIE: &str = "csrf_token";
+/// Header cookie authenticated requests have to repeat the CSRF token in
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Paths checking the CSRF token themselves. The confirmation of a login link is a plain form, which can't set the header,
+/// so it compares the token in the form with the cookie instead
+const EXEMPT_PATHS: [&str; 1] = ["/user/login/link/confirm"];
+
+/** Returns whether the request has to carry a CSRF token: it changes state and the browser authenticated it with cookies.
+ * This includes `/api/refresh`, otherwise another site could make the browser rotate its refresh token
+ */
+fn requires_token(req: &HttpRequest) -> bool {
+    let mutating = matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::DELETE | Method::PATCH
+    );
+    let session_cookie = match req.app_data::<Data<AppState>>() {
+        Some(app_state) => app_state.session_config.cookie.name(),
+        None => CookieConfig::default().name(),
+    };
+    //A remembered device cookie alone restores a session, so it authenticates the request as well
+    let cookie_authenticated = Token::from_header(req).is_none()
+        && (req.cookie(&session_cookie).is_some()
+            || req.cookie(REFRESH_COOKIE).is_some()
+            || req.cookie(REMEMBER_COOKIE).is_some());
+
+    mutating && cookie_authenticated && !EXEMPT_PATHS.contains(&req.path())
+}
+
+/** Double-submit check, the header has to repeat the value of the CSRF cookie */
+fn has_valid_token(req: &HttpRequest) -> bool {
+    let cookie = match req.cookie(CSRF_COOKIE) {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+    let header = match req.headers().get(CSRF_HEADER).and_then(|h| h.to_str().ok()) {
+        Some(header) => header,
+        None => return false,
+    };
+
+    !cookie.value().is_empty() && constant_time_eq(cookie.value().as_bytes(), header.as_bytes())
+}
+
+/// Middleware rejecting cookie authenticated POST, PUT and DELETE requests without a matching CSRF token
+/// with `403 Forbidden`. Requests authenticated with an `Authorization: Bearer` header can't be forged
+/// by another site, so they are let through
+///
+/// # Example
+/// ```
+/// App::new().wrap(CsrfProtection).service(create_new_blog)
+/// ```
+pub struct CsrfProtection;
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware { service }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !requires_token(req.request()) || has_valid_token(req.request()) {
+            let response = self.service.call(req);
+            return Box::pin(
+                async move { response.await.map(ServiceResponse::map_into_left_body) },
+            );
+        }
+
+        let (req, _payload) = req.into_parts();
+        let response = AppError::Forbidden.error_response().map_into_right_body();
+        Box::pin(async move { Ok(ServiceResponse::new(req, response)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{cookie::Cookie, post, put, test, App, HttpResponse};
+
+    #[post("/csrf_test")]
+    async fn csrf_test() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[put("/api/refresh")]
+    async fn csrf_refresh_test() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn test_csrf_protection() {
+        let app = test::init_service(
+            App::new()
+                .wrap(CsrfProtection)
+                .service(csrf_test)
+                .service(csrf_refresh_test),
+        )
+        .await;
+        let csrf = CookieConfig::default().csrf_cookie();
+
+        let req = test::TestRequest::post()
+            .uri("/csrf_test")
+            .cookie(Cookie::new("token", "test_token"))
+            .cookie(csrf.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/csrf_test")
+            .cookie(Cookie::new("token", "test_token"))
+            .cookie(csrf.clone())
+            .insert_header((CSRF_HEADER, csrf.value()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/csrf_test")
+            .insert_header(("Authorization", "Bearer test_token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        //The refresh token cookie authenticates the refresh as well
+        let req = test::TestRequest::put()
+            .uri("/api/refresh")
+            .cookie(Cookie::new(REFRESH_COOKIE, "test_refresh_token"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::put()
+            .uri("/api/refresh")
+            .cookie(Cookie::new(REFRESH_COOKIE, "test_refresh_token"))
+            .cookie(csrf.clone())
+            .insert_header((CSRF_HEADER, csrf.value()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+    }
+}
@@ -0,0 +1,116 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::app::config::EmailConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/** Signs the user, the address being verified and the expiry, so a link stops working once the address changes */
+fn mac(config: &EmailConfig, user_id: &str, email: &str, expires: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(config.signing_key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}\n{}", user_id, email, expires).as_bytes());
+    mac
+}
+
+/** Returns whether the address looks like an email address, delivery is what really verifies it */
+pub fn is_valid_address(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(|c| c.is_whitespace())
+                && !domain.contains('@')
+        }
+        None => false,
+    }
+}
+
+/** Builds the link verifying the address of the user, valid for the configured amount of seconds after `now` */
+pub fn verification_link(config: &EmailConfig, user_id: &str, email: &str, now: i64) -> String {
+    let expires = now + config.link_ttl;
+    let signature = base64::encode_config(
+        mac(config, user_id, email, expires).finalize().into_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    format!(
+        "{}/user/email/verify?user={}&exp={}&sig={}",
+        config.base_url, user_id, expires, signature
+    )
+}
+
+/** Checks the signature and expiry of a verification link against the current address of the user */
+pub fn verify_link(
+    config: &EmailConfig,
+    user_id: &str,
+    email: &str,
+    expires: i64,
+    signature: &str,
+    now: i64,
+) -> bool {
+    let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    expires > now
+        && mac(config, user_id, email, expires)
+            .verify_slice(&signature)
+            .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verification_link() {
+        let config = EmailConfig::default();
+        let link = verification_link(&config, "123", "test@example.com", 1000);
+        let query = link.split_once('?').unwrap().1;
+        let params: std::collections::HashMap<String, String> =
+            serde_urlencoded::from_str(query).unwrap();
+        let expires = params["exp"].parse::<i64>().unwrap();
+
+        debug_assert!(verify_link(
+            &config,
+            "123",
+            "test@example.com",
+            expires,
+            &params["sig"],
+            1000
+        ));
+        //Changing the address, the expiry or waiting too long invalidates the link
+        debug_assert!(!verify_link(
+            &config,
+            "123",
+            "other@example.com",
+            expires,
+            &params["sig"],
+            1000
+        ));
+        debug_assert!(!verify_link(
+            &config,
+            "123",
+            "test@example.com",
+            expires + 1,
+            &params["sig"],
+            1000
+        ));
+        debug_assert!(!verify_link(
+            &config,
+            "123",
+            "test@example.com",
+            expires,
+            &params["sig"],
+            expires
+        ));
+
+        debug_assert!(is_valid_address("test@example.com"));
+        debug_assert!(!is_valid_address("test@example"));
+        debug_assert!(!is_valid_address("test example@example.com"));
+    }
+}
@@ -0,0 +1,179 @@
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, Ready};
+
+use crate::{
+    app::{AppError, AppState},
+    auth::{
+        policy::{authorize, can, Action, Resource},
+        remember::RestoredSession,
+        signed::SignedToken,
+        sliding::SlidSession,
+        token::{Impersonator, SessionClient, Token},
+    },
+    database::models::{api_key::ApiKey, impersonation::Impersonation, user::*},
+};
+
+/// Extractor for routes which require a logged in user.
+/// Resolves the token from the `Authorization: Bearer` header or the login token cookie and loads the [User] it belongs to.
+/// Signed tokens are verified locally, opaque ones are looked up in the token store.
+/// [API keys](ApiKey) are accepted in the header too, their scopes are checked by the [policy](crate::auth::policy::authorize)
+///
+/// # Example
+/// ```
+/// #[get("/whoami")]
+/// pub async fn whoami(auth: AuthenticatedUser) -> HttpResponse {
+///     HttpResponse::Ok().body(auth.user.username)
+/// }
+/// ```
+///
+/// # Error
+/// - Unauthorized if the token is missing, expired or belongs to an user which no longer exists,
+///   or it's an impersonation session of an administrator which may no longer manage users
+/// - Internal server error
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user: User,
+    /// Token the request was authenticated with
+    pub token: String,
+    /// Key the request was authenticated with, if it wasn't authenticated with a login token
+    pub api_key: Option<ApiKey>,
+    /// Administrator acting as the user, if the token is an impersonation session
+    pub impersonator: Option<Impersonator>,
+}
+
+/// Extractor for routes managing the account itself (passwords, two-factor authentication, sessions and API keys),
+/// which require a login token of the user
+///
+/// # Error
+/// - Unauthorized if the user isn't logged in
+/// - Forbidden if the request was authenticated with an API key or an impersonation session
+#[derive(Debug, Clone)]
+pub struct SessionUser(pub AuthenticatedUser);
+
+/// Extractor for routes which may only be used by administrators, the role of the user has to grant [managing users](Action::ManageUsers)
+///
+/// # Error
+/// - Unauthorized if the user isn't logged in
+/// - Forbidden if the user isn't an administrator
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthenticatedUser);
+
+fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, AppError> {
+    let app_state = req
+        .app_data::<Data<AppState>>()
+        .ok_or(AppError::InternalServerError)?;
+    //A session restored from a remembered device replaces the expired one the request carries
+    let restored = req
+        .extensions()
+        .get::<RestoredSession>()
+        .map(|session| session.0.clone());
+    let token = restored
+        .clone()
+        .or_else(|| Token::from_request(req, &app_state.session_config.cookie))
+        .ok_or(AppError::UnauthorizedError)?;
+
+    let psql_conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    let store = app_state.token_store.as_ref();
+    let config = &app_state.session_config;
+    let mut api_key = None;
+    let mut impersonator = None;
+    let user_id = if ApiKey::is_api_key(&token) {
+        let key = ApiKey::authenticate(&psql_conn, &token)?;
+        let user_id = key.user_id.clone();
+        api_key = Some(key);
+        user_id
+    } else if SignedToken::is_signed(&token) {
+        SignedToken::authenticate(store, &app_state.denylist, &token, config)?.sub
+    } else {
+        let from_cookie = Token::from_header(req).is_none();
+        let (user_id, ttl) = Token::authenticate(
+            store,
+            &token,
+            config,
+            &SessionClient::from_request(req),
+            from_cookie,
+        )?;
+        impersonator = Token::impersonator(store, &Token::key(config, &token));
+        //Lets the renewal middleware extend the cookie, a restored session already sets a fresh one
+        if let (Some(ttl), true, None) = (ttl, from_cookie, &restored) {
+            req.extensions_mut().insert(SlidSession {
+                token: token.clone(),
+                ttl,
+            });
+        }
+        user_id
+    };
+
+    let user =
+        User::find_by_id(Some(&psql_conn), &user_id).map_err(|_| AppError::UnauthorizedError)?;
+
+    if let Some(impersonator) = &impersonator {
+        //The session ends once the administrator is deleted or may no longer manage users
+        let permitted = match User::find_by_id(Some(&psql_conn), &impersonator.admin_id) {
+            Ok(admin) => can(&psql_conn, &admin, Action::ManageUsers, Resource::None)?,
+            Err(_) => false,
+        };
+        if !permitted {
+            Token::delete(store, &Token::key(config, &token));
+            Impersonation::end(&psql_conn, &impersonator.record_id)?;
+            return Err(AppError::UnauthorizedError);
+        }
+
+        //Lets the marker middleware flag the response
+        req.extensions_mut().insert(impersonator.clone());
+    }
+
+    Ok(AuthenticatedUser {
+        user,
+        token,
+        api_key,
+        impersonator,
+    })
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+impl FromRequest for SessionUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate(req).and_then(|auth| {
+            match auth.api_key.is_some() || auth.impersonator.is_some() {
+                true => Err(AppError::Forbidden),
+                false => Ok(SessionUser(auth)),
+            }
+        }))
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate(req).and_then(|auth| {
+            let app_state = req
+                .app_data::<Data<AppState>>()
+                .ok_or(AppError::InternalServerError)?;
+            let psql_conn = app_state
+                .psql_pool
+                .get()
+                .map_err(|_| AppError::InternalServerError)?;
+            authorize(&psql_conn, &auth, Action::ManageUsers, Resource::None)?;
+
+            Ok(AdminUser(auth))
+        }))
+    }
+}
@@ -0,0 +1,77 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpMessage,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::auth::token::Impersonator;
+
+/// Seconds an impersonation session lives for, it can't be refreshed
+pub const IMPERSONATION_TTL: i64 = 60 * 60;
+/// Header marking responses to impersonation sessions, holds the id of the administrator
+pub const IMPERSONATION_HEADER: &str = "X-Impersonated-By";
+
+/// Middleware adding the [IMPERSONATION_HEADER] to every response of a request authenticated with an impersonation session,
+/// so clients can show that an administrator is acting as the user
+///
+/// # Example
+/// ```
+/// App::new().wrap(ImpersonationMarker).service(get_blogs_by_user)
+/// ```
+pub struct ImpersonationMarker;
+
+impl<S, B> Transform<S, ServiceRequest> for ImpersonationMarker
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ImpersonationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ImpersonationMiddleware { service }))
+    }
+}
+
+pub struct ImpersonationMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ImpersonationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let mut response = response.await?;
+            //Set by the authentication extractor, which runs inside the wrapped service
+            let admin_id = response
+                .request()
+                .extensions()
+                .get::<Impersonator>()
+                .map(|impersonator| impersonator.admin_id.clone());
+
+            let name = HeaderName::from_bytes(IMPERSONATION_HEADER.as_bytes());
+            let value = admin_id.and_then(|id| HeaderValue::from_str(&id).ok());
+            if let (Ok(name), Some(value)) = (name, value) {
+                response.headers_mut().insert(name, value);
+            }
+            Ok(response)
+        })
+    }
+}
@@ -1 +1,18 @@
+pub mod audit;
+pub mod cookie;
+pub mod csrf;
+pub mod email;
+pub mod extractor;
+pub mod impersonation;
+pub mod oidc;
+pub mod password;
+pub mod password_policy;
+pub mod policy;
+pub mod refresh;
+pub mod remember;
+pub mod signed;
+pub mod sliding;
+pub mod store;
+pub mod throttle;
 pub mod token;
+pub mod totp;
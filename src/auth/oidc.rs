@@ -0,0 +1,285 @@
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::app::{config::OidcConfig, AppError};
+
+/** Endpoints of the provider, read from its discovery document */
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: Option<String>,
+}
+
+/// How long a fetched discovery document is used before it's fetched again
+const DISCOVERY_TTL: Duration = Duration::from_secs(3600);
+/// How long the fetched signing keys of the provider are used before they're fetched again
+const JWKS_TTL: Duration = Duration::from_secs(3600);
+
+/** Claims of an ID token this site uses */
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdClaims {
+    /// Id of the user at the provider
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub preferred_username: Option<String>,
+    pub email: Option<String>,
+}
+
+/** Values tying the callback to the login it was started by, kept in the token store until the callback */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingLogin {
+    /// PKCE code verifier, only its digest is sent to the provider
+    pub verifier: String,
+    /// Expected in the ID token, so a token issued for another login can't be replayed
+    pub nonce: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/** Returns the S256 PKCE challenge of the verifier */
+pub fn pkce_challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/** Returns the cached discovery document of the issuer, if it was fetched within [DISCOVERY_TTL] */
+fn cached_metadata(issuer: &str) -> Option<ProviderMetadata> {
+    let cache = discovery_cache().lock().ok()?;
+    match cache.get(issuer) {
+        Some((metadata, fetched)) if fetched.elapsed() < DISCOVERY_TTL => Some(metadata.clone()),
+        _ => None,
+    }
+}
+
+fn discovery_cache() -> &'static Mutex<HashMap<String, (ProviderMetadata, Instant)>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, (ProviderMetadata, Instant)>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/** Returns the cached signing keys fetched from `jwks_uri`, if they were fetched within [JWKS_TTL] */
+fn cached_jwks(jwks_uri: &str) -> Option<JwkSet> {
+    let cache = jwks_cache().lock().ok()?;
+    match cache.get(jwks_uri) {
+        Some((jwks, fetched)) if fetched.elapsed() < JWKS_TTL => Some(jwks.clone()),
+        _ => None,
+    }
+}
+
+fn jwks_cache() -> &'static Mutex<HashMap<String, (JwkSet, Instant)>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, (JwkSet, Instant)>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/** Returns the signing keys of the provider, they're only fetched once every [JWKS_TTL].
+ * `refetch` skips the cache, for tokens signed with a key the cached ones don't contain yet
+ */
+async fn jwks(jwks_uri: &str, refetch: bool) -> Result<JwkSet, AppError> {
+    if let Some(jwks) = cached_jwks(jwks_uri).filter(|_| !refetch) {
+        return Ok(jwks);
+    }
+
+    let jwks = awc::Client::default()
+        .get(jwks_uri)
+        .send()
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .json::<JwkSet>()
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+
+    jwks_cache()
+        .lock()
+        .map_err(|_| AppError::InternalServerError)?
+        .insert(jwks_uri.to_string(), (jwks.clone(), Instant::now()));
+    Ok(jwks)
+}
+
+/** Returns the discovery document of the provider, the issuer in it has to match the configured one.
+ * It's only fetched once every [DISCOVERY_TTL], instead of on every login and callback
+ */
+pub async fn discover(config: &OidcConfig) -> Result<ProviderMetadata, AppError> {
+    if let Some(metadata) = cached_metadata(&config.issuer) {
+        return Ok(metadata);
+    }
+
+    let metadata = awc::Client::default()
+        .get(format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer
+        ))
+        .send()
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .json::<ProviderMetadata>()
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+    if metadata.issuer.trim_end_matches('/') != config.issuer {
+        return Err(AppError::InternalServerError);
+    }
+
+    discovery_cache()
+        .lock()
+        .map_err(|_| AppError::InternalServerError)?
+        .insert(config.issuer.clone(), (metadata.clone(), Instant::now()));
+    Ok(metadata)
+}
+
+/** Starts a login, returns the url to send the user to and the values needed once the user returns with `state` */
+pub fn authorization_request(
+    metadata: &ProviderMetadata,
+    config: &OidcConfig,
+) -> Result<(String, String, PendingLogin), AppError> {
+    let state = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    let pending = PendingLogin {
+        verifier: Alphanumeric.sample_string(&mut rand::thread_rng(), 64),
+        nonce: Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+    };
+
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", config.client_id.as_str()),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("scope", "openid profile email"),
+        ("state", state.as_str()),
+        ("nonce", pending.nonce.as_str()),
+        ("code_challenge", pkce_challenge(&pending.verifier).as_str()),
+        ("code_challenge_method", "S256"),
+    ])
+    .map_err(|_| AppError::InternalServerError)?;
+
+    Ok((
+        format!("{}?{}", metadata.authorization_endpoint, query),
+        state,
+        pending,
+    ))
+}
+
+/** Exchanges the authorization code for the ID token at the token endpoint */
+pub async fn exchange_code(
+    metadata: &ProviderMetadata,
+    config: &OidcConfig,
+    code: &str,
+    pending: &PendingLogin,
+) -> Result<String, AppError> {
+    let mut response = awc::Client::default()
+        .post(&metadata.token_endpoint)
+        .send_form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+            ("code_verifier", pending.verifier.as_str()),
+        ])
+        .await
+        .map_err(|_| AppError::InternalServerError)?;
+    //The provider rejects codes which are invalid, expired or were issued for another verifier
+    if !response.status().is_success() {
+        return Err(AppError::UnauthorizedError);
+    }
+
+    Ok(response
+        .json::<TokenResponse>()
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+        .id_token)
+}
+
+/** Returns the key the ID token was signed with, RS256 keys are fetched from the provider.
+ * HS256 tokens are signed with the client secret, they are only accepted if the provider is configured to use them,
+ * otherwise anyone knowing the secret could forge ID tokens
+ */
+async fn decoding_key(
+    metadata: &ProviderMetadata,
+    config: &OidcConfig,
+    algorithm: Algorithm,
+    kid: Option<String>,
+) -> Result<DecodingKey, AppError> {
+    match algorithm {
+        Algorithm::HS256 if config.allow_hs256 => {
+            Ok(DecodingKey::from_secret(config.client_secret.as_bytes()))
+        }
+        Algorithm::RS256 => {
+            let jwks_uri = metadata
+                .jwks_uri
+                .as_ref()
+                .ok_or(AppError::InternalServerError)?;
+            let find = |jwks: JwkSet| {
+                jwks.keys
+                    .into_iter()
+                    .find(|jwk| kid.is_none() || jwk.kid == kid)
+            };
+            //The provider rotated its keys if the token's isn't among the cached ones
+            let jwk = match find(jwks(jwks_uri, false).await?) {
+                Some(jwk) => jwk,
+                None => find(jwks(jwks_uri, true).await?).ok_or(AppError::UnauthorizedError)?,
+            };
+            match (&jwk.n, &jwk.e) {
+                (Some(n), Some(e)) => {
+                    DecodingKey::from_rsa_components(n, e).map_err(|_| AppError::UnauthorizedError)
+                }
+                _ => Err(AppError::UnauthorizedError),
+            }
+        }
+        _ => Err(AppError::UnauthorizedError),
+    }
+}
+
+/** Verifies signature, issuer, audience, expiry and nonce of the ID token */
+pub async fn verify_id_token(
+    metadata: &ProviderMetadata,
+    config: &OidcConfig,
+    id_token: &str,
+    pending: &PendingLogin,
+) -> Result<IdClaims, AppError> {
+    let header = decode_header(id_token).map_err(|_| AppError::UnauthorizedError)?;
+    let key = decoding_key(metadata, config, header.alg, header.kid).await?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_issuer(&[&metadata.issuer]);
+    let claims = decode::<IdClaims>(id_token, &key, &validation)
+        .map_err(|_| AppError::UnauthorizedError)?
+        .claims;
+
+    match claims.nonce.as_ref() == Some(&pending.nonce) {
+        true => Ok(claims),
+        false => Err(AppError::UnauthorizedError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce_challenge() {
+        debug_assert!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r7wW1gFWFOEjXk")
+                == "bwWFMyPfdG9qreDhH2lmftFx_dFeLDalzcT1gb_j68g"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use std::sync::OnceLock;
+
+use crate::app::AppError;
+
+/// Outcome of checking a password against a stored hash
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// Password matches and the stored hash is up to date
+    Valid,
+    /// Password matches, but the stored hash uses a legacy scheme and should be replaced
+    ValidNeedsRehash,
+    Invalid,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        *self != Verification::Invalid
+    }
+}
+
+/** Hashes the password with Argon2id and a random salt, returning a PHC formatted string */
+pub fn hash_password(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| AppError::InternalServerError)
+}
+
+/** Verifies the password against a stored hash.
+ * Accepts Argon2 PHC strings as well as legacy unsalted SHA256 digests, the latter are reported
+ * as [`Verification::ValidNeedsRehash`] when they match
+ */
+pub fn verify_password(password: &str, stored: &str) -> Verification {
+    if is_legacy_hash(stored) {
+        let digest = sha256::digest(password.to_string());
+        return match constant_time_eq(digest.as_bytes(), stored.as_bytes()) {
+            true => Verification::ValidNeedsRehash,
+            false => Verification::Invalid,
+        };
+    }
+
+    let parsed = match PasswordHash::new(stored) {
+        Ok(parsed) => parsed,
+        Err(_) => return Verification::Invalid,
+    };
+
+    match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(_) => Verification::Valid,
+        Err(_) => Verification::Invalid,
+    }
+}
+
+/** Verifies the password against a fixed Argon2 hash, for logins of usernames which don't exist.
+ * They take as long as a wrong password that way, so the response time doesn't reveal which usernames exist
+ */
+pub fn verify_dummy(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY_HASH.get_or_init(|| hash_password("dummy password").unwrap_or_default());
+
+    let _res = verify_password(password, hash);
+}
+
+/** Returns `true` if the stored hash is an unsalted SHA256 hex digest */
+fn is_legacy_hash(stored: &str) -> bool {
+    stored.len() == 64 && stored.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/** Compares two byte slices in time independent of where they differ */
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argon2_roundtrip() {
+        let hash = hash_password("correct horse battery").unwrap();
+
+        debug_assert!(hash.starts_with("$argon2id$"));
+        debug_assert!(verify_password("correct horse battery", &hash) == Verification::Valid);
+        debug_assert!(verify_password("wrong horse battery", &hash) == Verification::Invalid);
+    }
+
+    #[test]
+    fn test_legacy_hash() {
+        let legacy = sha256::digest("gaser_marko".to_string());
+
+        debug_assert!(verify_password("gaser_marko", &legacy) == Verification::ValidNeedsRehash);
+        debug_assert!(verify_password("gaser_mark0", &legacy) == Verification::Invalid);
+    }
+}
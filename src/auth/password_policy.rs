@@ -0,0 +1,229 @@
+use actix_web::web;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::app::{config::PasswordConfig, AppError};
+
+/// Length of the SHA1 prefix the breached password list is split by
+const PREFIX_LENGTH: usize = 5;
+/// Fewest characters a username needs before passwords containing it are rejected,
+/// shorter ones appear in too many passwords by chance
+const MIN_USERNAME_LENGTH: usize = 4;
+
+/// Reason a new password was rejected, returned to the client in the `reasons` list of the error
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum PasswordRejection {
+    TooShort {
+        min_length: usize,
+    },
+    TooLong {
+        max_length: usize,
+    },
+    ContainsUsername,
+    /// The password appears in the breached password list
+    Breached,
+}
+
+impl PasswordRejection {
+    /** Human readable description, used by the admin command */
+    pub fn describe(&self) -> String {
+        match self {
+            PasswordRejection::TooShort { min_length } => {
+                format!(
+                    "The password must be at least {} characters long",
+                    min_length
+                )
+            }
+            PasswordRejection::TooLong { max_length } => {
+                format!(
+                    "The password must be at most {} characters long",
+                    max_length
+                )
+            }
+            PasswordRejection::ContainsUsername => {
+                "The password must not contain the username".to_string()
+            }
+            PasswordRejection::Breached => {
+                "The password appeared in a data breach, choose another one".to_string()
+            }
+        }
+    }
+}
+
+/** Uppercase hex SHA1 digest of the password, the format of the breached password list */
+fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
+
+/** Returns whether a `SUFFIX:COUNT` line lists the hash suffix */
+fn lists_hash(line: &str, suffix: &str) -> bool {
+    let listed = line.split(':').next().unwrap_or_default().trim();
+    listed.eq_ignore_ascii_case(suffix)
+}
+
+/** Looks the password up in the breached password list, only the file of its hash prefix is read */
+fn is_breached(list: &Path, password: &str) -> io::Result<bool> {
+    let hash = sha1_hex(password);
+    let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+
+    let file = match File::open(list.join(prefix)) {
+        Ok(file) => file,
+        //No listed password has the prefix
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    for line in BufReader::new(file).lines() {
+        if lists_hash(&line?, suffix) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/** Checks a new password against the configured rules, surrounding whitespace is ignored like when the password is stored.
+ * Every broken rule is reported with [PasswordRejected](AppError::PasswordRejected),
+ * the username is only checked if it's known.
+ * Reading the breached password list blocks, so it runs on the thread pool for blocking work
+ */
+pub async fn check_password(
+    config: &PasswordConfig,
+    password: &str,
+    username: Option<&str>,
+) -> Result<(), AppError> {
+    let config = config.clone();
+    let password = password.to_string();
+    let username = username.map(str::to_string);
+
+    web::block(move || check_password_blocking(&config, &password, username.as_deref()))
+        .await
+        .map_err(|_| AppError::InternalServerError)?
+}
+
+/** Same as [check_password], but reads the breached password list on the calling thread. Used by the admin command */
+pub fn check_password_blocking(
+    config: &PasswordConfig,
+    password: &str,
+    username: Option<&str>,
+) -> Result<(), AppError> {
+    let password = password.trim();
+    let length = password.chars().count();
+    let mut rejections = Vec::new();
+
+    if length < config.min_length {
+        rejections.push(PasswordRejection::TooShort {
+            min_length: config.min_length,
+        });
+    }
+    if length > config.max_length {
+        rejections.push(PasswordRejection::TooLong {
+            max_length: config.max_length,
+        });
+    }
+    if let Some(username) = username
+        .map(str::trim)
+        .filter(|name| name.chars().count() >= MIN_USERNAME_LENGTH)
+    {
+        if config.disallow_username && password.to_lowercase().contains(&username.to_lowercase()) {
+            rejections.push(PasswordRejection::ContainsUsername);
+        }
+    }
+    if let Some(list) = &config.breached_list {
+        if is_breached(list, password).map_err(|_| AppError::InternalServerError)? {
+            rejections.push(PasswordRejection::Breached);
+        }
+    }
+
+    match rejections.is_empty() {
+        true => Ok(()),
+        false => Err(AppError::PasswordRejected(rejections)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn rejections(result: Result<(), AppError>) -> Vec<PasswordRejection> {
+        match result {
+            Err(AppError::PasswordRejected(rejections)) => rejections,
+            _ => Vec::new(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_password_rules() {
+        let config = PasswordConfig {
+            min_length: 10,
+            max_length: 20,
+            ..PasswordConfig::default()
+        };
+
+        debug_assert!(check_password(&config, "  long enough  ", Some("marko"))
+            .await
+            .is_ok());
+        debug_assert!(
+            rejections(check_password(&config, " short    ", None).await)
+                == vec![PasswordRejection::TooShort { min_length: 10 }]
+        );
+        debug_assert!(
+            rejections(check_password(&config, "way too long for the policy", None).await)
+                == vec![PasswordRejection::TooLong { max_length: 20 }]
+        );
+        debug_assert!(
+            rejections(check_password(&config, "iam_MARKO_13", Some("marko")).await)
+                == vec![PasswordRejection::ContainsUsername]
+        );
+        //Short usernames aren't looked for, they would reject many passwords by chance
+        debug_assert!(check_password(&config, "a bold password", Some("bol"))
+            .await
+            .is_ok());
+        debug_assert!(
+            rejections(check_password(&config, "a bold password", Some("bold")).await)
+                == vec![PasswordRejection::ContainsUsername]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_breached_list() {
+        let dir = env::temp_dir().join(format!("breached_list_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let hash = sha1_hex("password123");
+        fs::write(
+            dir.join(&hash[..PREFIX_LENGTH]),
+            format!(
+                "0000000000000000000000000000000000A:1\r\n{}:42\r\n",
+                &hash[PREFIX_LENGTH..]
+            ),
+        )
+        .unwrap();
+
+        let config = PasswordConfig {
+            min_length: 8,
+            breached_list: Some(dir.clone()),
+            ..PasswordConfig::default()
+        };
+        debug_assert!(
+            rejections(check_password(&config, "password123", None).await)
+                == vec![PasswordRejection::Breached]
+        );
+        debug_assert!(check_password(&config, "not in the list", None)
+            .await
+            .is_ok());
+        debug_assert!(
+            rejections(check_password_blocking(&config, "password123", None))
+                == vec![PasswordRejection::Breached]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
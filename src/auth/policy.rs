@@ -0,0 +1,244 @@
+use diesel::{
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+
+use crate::{
+    app::{config::EmailConfig, AppError},
+    auth::extractor::AuthenticatedUser,
+    database::models::{blog::Blog, comment::Comment, role::Role, user::User},
+};
+
+/// Scopes an [API key](crate::database::models::api_key::ApiKey) can be limited to.
+/// Reading is public, the read scopes only let a key identify its user on the read routes
+pub const SCOPES: [&str; 6] = [
+    "blogs:read",
+    "blogs:write",
+    "comments:read",
+    "comments:write",
+    "users:write",
+    "admin",
+];
+
+/// Something an user may attempt, every handler changing data checks its action through [authorize]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    CreateBlog,
+    EditBlog,
+    DeleteBlog,
+    LikeBlog,
+    CreateComment,
+    DeleteComment,
+    DeleteUser,
+    /// Administration of other users, like changing roles or unlocking logins
+    ManageUsers,
+}
+
+/// What the action is performed on, decides whether the user owns it
+#[derive(Clone, Copy)]
+pub enum Resource<'a> {
+    None,
+    Blog(&'a Blog),
+    Comment(&'a Comment),
+    User(&'a User),
+}
+
+impl Action {
+    /** Returns the permission allowing the action on any resource,
+     * and the permission allowing it only on resources owned by the user
+     */
+    fn permissions(&self) -> (&'static str, Option<&'static str>) {
+        match self {
+            Action::CreateBlog => ("blogs.create", None),
+            Action::EditBlog => ("blogs.edit_any", Some("blogs.edit_own")),
+            Action::DeleteBlog => ("blogs.delete_any", Some("blogs.delete_own")),
+            Action::LikeBlog => ("blogs.like", None),
+            Action::CreateComment => ("comments.create", None),
+            Action::DeleteComment => ("comments.delete_any", Some("comments.delete_own")),
+            Action::DeleteUser => ("users.manage", Some("users.delete_own")),
+            Action::ManageUsers => ("users.manage", None),
+        }
+    }
+}
+
+impl Action {
+    /** Whether the action destroys or overwrites data or changes other accounts, which administrators impersonating
+     * an user may not do. Editing counts, it replaces what the user wrote without leaving a copy
+     */
+    fn is_destructive(&self) -> bool {
+        matches!(
+            self,
+            Action::EditBlog
+                | Action::DeleteBlog
+                | Action::DeleteComment
+                | Action::DeleteUser
+                | Action::ManageUsers
+        )
+    }
+
+    /** Scope an API key needs for the action */
+    fn scope(&self) -> &'static str {
+        match self {
+            Action::CreateBlog | Action::EditBlog | Action::DeleteBlog | Action::LikeBlog => {
+                "blogs:write"
+            }
+            Action::CreateComment | Action::DeleteComment => "comments:write",
+            Action::DeleteUser => "users:write",
+            Action::ManageUsers => "admin",
+        }
+    }
+}
+
+impl Resource<'_> {
+    /** Id of the user owning the resource */
+    fn owner(&self) -> Option<&String> {
+        match self {
+            Resource::None => None,
+            Resource::Blog(blog) => Some(&blog.created_by),
+            Resource::Comment(comment) => Some(&comment.user_id),
+            Resource::User(user) => Some(&user.id),
+        }
+    }
+}
+
+/** Decides the action with the permissions of the user already loaded */
+pub fn allows(permissions: &[String], user: &User, action: Action, resource: Resource) -> bool {
+    let granted = |name: &str| permissions.iter().any(|permission| permission == name);
+    let (any, own) = action.permissions();
+
+    granted(any)
+        || match (own, resource.owner()) {
+            (Some(own), Some(owner)) => owner == &user.id && granted(own),
+            _ => false,
+        }
+}
+
+/// Answers whether the user can perform the action on the resource, using the permissions of the role of the user
+///
+/// # Example
+/// ```
+/// if !can(&conn, &user, Action::EditBlog, Resource::Blog(&blog))? {
+///     return Err(AppError::Forbidden);
+/// }
+/// ```
+pub fn can(
+    conn: &PooledConnection<ConnectionManager<PgConnection>>,
+    user: &User,
+    action: Action,
+    resource: Resource,
+) -> Result<bool, AppError> {
+    let permissions = Role::permissions(conn, &user.role)?;
+
+    Ok(allows(&permissions, user, action, resource))
+}
+
+/** Same as [can], but returns `Forbidden` if the user can't perform the action.
+ * Requests authenticated with an API key also need the scope of the action,
+ * impersonation sessions can't edit or delete anything
+ */
+pub fn authorize(
+    conn: &PooledConnection<ConnectionManager<PgConnection>>,
+    auth: &AuthenticatedUser,
+    action: Action,
+    resource: Resource,
+) -> Result<(), AppError> {
+    if auth.impersonator.is_some() && action.is_destructive() {
+        return Err(AppError::Forbidden);
+    }
+    if let Some(api_key) = &auth.api_key {
+        if !api_key.scopes.iter().any(|scope| scope == action.scope()) {
+            return Err(AppError::Forbidden);
+        }
+    }
+
+    match can(conn, &auth.user, action, resource)? {
+        true => Ok(()),
+        false => Err(AppError::Forbidden),
+    }
+}
+
+/** Returns `Forbidden` if publishing requires a verified email address and the user hasn't verified one */
+pub fn require_verified_email(config: &EmailConfig, user: &User) -> Result<(), AppError> {
+    match !config.require_verified || user.email_verified {
+        true => Ok(()),
+        false => Err(AppError::Forbidden),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn user(id: &str, role: Role) -> User {
+        User {
+            id: id.to_string(),
+            username: format!("Test {} user", role),
+            pass: String::new(),
+            totp_secret: None,
+            totp_enabled: false,
+            role: role.to_string(),
+            email: None,
+            email_verified: false,
+        }
+    }
+
+    #[test]
+    fn test_ownership_permissions() {
+        let author = user("1", Role::Author);
+        let other = user("2", Role::Author);
+        let blog = Blog {
+            id: 1,
+            title: "Test title".to_string(),
+            body: "Test body".to_string(),
+            image_id: None,
+            created_by: author.id.clone(),
+            created_at: Utc::now().naive_utc(),
+            updated_at: Utc::now().naive_utc(),
+            likes: 0,
+        };
+        let author_permissions = vec![
+            "blogs.create".to_string(),
+            "blogs.edit_own".to_string(),
+            "blogs.delete_own".to_string(),
+        ];
+        let moderator_permissions = vec!["blogs.delete_any".to_string()];
+
+        assert!(allows(
+            &author_permissions,
+            &author,
+            Action::EditBlog,
+            Resource::Blog(&blog)
+        ));
+        assert!(!allows(
+            &author_permissions,
+            &other,
+            Action::EditBlog,
+            Resource::Blog(&blog)
+        ));
+        assert!(!allows(
+            &author_permissions,
+            &other,
+            Action::DeleteBlog,
+            Resource::Blog(&blog)
+        ));
+        assert!(allows(
+            &moderator_permissions,
+            &other,
+            Action::DeleteBlog,
+            Resource::Blog(&blog)
+        ));
+        assert!(!allows(
+            &moderator_permissions,
+            &other,
+            Action::EditBlog,
+            Resource::Blog(&blog)
+        ));
+        assert!(!allows(
+            &author_permissions,
+            &author,
+            Action::ManageUsers,
+            Resource::None
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+use rand::distributions::{Alphanumeric, DistString};
+
+use crate::{
+    app::{config::SessionConfig, AppError},
+    auth::{
+        store::TokenStore,
+        token::{hash_secret, Token},
+    },
+};
+
+/// Length of a refresh token, tokens stored under their raw value are only looked up if they have this shape
+const TOKEN_LENGTH: usize = 48;
+
+/// Long lived tokens used to obtain new access [tokens](Token).
+///
+/// Every login starts a family of refresh tokens, refreshing replaces the family's current token with a new one.
+/// Presenting a token which was already replaced means it leaked, so the whole family is revoked together
+/// with every access token it issued.
+///
+/// Like login tokens, refresh tokens are stored under their keyed hash. Tokens issued before are still
+/// accepted under their raw value until they're rotated
+pub struct RefreshToken {}
+
+fn token_key(config: &SessionConfig, token: &str) -> String {
+    format!("refresh:{}", hash_secret(config, token))
+}
+fn legacy_token_key(token: &str) -> Option<String> {
+    if token.len() == TOKEN_LENGTH && token.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(format!("refresh:{}", token))
+    } else {
+        None
+    }
+}
+fn family_key(family: &str) -> String {
+    format!("refresh_family:{}", family)
+}
+fn family_tokens_key(family: &str) -> String {
+    format!("refresh_family:{}:tokens", family)
+}
+fn user_families_key(user_id: &str) -> String {
+    format!("refresh_families:{}", user_id)
+}
+
+impl RefreshToken {
+    /** Starts a new token family for the user and returns its first refresh token together with the family */
+    pub fn issue(
+        store: &dyn TokenStore,
+        user_id: &str,
+        config: &SessionConfig,
+    ) -> Result<(String, String), AppError> {
+        let family = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
+        let ttl = config.refresh_ttl;
+
+        store.set_hash(
+            &family_key(&family),
+            &[
+                ("user_id", user_id.to_string()),
+                ("current", hash_secret(config, &token)),
+            ],
+            ttl,
+        )?;
+        store.set(&token_key(config, &token), &family, ttl)?;
+        store.add_to_set(&user_families_key(user_id), &family, ttl)?;
+
+        Ok((token, family))
+    }
+
+    /** Returns the family the refresh token belongs to */
+    pub fn family(
+        store: &dyn TokenStore,
+        token: &str,
+        config: &SessionConfig,
+    ) -> Result<String, AppError> {
+        if let Some(family) = store.get(&token_key(config, token))? {
+            return Ok(family);
+        }
+        match legacy_token_key(token) {
+            Some(key) => store.get(&key)?.ok_or(AppError::UnauthorizedError),
+            None => Err(AppError::UnauthorizedError),
+        }
+    }
+
+    /** Returns the id of the user the family belongs to */
+    pub fn family_owner(store: &dyn TokenStore, family: &str) -> Result<String, AppError> {
+        store
+            .get_field(&family_key(family), "user_id")?
+            .ok_or(AppError::UnauthorizedError)
+    }
+
+    /** Replaces the refresh token with a new one from the same family, returning the new token and the family.
+     * If the token was already replaced the whole family is revoked
+     */
+    pub fn rotate(
+        store: &dyn TokenStore,
+        token: &str,
+        config: &SessionConfig,
+    ) -> Result<(String, String), AppError> {
+        let family = RefreshToken::family(store, token, config)?;
+        let ttl = store
+            .ttl(&family_key(&family))?
+            .ok_or(AppError::UnauthorizedError)?;
+
+        //Only succeeds if the token is still the current one, so two concurrent refreshes with the same token can't both succeed
+        let new_token = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
+        let new_hash = hash_secret(config, &new_token);
+        let replaced = store.compare_and_set_field(
+            &family_key(&family),
+            "current",
+            &hash_secret(config, token),
+            &new_hash,
+        )? || (legacy_token_key(token).is_some()
+            && store.compare_and_set_field(&family_key(&family), "current", token, &new_hash)?);
+        if !replaced {
+            //The token was already used once, someone else has a copy of it
+            RefreshToken::revoke_family(store, &family);
+            return Err(AppError::UnauthorizedError);
+        }
+
+        //Replaced tokens are kept until the family expires so their reuse can be detected
+        store.set(&token_key(config, &new_token), &family, ttl)?;
+
+        Ok((new_token, family))
+    }
+
+    /** Records that the session, given by its [key](Token::key), was issued by the family, so it's revoked together with it */
+    pub fn attach(store: &dyn TokenStore, family: &str, session_key: &str) -> Result<(), AppError> {
+        let ttl = store
+            .ttl(&family_key(family))?
+            .ok_or(AppError::UnauthorizedError)?;
+
+        store.add_to_set(&family_tokens_key(family), session_key, ttl)?;
+        store.set_field(session_key, "family", family)?;
+
+        Ok(())
+    }
+
+    /** Revokes the family, its refresh tokens and every access token it issued */
+    pub fn revoke_family(store: &dyn TokenStore, family: &str) {
+        let session_keys = store
+            .set_members(&family_tokens_key(family))
+            .unwrap_or_default();
+        for session_key in session_keys {
+            Token::delete(store, &session_key);
+        }
+
+        if let Ok(user_id) = RefreshToken::family_owner(store, family) {
+            let _res = store.remove_from_set(&user_families_key(&user_id), family);
+        }
+        let _res = store.delete(&family_key(family));
+        let _res = store.delete(&family_tokens_key(family));
+    }
+
+    /** Revokes every token family of the user */
+    pub fn delete_by_user(store: &dyn TokenStore, user_id: &str) {
+        let families = store
+            .set_members(&user_families_key(user_id))
+            .unwrap_or_default();
+
+        for family in families {
+            RefreshToken::revoke_family(store, &family);
+        }
+        let _res = store.delete(&user_families_key(user_id));
+    }
+}
@@ -0,0 +1,310 @@
+use actix_web::{
+    cookie::Cookie,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web::Data,
+    Error, HttpMessage, HttpRequest,
+};
+use chrono::{Duration, Utc};
+use diesel::{
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use rand::distributions::{Alphanumeric, DistString};
+
+use crate::{
+    app::{config::SessionConfig, AppError, AppState},
+    auth::{
+        audit,
+        cookie::REMEMBER_COOKIE,
+        csrf::CSRF_COOKIE,
+        password::constant_time_eq,
+        signed::SignedToken,
+        token::{SessionClient, Token},
+    },
+    database::models::{
+        api_key::ApiKey, auth_event::AuthEventKind, remembered_device::RememberedDevice, user::*,
+    },
+    routes::token::{new_session, revoke_user_sessions},
+};
+
+const SELECTOR_LENGTH: usize = 24;
+const VALIDATOR_LENGTH: usize = 48;
+/// Seconds after a rotation in which the replaced validator is rejected without being treated as stolen,
+/// a browser sends several requests with the old cookie when a page loads after the session expired
+const ROTATION_GRACE: i64 = 30;
+
+/// Long lived credential of a device the user chose to stay logged in on, stored in the [REMEMBER_COOKIE]
+/// as `selector:validator`. When the login session of the device expired, the [RememberMeRestore] middleware
+/// uses it to start a new one and replaces the validator. A replaced validator being presented again means
+/// the cookie was copied, so every session and remembered device of the user is revoked
+pub struct RememberMe {}
+
+/** Outcome of redeeming a remembered device credential */
+pub enum Redemption {
+    /// The credential was valid, holds the user and the credential replacing it
+    Restored { user_id: String, credential: String },
+    /// A replaced validator was presented again
+    Reused { user_id: String },
+    /// Another request of the client just replaced the validator, its response carries the new credential.
+    /// Nothing is set, so the cookie isn't removed from under that response
+    Superseded,
+    /// Unknown, expired or malformed credential
+    Invalid,
+}
+
+/** Validators are long random strings, so an unsalted digest is enough to protect them */
+fn hash_validator(validator: &str) -> String {
+    sha256::digest(validator.to_string())
+}
+
+fn split_credential(credential: &str) -> Option<(&str, &str)> {
+    let (selector, validator) = credential.split_once(':')?;
+    match selector.len() == SELECTOR_LENGTH && validator.len() == VALIDATOR_LENGTH {
+        true => Some((selector, validator)),
+        false => None,
+    }
+}
+
+impl RememberMe {
+    /** Remembers the device of the client for the configured TTL, returns the credential to store in the cookie */
+    pub fn issue(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user_id: &str,
+        config: &SessionConfig,
+        client: &SessionClient,
+    ) -> Result<String, AppError> {
+        let selector = Alphanumeric.sample_string(&mut rand::thread_rng(), SELECTOR_LENGTH);
+        let validator = Alphanumeric.sample_string(&mut rand::thread_rng(), VALIDATOR_LENGTH);
+
+        RememberedDevice::new(
+            conn,
+            user_id,
+            &selector,
+            &hash_validator(&validator),
+            client.ip.clone(),
+            client.user_agent.clone(),
+            (Utc::now() + Duration::seconds(config.remember_ttl)).naive_utc(),
+        )?;
+
+        Ok(format!("{}:{}", selector, validator))
+    }
+
+    /** Checks the credential and replaces its validator, expired devices are forgotten */
+    pub fn redeem(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        credential: &str,
+        client: &SessionClient,
+    ) -> Redemption {
+        let (selector, validator) = match split_credential(credential) {
+            Some(parts) => parts,
+            None => return Redemption::Invalid,
+        };
+        let device = match RememberedDevice::find_by_selector(conn, selector) {
+            Some(device) => device,
+            None => return Redemption::Invalid,
+        };
+
+        let now = Utc::now().naive_utc();
+        if device.expires_at <= now {
+            let _res = RememberedDevice::delete(conn, &device.user_id, &device.id);
+            return Redemption::Invalid;
+        }
+        if !constant_time_eq(
+            hash_validator(validator).as_bytes(),
+            device.validator_hash.as_bytes(),
+        ) {
+            return match (now - device.last_used_at).num_seconds() < ROTATION_GRACE {
+                true => Redemption::Superseded,
+                false => Redemption::Reused {
+                    user_id: device.user_id,
+                },
+            };
+        }
+
+        let new_validator = Alphanumeric.sample_string(&mut rand::thread_rng(), VALIDATOR_LENGTH);
+        match device.rotate(
+            conn,
+            &hash_validator(&new_validator),
+            client.ip.clone(),
+            client.user_agent.clone(),
+        ) {
+            Ok(true) => Redemption::Restored {
+                user_id: device.user_id,
+                credential: format!("{}:{}", selector, new_validator),
+            },
+            //Another request rotated it first
+            Ok(false) => Redemption::Superseded,
+            //The credential is still valid, it's kept for a later request
+            Err(_) => Redemption::Superseded,
+        }
+    }
+
+    /** Forgets the device holding the credential, used when the user logs out on it */
+    pub fn forget(conn: &PooledConnection<ConnectionManager<PgConnection>>, credential: &str) {
+        let device = split_credential(credential)
+            .and_then(|(selector, _)| RememberedDevice::find_by_selector(conn, selector));
+        if let Some(device) = device {
+            let _res = RememberedDevice::delete(conn, &device.user_id, &device.id);
+        }
+    }
+}
+
+/** Login token of a session the [RememberMeRestore] middleware started for the request, read by the authentication extractor */
+#[derive(Debug, Clone)]
+pub struct RestoredSession(pub String);
+
+/** Returns whether the token still authenticates */
+fn session_alive(app_state: &AppState, token: &str) -> bool {
+    let store = app_state.token_store.as_ref();
+    let config = &app_state.session_config;
+
+    if ApiKey::is_api_key(token) {
+        true
+    } else if SignedToken::is_signed(token) {
+        SignedToken::authenticate(store, &app_state.denylist, token, config).is_ok()
+    } else {
+        Token::find(store, token, config).is_ok()
+    }
+}
+
+/** Redeems the remembered device cookie of a request without a live session, returns the cookies to set on the response */
+fn restore(req: &HttpRequest) -> Vec<Cookie<'static>> {
+    let app_state = match req.app_data::<Data<AppState>>() {
+        Some(app_state) => app_state,
+        None => return Vec::new(),
+    };
+    let config = &app_state.session_config;
+    let credential = match req.cookie(REMEMBER_COOKIE) {
+        Some(cookie) if Token::from_header(req).is_none() => cookie.value().to_string(),
+        _ => return Vec::new(),
+    };
+    if let Some(token) = req.cookie(&config.cookie.name()) {
+        if session_alive(app_state, token.value()) {
+            return Vec::new();
+        }
+    }
+    let conn = match app_state.psql_pool.get() {
+        Ok(conn) => conn,
+        Err(_) => return Vec::new(),
+    };
+
+    let client = SessionClient::from_request(req);
+    match RememberMe::redeem(&conn, &credential, &client) {
+        Redemption::Restored {
+            user_id,
+            credential,
+        } => {
+            let user = match User::find_by_id(Some(&conn), &user_id) {
+                Ok(user) => user,
+                Err(_) => return Vec::new(),
+            };
+            //Same session a login starts, so it respects the session mode and can be refreshed
+            let (issued, refresh) =
+                match new_session(app_state.token_store.as_ref(), config, &user, &client) {
+                    Ok(session) => session,
+                    //The old validator was replaced already, presenting it again later would look like a stolen cookie
+                    Err(_) => {
+                        return vec![config
+                            .cookie
+                            .remember_cookie(credential, config.remember_ttl)]
+                    }
+                };
+            req.extensions_mut()
+                .insert(RestoredSession(issued.token.clone()));
+            audit::record(
+                app_state,
+                Some(req),
+                AuthEventKind::DeviceLogin,
+                Some(&user_id),
+                None,
+            );
+
+            let mut cookies = vec![
+                config
+                    .cookie
+                    .session_cookie(issued.token, issued.expires_in),
+                config.cookie.refresh_cookie(refresh, config.refresh_ttl),
+                config
+                    .cookie
+                    .remember_cookie(credential, config.remember_ttl),
+            ];
+            //Pages may still hold the current CSRF token, it's only set if the client has none
+            if req.cookie(CSRF_COOKIE).is_none() {
+                cookies.push(config.cookie.csrf_cookie());
+            }
+            cookies
+        }
+        Redemption::Reused { user_id } => {
+            let _res = revoke_user_sessions(app_state, &user_id);
+            audit::record(
+                app_state,
+                Some(req),
+                AuthEventKind::SessionRevoked,
+                Some(&user_id),
+                None,
+            );
+            vec![config.cookie.remember_cookie(String::new(), 0)]
+        }
+        Redemption::Superseded => Vec::new(),
+        Redemption::Invalid => vec![config.cookie.remember_cookie(String::new(), 0)],
+    }
+}
+
+/// Middleware starting a new login session for requests of a [remembered device](RememberMe) whose session expired.
+/// The new session is started like a login, of the configured session mode and with a refresh token.
+/// The request is authenticated with it, and the response sets its cookies together with
+/// the rotated remembered device cookie. Has to be wrapped inside [CsrfProtection](crate::auth::csrf::CsrfProtection)
+///
+/// # Example
+/// ```
+/// App::new().wrap(RememberMeRestore).wrap(CsrfProtection).service(get_blogs_by_user)
+/// ```
+pub struct RememberMeRestore;
+
+impl<S, B> Transform<S, ServiceRequest> for RememberMeRestore
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RememberMeMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RememberMeMiddleware { service }))
+    }
+}
+
+pub struct RememberMeMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RememberMeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let cookies = restore(req.request());
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let mut response = response.await?;
+            for cookie in cookies {
+                let _res = response.response_mut().add_cookie(&cookie);
+            }
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{config::SessionConfig, AppError},
+    auth::store::TokenStore,
+    database::models::user::User,
+};
+
+/// Key of the sorted set holding every revocation, scored by when the tokens it covers expire. Shared by all server instances
+const DENYLIST_KEY: &str = "signed_denylist:entries";
+/// Key of the plain set revocations were stored in before, read until it expires
+const LEGACY_DENYLIST_KEY: &str = "signed_denylist";
+/// Key changing with every revocation, instances only reload the denylist once it changed
+const DENYLIST_VERSION_KEY: &str = "signed_denylist:version";
+
+/** Claims carried by a [signed token](SignedToken) */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// Id of the user the token was issued to
+    pub sub: String,
+    /// Name of the role of the user when the token was issued, informational only since permissions are loaded per request
+    pub role: String,
+    /// Unix timestamp the token was issued at
+    pub iat: i64,
+    /// Unix timestamp in microseconds the token was issued at, revocations of all tokens of the user are compared against it.
+    /// Tokens issued before it was added count as issued at `0`, so they are covered by every such revocation
+    #[serde(default)]
+    pub iat_micros: i64,
+    /// Unix timestamp the token expires at
+    pub exp: i64,
+    /// Unique id of the token, used to revoke it
+    pub jti: String,
+    /// Refresh token family the token was issued by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fam: Option<String>,
+}
+
+/// Self contained login tokens signed with `SESSION_SIGNING_KEY` (HS256 JWTs).
+///
+/// They are verified without touching the token store, so they can't be extended and live for `SESSION_TTL`,
+/// clients obtain new ones with their refresh token. Revoked tokens are kept on the [Denylist] until they expire
+pub struct SignedToken {}
+
+impl SignedToken {
+    /** Returns whether the token is a signed token rather than an opaque one, opaque tokens are purely alphanumeric */
+    pub fn is_signed(token: &str) -> bool {
+        token.contains('.')
+    }
+
+    /** Issues a signed token for the user, `family` is the refresh token family it's issued by */
+    pub fn issue(
+        user: &User,
+        family: Option<&String>,
+        config: &SessionConfig,
+    ) -> Result<String, AppError> {
+        let key = config
+            .signing_key
+            .as_ref()
+            .ok_or(AppError::InternalServerError)?;
+        let issued_at = Utc::now();
+        let now = issued_at.timestamp();
+
+        let claims = Claims {
+            sub: user.id.clone(),
+            role: user.role.clone(),
+            iat: now,
+            iat_micros: issued_at.timestamp_micros(),
+            exp: now + config.ttl,
+            jti: Alphanumeric.sample_string(&mut rand::thread_rng(), 24),
+            fam: family.cloned(),
+        };
+
+        encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(key.as_bytes()),
+        )
+        .map_err(|_| AppError::InternalServerError)
+    }
+
+    /** Checks the signature and expiry of the token and returns its claims, without consulting the denylist */
+    pub fn decode(token: &str, config: &SessionConfig) -> Result<Claims, AppError> {
+        let key = config
+            .signing_key
+            .as_ref()
+            .ok_or(AppError::UnauthorizedError)?;
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+
+        decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(key.as_bytes()),
+            &validation,
+        )
+        .map(|data| data.claims)
+        .map_err(|_| AppError::UnauthorizedError)
+    }
+
+    /** Returns the claims of the token if it's valid and wasn't revoked */
+    pub fn authenticate(
+        store: &dyn TokenStore,
+        denylist: &Denylist,
+        token: &str,
+        config: &SessionConfig,
+    ) -> Result<Claims, AppError> {
+        let claims = SignedToken::decode(token, config)?;
+        match denylist.is_revoked(store, &claims) {
+            true => Err(AppError::UnauthorizedError),
+            false => Ok(claims),
+        }
+    }
+}
+
+/// User revocations below this value were recorded in seconds rather than microseconds
+const SECONDS_CUTOFF_LIMIT: i64 = 100_000_000_000;
+
+#[derive(Debug, Default)]
+struct DenylistCache {
+    synced_at: Option<Instant>,
+    /// Version of the stored denylist the copy was loaded from
+    version: Option<String>,
+    /// Ids of revoked tokens
+    tokens: HashSet<String>,
+    /// Tokens of the user issued before the timestamp in microseconds are revoked
+    users: HashMap<String, i64>,
+}
+
+/// Revoked signed tokens.
+///
+/// Revocations are stored in the token store so every instance sees them, each instance keeps a local copy
+/// which is checked at most every `SESSION_DENYLIST_SYNC` seconds and only reloaded if a revocation was added since.
+/// Authenticating a signed token therefore rarely hits the store, at the cost of revocations taking up to that long
+/// to reach other instances. Each entry is only kept until the tokens it covers expire, then it's pruned
+#[derive(Debug)]
+pub struct Denylist {
+    sync_interval: Duration,
+    cache: RwLock<DenylistCache>,
+}
+
+impl Denylist {
+    pub fn new(sync_interval: u64) -> Self {
+        Denylist {
+            sync_interval: Duration::from_secs(sync_interval),
+            cache: RwLock::new(DenylistCache::default()),
+        }
+    }
+
+    /** Revokes a single signed token */
+    pub fn revoke(
+        &self,
+        store: &dyn TokenStore,
+        claims: &Claims,
+        config: &SessionConfig,
+    ) -> Result<(), AppError> {
+        if claims.exp <= Utc::now().timestamp() {
+            return Ok(());
+        }
+        Denylist::add(
+            store,
+            &format!("token:{}:{}", claims.jti, claims.exp),
+            claims.exp,
+            config,
+        )?;
+
+        if let Ok(mut cache) = self.cache.write() {
+            cache.tokens.insert(claims.jti.clone());
+        }
+        Ok(())
+    }
+
+    /** Revokes every signed token issued to the user up to now, tokens issued afterwards stay valid even within the same second.
+     * Does nothing if signed tokens aren't enabled
+     */
+    pub fn revoke_user(
+        &self,
+        store: &dyn TokenStore,
+        user_id: &String,
+        config: &SessionConfig,
+    ) -> Result<(), AppError> {
+        if config.signing_key.is_none() {
+            return Ok(());
+        }
+        let revoked_at = Utc::now();
+        let now = revoked_at.timestamp_micros();
+        //The tokens issued up to now expire within the session TTL
+        Denylist::add(
+            store,
+            &format!("user:{}:{}", user_id, now),
+            revoked_at.timestamp() + config.ttl,
+            config,
+        )?;
+
+        if let Ok(mut cache) = self.cache.write() {
+            let cutoff = cache.users.entry(user_id.clone()).or_insert(now);
+            *cutoff = now.max(*cutoff);
+        }
+        Ok(())
+    }
+
+    /** Stores an entry needed until `expires_at` (unix timestamp), pruning the expired ones, and announces the change to every instance */
+    fn add(
+        store: &dyn TokenStore,
+        member: &str,
+        expires_at: i64,
+        config: &SessionConfig,
+    ) -> Result<(), AppError> {
+        store.remove_by_score(DENYLIST_KEY, Utc::now().timestamp())?;
+        store.add_to_sorted_set(DENYLIST_KEY, member, expires_at)?;
+        //Every entry expired once the version does, so instances reloading then only drop expired entries
+        let version = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        store.set(DENYLIST_VERSION_KEY, &version, config.ttl)
+    }
+
+    /** Returns whether the token was revoked, reloading the local copy first if it's stale */
+    pub fn is_revoked(&self, store: &dyn TokenStore, claims: &Claims) -> bool {
+        self.sync(store);
+
+        let cache = match self.cache.read() {
+            Ok(cache) => cache,
+            Err(_) => return true,
+        };
+        cache.tokens.contains(&claims.jti)
+            || cache
+                .users
+                .get(&claims.sub)
+                .is_some_and(|cutoff| claims.iat_micros < *cutoff)
+    }
+
+    fn sync(&self, store: &dyn TokenStore) {
+        let stale = match self.cache.read() {
+            Ok(cache) => cache
+                .synced_at
+                .is_none_or(|synced_at| synced_at.elapsed() >= self.sync_interval),
+            Err(_) => false,
+        };
+        if !stale {
+            return;
+        }
+
+        let mut cache = match self.cache.write() {
+            Ok(cache) => cache,
+            Err(_) => return,
+        };
+        //If the store is unreachable the old copy is kept until the next attempt
+        let first_sync = cache.synced_at.is_none();
+        cache.synced_at = Some(Instant::now());
+        let version = match store.get(DENYLIST_VERSION_KEY) {
+            Ok(version) => version,
+            Err(_) => return,
+        };
+        if !first_sync && version == cache.version {
+            return;
+        }
+
+        let now = Utc::now().timestamp();
+        let members = match (
+            store.sorted_set_members(DENYLIST_KEY, now + 1),
+            store.set_members(LEGACY_DENYLIST_KEY),
+        ) {
+            (Ok(members), Ok(legacy)) => members.into_iter().chain(legacy),
+            _ => return,
+        };
+
+        cache.version = version;
+        cache.tokens.clear();
+        cache.users.clear();
+        for member in members {
+            let mut parts = member.splitn(3, ':');
+            let (kind, id, timestamp) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(kind), Some(id), Some(timestamp)) => (kind, id, timestamp),
+                _ => continue,
+            };
+            let timestamp = match timestamp.parse::<i64>() {
+                Ok(timestamp) => timestamp,
+                Err(_) => continue,
+            };
+
+            match kind {
+                "token" if timestamp > now => {
+                    cache.tokens.insert(id.to_string());
+                }
+                "user" => {
+                    //Revocations stored before they were recorded in microseconds
+                    let timestamp = match timestamp < SECONDS_CUTOFF_LIMIT {
+                        true => (timestamp + 1) * 1_000_000,
+                        false => timestamp,
+                    };
+                    let cutoff = cache.users.entry(id.to_string()).or_insert(timestamp);
+                    *cutoff = timestamp.max(*cutoff);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::store::MemoryTokenStore;
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            signing_key: Some("test signing key which is long enough".to_string()),
+            ..SessionConfig::default()
+        }
+    }
+
+    fn user() -> User {
+        User {
+            id: "123456677899".to_string(),
+            username: "Test signed user".to_string(),
+            pass: String::new(),
+            totp_secret: None,
+            totp_enabled: false,
+            role: "author".to_string(),
+            email: None,
+            email_verified: false,
+        }
+    }
+
+    #[test]
+    fn test_signed_token_roundtrip() {
+        let config = config();
+        let token = SignedToken::issue(&user(), None, &config).unwrap();
+        debug_assert!(SignedToken::is_signed(&token));
+
+        let claims = SignedToken::decode(&token, &config).unwrap();
+        debug_assert!(claims.sub == "123456677899");
+        debug_assert!(claims.role == "author");
+
+        let other = SessionConfig {
+            signing_key: Some("another signing key which is long enough".to_string()),
+            ..SessionConfig::default()
+        };
+        debug_assert!(SignedToken::decode(&token, &other).is_err());
+    }
+
+    #[test]
+    fn test_denylist() {
+        let config = config();
+        let store = MemoryTokenStore::new();
+        let denylist = Denylist::new(0);
+
+        let token = SignedToken::issue(&user(), None, &config).unwrap();
+        let claims = SignedToken::authenticate(&store, &denylist, &token, &config).unwrap();
+        denylist.revoke(&store, &claims, &config).unwrap();
+
+        //A fresh instance only sees the revocation through the store
+        let other = Denylist::new(0);
+        debug_assert!(SignedToken::authenticate(&store, &other, &token, &config).is_err());
+
+        let token = SignedToken::issue(&user(), None, &config).unwrap();
+        denylist.revoke_user(&store, &user().id, &config).unwrap();
+        debug_assert!(SignedToken::authenticate(&store, &other, &token, &config).is_err());
+
+        //Logging in again right after revoking every token works, even within the same second
+        let token = SignedToken::issue(&user(), None, &config).unwrap();
+        debug_assert!(SignedToken::authenticate(&store, &denylist, &token, &config).is_ok());
+        debug_assert!(SignedToken::authenticate(&store, &other, &token, &config).is_ok());
+    }
+}
@@ -0,0 +1,171 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web::Data,
+    Error, HttpMessage,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::app::AppState;
+
+/// Login token which was [slid](crate::auth::token::Token::authenticate) by the request,
+/// together with the seconds it will live for
+#[derive(Debug, Clone)]
+pub struct SlidSession {
+    pub token: String,
+    pub ttl: i64,
+}
+
+/// Middleware extending the login token cookie when the request slid the session it was authenticated with,
+/// so the cookie doesn't expire before the stored session. Responses which already set the cookie keep theirs
+///
+/// # Example
+/// ```
+/// App::new().wrap(SessionRenewal).service(get_blogs_by_user)
+/// ```
+pub struct SessionRenewal;
+
+impl<S, B> Transform<S, ServiceRequest> for SessionRenewal
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SessionRenewalMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SessionRenewalMiddleware { service }))
+    }
+}
+
+pub struct SessionRenewalMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for SessionRenewalMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let mut response = response.await?;
+            //Set by the authentication extractor, which runs inside the wrapped service
+            let slid = response
+                .request()
+                .extensions()
+                .get::<SlidSession>()
+                .cloned();
+            let config = response
+                .request()
+                .app_data::<Data<AppState>>()
+                .map(|app_state| app_state.session_config.cookie.clone());
+
+            if let (Some(slid), Some(config)) = (slid, config) {
+                let name = config.name();
+                let already_set = response
+                    .response()
+                    .cookies()
+                    .any(|cookie| cookie.name() == name);
+                if !already_set {
+                    let cookie = config.session_cookie(slid.token, slid.ttl);
+                    let _res = response.response_mut().add_cookie(&cookie);
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        cookie::{Cookie, CookieBuilder},
+        test::{self, call_service},
+        App,
+    };
+
+    use super::*;
+    use crate::{
+        auth::{password::hash_password, store::MemoryTokenStore, token::Token},
+        database::models::{role::Role, user::*},
+        routes::token::{deauth_token, get_sessions},
+    };
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_session_renewal() {
+        let mut app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        app_state.session_config.sliding = true;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(app_state.clone()))
+                .wrap(SessionRenewal)
+                .service(get_sessions)
+                .service(deauth_token),
+        )
+        .await;
+
+        let usr = User::new(
+            Some(&app_state.psql_pool.get().unwrap()),
+            &String::from("test_session_renewal"),
+            &hash_password("asd123").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let store = app_state.token_store.as_ref();
+        let token = Token::new(store, &usr.id, &app_state.session_config);
+        let name = app_state.session_config.cookie.name();
+
+        let req = test::TestRequest::get()
+            .uri("/api/sessions")
+            .cookie(CookieBuilder::new(name.clone(), &token).finish())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let set_cookie = resp.headers().get("set-cookie").unwrap().to_str().unwrap();
+        let cookie = Cookie::parse(set_cookie).unwrap();
+        debug_assert!(cookie.name() == name);
+        debug_assert!(cookie.value() == token);
+        debug_assert!(cookie.max_age().unwrap().whole_seconds() > 0);
+
+        //Bearer tokens have no cookie to extend
+        let req = test::TestRequest::get()
+            .uri("/api/sessions")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(resp.headers().get("set-cookie").is_none());
+
+        //The removal cookie of a logout is kept
+        let req = test::TestRequest::delete()
+            .uri("/api/deauth")
+            .cookie(CookieBuilder::new(name.clone(), &token).finish())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let removal = resp
+            .headers()
+            .get_all("set-cookie")
+            .map(|header| Cookie::parse(header.to_str().unwrap().to_string()).unwrap())
+            .find(|cookie| cookie.name() == name)
+            .unwrap();
+        debug_assert!(removal.value().is_empty());
+
+        usr.delete(Some(&app_state.psql_pool.get().unwrap()));
+    }
+}
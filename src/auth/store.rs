@@ -0,0 +1,692 @@
+use diesel::r2d2::{Pool, PooledConnection};
+use r2d2_redis::{
+    redis::{self, Commands},
+    RedisConnectionManager,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::app::AppError;
+
+/// Storage for tokens and other short lived authentication data.
+///
+/// Keys hold either a string, a hash of fields, a set of members or a sorted set of scored members,
+/// every key can have a TTL in seconds after which it's removed. Using a key as a different type than it was created with is an error
+pub trait TokenStore: Send + Sync + std::fmt::Debug {
+    /** Returns the string stored under `key` */
+    fn get(&self, key: &str) -> Result<Option<String>, AppError>;
+    /** Stores a string under `key` for `ttl` seconds, replacing anything stored before */
+    fn set(&self, key: &str, value: &str, ttl: i64) -> Result<(), AppError>;
+    /** Returns the string stored under `key` and deletes it, so only one caller can get it */
+    fn take(&self, key: &str) -> Result<Option<String>, AppError>;
+    /** Increments the counter stored under `key`, starting at 0, sets its TTL to `ttl` seconds and returns the new value */
+    fn increment(&self, key: &str, ttl: i64) -> Result<i64, AppError>;
+    /** Stores `value` under `key` for `ttl` seconds only if no number at least as large is stored there, in one step.
+     * Returns whether it was stored
+     */
+    fn set_if_greater(&self, key: &str, value: i64, ttl: i64) -> Result<bool, AppError>;
+
+    /** Returns a field of the hash stored under `key` */
+    fn get_field(&self, key: &str, field: &str) -> Result<Option<String>, AppError>;
+    /** Stores a hash under `key` for `ttl` seconds, replacing anything stored before */
+    fn set_hash(&self, key: &str, fields: &[(&str, String)], ttl: i64) -> Result<(), AppError>;
+    /** Sets a field of an existing hash keeping its TTL, returns `false` if there is no hash under `key` */
+    fn set_field(&self, key: &str, field: &str, value: &str) -> Result<bool, AppError>;
+    /** Increments a counter field of an existing hash keeping its TTL and returns the new value, `None` if there is no hash under `key` */
+    fn increment_field(&self, key: &str, field: &str) -> Result<Option<i64>, AppError>;
+    /** Sets a field of the hash to `new` only if it currently holds `expected`, returns whether it was set */
+    fn compare_and_set_field(
+        &self,
+        key: &str,
+        field: &str,
+        expected: &str,
+        new: &str,
+    ) -> Result<bool, AppError>;
+
+    /** Adds a member to the set under `key` and sets the TTL of the set to `ttl` seconds */
+    fn add_to_set(&self, key: &str, member: &str, ttl: i64) -> Result<(), AppError>;
+    /** Removes a member from the set under `key` */
+    fn remove_from_set(&self, key: &str, member: &str) -> Result<(), AppError>;
+    /** Returns all members of the set under `key`, an empty vector if there is none */
+    fn set_members(&self, key: &str) -> Result<Vec<String>, AppError>;
+
+    /** Adds a member with the score to the sorted set under `key`, or updates its score. The set doesn't expire as a whole,
+     * its members are removed with [remove_by_score](TokenStore::remove_by_score)
+     */
+    fn add_to_sorted_set(&self, key: &str, member: &str, score: i64) -> Result<(), AppError>;
+    /** Removes every member of the sorted set under `key` whose score is at most `max` */
+    fn remove_by_score(&self, key: &str, max: i64) -> Result<(), AppError>;
+    /** Returns the members of the sorted set under `key` whose score is at least `min`, an empty vector if there is none */
+    fn sorted_set_members(&self, key: &str, min: i64) -> Result<Vec<String>, AppError>;
+
+    /** Deletes the key, returns `false` if it didn't exist */
+    fn delete(&self, key: &str) -> Result<bool, AppError>;
+    /** Moves whatever is stored under `key` to `new_key` keeping its TTL, in one step.
+     * Returns `false` if there is nothing under `key` or something under `new_key` already
+     */
+    fn rename(&self, key: &str, new_key: &str) -> Result<bool, AppError>;
+    /** Sets the TTL of the key, returns `false` if it doesn't exist */
+    fn expire(&self, key: &str, ttl: i64) -> Result<bool, AppError>;
+    /** Returns the seconds until the key expires, `None` if it doesn't exist or never expires */
+    fn ttl(&self, key: &str) -> Result<Option<i64>, AppError>;
+}
+
+/// [TokenStore] keeping everything in redis
+#[derive(Clone)]
+pub struct RedisTokenStore {
+    pool: Arc<Pool<RedisConnectionManager>>,
+}
+
+impl std::fmt::Debug for RedisTokenStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisTokenStore")
+            .field("pool", &self.pool.state())
+            .finish()
+    }
+}
+
+/// Sets a hash field only if the hash exists, `HSET` alone would create a hash without a TTL
+const SET_FIELD_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+    return 1
+end
+return 0
+";
+
+const INCREMENT_FIELD_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return redis.call('HINCRBY', KEYS[1], ARGV[1], 1)
+end
+return false
+";
+
+const COMPARE_AND_SET_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+    return 1
+end
+return 0
+";
+
+const SET_IF_GREATER_SCRIPT: &str = r"
+local current = tonumber(redis.call('GET', KEYS[1]))
+if current and current >= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+return 1
+";
+
+/// `RENAMENX` alone fails with an error if the key doesn't exist
+const RENAME_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return redis.call('RENAMENX', KEYS[1], KEYS[2])
+end
+return 0
+";
+
+impl RedisTokenStore {
+    pub fn new(pool: Arc<Pool<RedisConnectionManager>>) -> Self {
+        RedisTokenStore { pool }
+    }
+
+    fn conn(&self) -> Result<PooledConnection<RedisConnectionManager>, AppError> {
+        self.pool.get().map_err(|_| AppError::InternalServerError)
+    }
+}
+
+impl TokenStore for RedisTokenStore {
+    fn get(&self, key: &str) -> Result<Option<String>, AppError> {
+        Ok(self.conn()?.get::<&str, Option<String>>(key)?)
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: i64) -> Result<(), AppError> {
+        Ok(self
+            .conn()?
+            .set_ex::<&str, &str, ()>(key, value, ttl.max(1) as usize)?)
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, AppError> {
+        let mut conn = self.conn()?;
+
+        let (value,) = redis::pipe()
+            .atomic()
+            .get(key)
+            .del(key)
+            .ignore()
+            .query::<(Option<String>,)>(&mut *conn)?;
+        Ok(value)
+    }
+
+    fn increment(&self, key: &str, ttl: i64) -> Result<i64, AppError> {
+        let mut conn = self.conn()?;
+
+        let (count,) = redis::pipe()
+            .atomic()
+            .incr(key, 1)
+            .expire(key, ttl.max(1) as usize)
+            .ignore()
+            .query::<(i64,)>(&mut *conn)?;
+        Ok(count)
+    }
+
+    fn set_if_greater(&self, key: &str, value: i64, ttl: i64) -> Result<bool, AppError> {
+        let mut conn = self.conn()?;
+
+        let set = redis::Script::new(SET_IF_GREATER_SCRIPT)
+            .key(key)
+            .arg(value)
+            .arg(ttl.max(1))
+            .invoke::<i32>(&mut *conn)?;
+        Ok(set == 1)
+    }
+
+    fn get_field(&self, key: &str, field: &str) -> Result<Option<String>, AppError> {
+        Ok(self
+            .conn()?
+            .hget::<&str, &str, Option<String>>(key, field)?)
+    }
+
+    fn set_hash(&self, key: &str, fields: &[(&str, String)], ttl: i64) -> Result<(), AppError> {
+        let mut conn = self.conn()?;
+
+        Ok(redis::pipe()
+            .atomic()
+            .del(key)
+            .hset_multiple(key, fields)
+            .expire(key, ttl.max(1) as usize)
+            .query::<()>(&mut *conn)?)
+    }
+
+    fn set_field(&self, key: &str, field: &str, value: &str) -> Result<bool, AppError> {
+        let mut conn = self.conn()?;
+
+        let set = redis::Script::new(SET_FIELD_SCRIPT)
+            .key(key)
+            .arg(field)
+            .arg(value)
+            .invoke::<i32>(&mut *conn)?;
+        Ok(set == 1)
+    }
+
+    fn increment_field(&self, key: &str, field: &str) -> Result<Option<i64>, AppError> {
+        let mut conn = self.conn()?;
+
+        Ok(redis::Script::new(INCREMENT_FIELD_SCRIPT)
+            .key(key)
+            .arg(field)
+            .invoke::<Option<i64>>(&mut *conn)?)
+    }
+
+    fn compare_and_set_field(
+        &self,
+        key: &str,
+        field: &str,
+        expected: &str,
+        new: &str,
+    ) -> Result<bool, AppError> {
+        let mut conn = self.conn()?;
+
+        let set = redis::Script::new(COMPARE_AND_SET_SCRIPT)
+            .key(key)
+            .arg(field)
+            .arg(expected)
+            .arg(new)
+            .invoke::<i32>(&mut *conn)?;
+        Ok(set == 1)
+    }
+
+    fn add_to_set(&self, key: &str, member: &str, ttl: i64) -> Result<(), AppError> {
+        let mut conn = self.conn()?;
+
+        Ok(redis::pipe()
+            .atomic()
+            .sadd(key, member)
+            .expire(key, ttl.max(1) as usize)
+            .query::<()>(&mut *conn)?)
+    }
+
+    fn remove_from_set(&self, key: &str, member: &str) -> Result<(), AppError> {
+        Ok(self.conn()?.srem::<&str, &str, ()>(key, member)?)
+    }
+
+    fn set_members(&self, key: &str) -> Result<Vec<String>, AppError> {
+        Ok(self.conn()?.smembers::<&str, Vec<String>>(key)?)
+    }
+
+    fn add_to_sorted_set(&self, key: &str, member: &str, score: i64) -> Result<(), AppError> {
+        Ok(self
+            .conn()?
+            .zadd::<&str, i64, &str, ()>(key, member, score)?)
+    }
+
+    fn remove_by_score(&self, key: &str, max: i64) -> Result<(), AppError> {
+        Ok(self
+            .conn()?
+            .zrembyscore::<&str, &str, i64, ()>(key, "-inf", max)?)
+    }
+
+    fn sorted_set_members(&self, key: &str, min: i64) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .conn()?
+            .zrangebyscore::<&str, i64, &str, Vec<String>>(key, min, "+inf")?)
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, AppError> {
+        Ok(self.conn()?.del::<&str, i32>(key)? > 0)
+    }
+
+    fn rename(&self, key: &str, new_key: &str) -> Result<bool, AppError> {
+        let mut conn = self.conn()?;
+
+        let renamed = redis::Script::new(RENAME_SCRIPT)
+            .key(key)
+            .key(new_key)
+            .invoke::<i32>(&mut *conn)?;
+        Ok(renamed == 1)
+    }
+
+    fn expire(&self, key: &str, ttl: i64) -> Result<bool, AppError> {
+        Ok(self
+            .conn()?
+            .expire::<&str, bool>(key, ttl.max(1) as usize)?)
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<i64>, AppError> {
+        match self.conn()?.ttl::<&str, i64>(key)? {
+            ttl if ttl < 0 => Ok(None),
+            ttl => Ok(Some(ttl)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MemoryValue {
+    Str(String),
+    Hash(HashMap<String, String>),
+    Set(HashSet<String>),
+    SortedSet(HashMap<String, i64>),
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: MemoryValue,
+    expires_at: Option<Instant>,
+}
+
+/// [TokenStore] keeping everything in the memory of the process, meant for development and tests.
+/// Nothing is shared between processes and everything is lost on restart
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    entries: Mutex<HashMap<String, MemoryEntry>>,
+}
+
+fn expiry(ttl: i64) -> Option<Instant> {
+    Some(Instant::now() + Duration::from_secs(ttl.max(1) as u64))
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        MemoryTokenStore::default()
+    }
+
+    /** Locks the entries, removing every entry which expired */
+    fn entries(&self) -> Result<MutexGuard<'_, HashMap<String, MemoryEntry>>, AppError> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| AppError::InternalServerError)?;
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.expires_at.is_none_or(|at| at > now));
+
+        Ok(entries)
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn get(&self, key: &str) -> Result<Option<String>, AppError> {
+        match self.entries()?.get(key).map(|entry| &entry.value) {
+            Some(MemoryValue::Str(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(AppError::InternalServerError),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: i64) -> Result<(), AppError> {
+        self.entries()?.insert(
+            key.to_string(),
+            MemoryEntry {
+                value: MemoryValue::Str(value.to_string()),
+                expires_at: expiry(ttl),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn take(&self, key: &str) -> Result<Option<String>, AppError> {
+        let mut entries = self.entries()?;
+        match entries.get(key).map(|entry| &entry.value) {
+            Some(MemoryValue::Str(_)) => {}
+            Some(_) => return Err(AppError::InternalServerError),
+            None => return Ok(None),
+        }
+
+        match entries.remove(key).map(|entry| entry.value) {
+            Some(MemoryValue::Str(value)) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    fn increment(&self, key: &str, ttl: i64) -> Result<i64, AppError> {
+        let mut entries = self.entries()?;
+        let entry = entries.entry(key.to_string()).or_insert(MemoryEntry {
+            value: MemoryValue::Str("0".to_string()),
+            expires_at: None,
+        });
+
+        let count = match &entry.value {
+            MemoryValue::Str(value) => value.parse::<i64>()? + 1,
+            _ => return Err(AppError::InternalServerError),
+        };
+        entry.value = MemoryValue::Str(count.to_string());
+        entry.expires_at = expiry(ttl);
+
+        Ok(count)
+    }
+
+    fn set_if_greater(&self, key: &str, value: i64, ttl: i64) -> Result<bool, AppError> {
+        let mut entries = self.entries()?;
+        let current = match entries.get(key).map(|entry| &entry.value) {
+            Some(MemoryValue::Str(current)) => current.parse::<i64>().ok(),
+            Some(_) => return Err(AppError::InternalServerError),
+            None => None,
+        };
+        if current.is_some_and(|current| current >= value) {
+            return Ok(false);
+        }
+
+        entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                value: MemoryValue::Str(value.to_string()),
+                expires_at: expiry(ttl),
+            },
+        );
+        Ok(true)
+    }
+
+    fn get_field(&self, key: &str, field: &str) -> Result<Option<String>, AppError> {
+        match self.entries()?.get(key).map(|entry| &entry.value) {
+            Some(MemoryValue::Hash(hash)) => Ok(hash.get(field).cloned()),
+            Some(_) => Err(AppError::InternalServerError),
+            None => Ok(None),
+        }
+    }
+
+    fn set_hash(&self, key: &str, fields: &[(&str, String)], ttl: i64) -> Result<(), AppError> {
+        let hash = fields
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.clone()))
+            .collect();
+        self.entries()?.insert(
+            key.to_string(),
+            MemoryEntry {
+                value: MemoryValue::Hash(hash),
+                expires_at: expiry(ttl),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn set_field(&self, key: &str, field: &str, value: &str) -> Result<bool, AppError> {
+        match self.entries()?.get_mut(key).map(|entry| &mut entry.value) {
+            Some(MemoryValue::Hash(hash)) => {
+                hash.insert(field.to_string(), value.to_string());
+                Ok(true)
+            }
+            Some(_) => Err(AppError::InternalServerError),
+            None => Ok(false),
+        }
+    }
+
+    fn increment_field(&self, key: &str, field: &str) -> Result<Option<i64>, AppError> {
+        match self.entries()?.get_mut(key).map(|entry| &mut entry.value) {
+            Some(MemoryValue::Hash(hash)) => {
+                let count = hash
+                    .get(field)
+                    .map_or(Ok(0), |count| count.parse::<i64>())
+                    .map_err(|_| AppError::InternalServerError)?
+                    + 1;
+                hash.insert(field.to_string(), count.to_string());
+                Ok(Some(count))
+            }
+            Some(_) => Err(AppError::InternalServerError),
+            None => Ok(None),
+        }
+    }
+
+    fn compare_and_set_field(
+        &self,
+        key: &str,
+        field: &str,
+        expected: &str,
+        new: &str,
+    ) -> Result<bool, AppError> {
+        match self.entries()?.get_mut(key).map(|entry| &mut entry.value) {
+            Some(MemoryValue::Hash(hash)) => {
+                if hash.get(field).map(String::as_str) != Some(expected) {
+                    return Ok(false);
+                }
+                hash.insert(field.to_string(), new.to_string());
+                Ok(true)
+            }
+            Some(_) => Err(AppError::InternalServerError),
+            None => Ok(false),
+        }
+    }
+
+    fn add_to_set(&self, key: &str, member: &str, ttl: i64) -> Result<(), AppError> {
+        let mut entries = self.entries()?;
+        let entry = entries.entry(key.to_string()).or_insert(MemoryEntry {
+            value: MemoryValue::Set(HashSet::new()),
+            expires_at: None,
+        });
+
+        match &mut entry.value {
+            MemoryValue::Set(set) => {
+                set.insert(member.to_string());
+            }
+            _ => return Err(AppError::InternalServerError),
+        }
+        entry.expires_at = expiry(ttl);
+
+        Ok(())
+    }
+
+    fn remove_from_set(&self, key: &str, member: &str) -> Result<(), AppError> {
+        let mut entries = self.entries()?;
+        let empty = match entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(MemoryValue::Set(set)) => {
+                set.remove(member);
+                set.is_empty()
+            }
+            Some(_) => return Err(AppError::InternalServerError),
+            None => false,
+        };
+        //Redis removes sets once they're empty
+        if empty {
+            entries.remove(key);
+        }
+
+        Ok(())
+    }
+
+    fn set_members(&self, key: &str) -> Result<Vec<String>, AppError> {
+        match self.entries()?.get(key).map(|entry| &entry.value) {
+            Some(MemoryValue::Set(set)) => Ok(set.iter().cloned().collect()),
+            Some(_) => Err(AppError::InternalServerError),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn add_to_sorted_set(&self, key: &str, member: &str, score: i64) -> Result<(), AppError> {
+        let mut entries = self.entries()?;
+        let entry = entries.entry(key.to_string()).or_insert(MemoryEntry {
+            value: MemoryValue::SortedSet(HashMap::new()),
+            expires_at: None,
+        });
+
+        match &mut entry.value {
+            MemoryValue::SortedSet(set) => {
+                set.insert(member.to_string(), score);
+                Ok(())
+            }
+            _ => Err(AppError::InternalServerError),
+        }
+    }
+
+    fn remove_by_score(&self, key: &str, max: i64) -> Result<(), AppError> {
+        let mut entries = self.entries()?;
+        let empty = match entries.get_mut(key).map(|entry| &mut entry.value) {
+            Some(MemoryValue::SortedSet(set)) => {
+                set.retain(|_, score| *score > max);
+                set.is_empty()
+            }
+            Some(_) => return Err(AppError::InternalServerError),
+            None => false,
+        };
+        //Redis removes sorted sets once they're empty
+        if empty {
+            entries.remove(key);
+        }
+
+        Ok(())
+    }
+
+    fn sorted_set_members(&self, key: &str, min: i64) -> Result<Vec<String>, AppError> {
+        match self.entries()?.get(key).map(|entry| &entry.value) {
+            Some(MemoryValue::SortedSet(set)) => Ok(set
+                .iter()
+                .filter(|(_, score)| **score >= min)
+                .map(|(member, _)| member.clone())
+                .collect()),
+            Some(_) => Err(AppError::InternalServerError),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<bool, AppError> {
+        Ok(self.entries()?.remove(key).is_some())
+    }
+
+    fn rename(&self, key: &str, new_key: &str) -> Result<bool, AppError> {
+        let mut entries = self.entries()?;
+        if entries.contains_key(new_key) {
+            return Ok(false);
+        }
+
+        match entries.remove(key) {
+            Some(entry) => {
+                entries.insert(new_key.to_string(), entry);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn expire(&self, key: &str, ttl: i64) -> Result<bool, AppError> {
+        match self.entries()?.get_mut(key) {
+            Some(entry) => {
+                entry.expires_at = expiry(ttl);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<i64>, AppError> {
+        Ok(self
+            .entries()?
+            .get(key)
+            .and_then(|entry| entry.expires_at)
+            .map(|at| at.saturating_duration_since(Instant::now()).as_secs() as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_hash() {
+        let store = MemoryTokenStore::new();
+
+        store
+            .set_hash("token", &[("user_id", "123".to_string())], 60)
+            .unwrap();
+        debug_assert!(store.get_field("token", "user_id").unwrap() == Some("123".to_string()));
+        debug_assert!(store.set_field("token", "family", "abc").unwrap());
+        debug_assert!(!store.set_field("missing", "family", "abc").unwrap());
+        debug_assert!(store.increment_field("token", "attempts").unwrap() == Some(1));
+        debug_assert!(store.increment_field("token", "attempts").unwrap() == Some(2));
+        debug_assert!(store
+            .increment_field("missing", "attempts")
+            .unwrap()
+            .is_none());
+        debug_assert!(!store
+            .compare_and_set_field("token", "family", "xyz", "def")
+            .unwrap());
+        debug_assert!(store
+            .compare_and_set_field("token", "family", "abc", "def")
+            .unwrap());
+        debug_assert!(store.ttl("token").unwrap().unwrap() <= 60);
+        debug_assert!(store.rename("token", "renamed").unwrap());
+        debug_assert!(!store.rename("token", "renamed").unwrap());
+        debug_assert!(store.get_field("renamed", "user_id").unwrap() == Some("123".to_string()));
+        debug_assert!(store.ttl("renamed").unwrap().unwrap() <= 60);
+        debug_assert!(store.delete("renamed").unwrap());
+        debug_assert!(store.get_field("renamed", "user_id").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_store_expiry() {
+        let store = MemoryTokenStore::new();
+
+        debug_assert!(store.set_if_greater("step", 5, 60).unwrap());
+        debug_assert!(!store.set_if_greater("step", 5, 60).unwrap());
+        debug_assert!(!store.set_if_greater("step", 4, 60).unwrap());
+        debug_assert!(store.set_if_greater("step", 6, 60).unwrap());
+
+        store.set("short", "value", 1).unwrap();
+        store.add_to_set("set", "member", 60).unwrap();
+        debug_assert!(store.get("short").unwrap() == Some("value".to_string()));
+        debug_assert!(store.set_members("set").unwrap() == vec!["member".to_string()]);
+
+        std::thread::sleep(Duration::from_millis(1100));
+        debug_assert!(store.get("short").unwrap().is_none());
+
+        store.remove_from_set("set", "member").unwrap();
+        debug_assert!(store.ttl("set").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_store_sorted_set() {
+        let store = MemoryTokenStore::new();
+
+        store.add_to_sorted_set("sorted", "old", 10).unwrap();
+        store.add_to_sorted_set("sorted", "new", 20).unwrap();
+        debug_assert!(store.sorted_set_members("sorted", 15).unwrap() == vec!["new".to_string()]);
+        debug_assert!(store.sorted_set_members("sorted", 0).unwrap().len() == 2);
+
+        store.remove_by_score("sorted", 10).unwrap();
+        debug_assert!(store.sorted_set_members("sorted", 0).unwrap() == vec!["new".to_string()]);
+        store.remove_by_score("sorted", 20).unwrap();
+        debug_assert!(!store.delete("sorted").unwrap());
+    }
+}
@@ -0,0 +1,160 @@
+use actix_web::{web::Data, HttpRequest};
+use serde::Serialize;
+
+use crate::{
+    app::{config::ThrottleConfig, AppError, AppState},
+    auth::store::TokenStore,
+};
+
+/// Brute-force protection for logins.
+///
+/// Failed logins are counted per username and per client IP address, once a counter reaches its limit the
+/// username or address is locked. Every further failure after a lockout doubles the next one, up to the configured maximum.
+/// A successful login resets the counter of the username, the counter of the address only decays,
+/// so one valid account can't be used to keep guessing the passwords of others
+pub struct LoginThrottle {}
+
+/** Failures and remaining lockout of an username, as shown to administrators */
+#[derive(Debug, Serialize)]
+pub struct ThrottleStatus {
+    pub failures: i64,
+    /// Seconds until the lockout ends, `0` if it isn't locked
+    pub locked_for: i64,
+}
+
+fn failures_key(kind: &str, id: &str) -> String {
+    format!("login_failures:{}:{}", kind, id)
+}
+fn lock_key(kind: &str, id: &str) -> String {
+    format!("login_lock:{}:{}", kind, id)
+}
+
+impl LoginThrottle {
+    /** Returns the IP address the request was sent from. Behind a [trusted proxy](ThrottleConfig::trusted_proxy)
+     * it's the address the proxy forwarded, otherwise the address of the peer
+     */
+    pub fn client_ip(req: &HttpRequest) -> String {
+        let trusted_proxy = req
+            .app_data::<Data<AppState>>()
+            .is_some_and(|app_state| app_state.throttle_config.trusted_proxy);
+        if trusted_proxy {
+            //Forwarded addresses may carry a port, like the peer address does
+            if let Some(addr) = req.connection_info().realip_remote_addr() {
+                return addr
+                    .parse::<std::net::SocketAddr>()
+                    .map(|addr| addr.ip().to_string())
+                    .unwrap_or_else(|_| addr.to_string());
+            }
+        }
+
+        req.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /** Fails with [TooManyRequests](AppError::TooManyRequests) if the username or the address is locked */
+    pub fn check(store: &dyn TokenStore, username: &str, ip: &str) -> Result<(), AppError> {
+        let user_lock = store.ttl(&lock_key("user", username))?;
+        let ip_lock = store.ttl(&lock_key("ip", ip))?;
+
+        match user_lock.max(ip_lock) {
+            Some(locked_for) => Err(AppError::TooManyRequests(locked_for)),
+            None => Ok(()),
+        }
+    }
+
+    /** Counts a failed login, returns for how many seconds the username or address is locked now, `0` if it isn't */
+    pub fn record_failure(
+        store: &dyn TokenStore,
+        username: &str,
+        ip: &str,
+        config: &ThrottleConfig,
+    ) -> Result<i64, AppError> {
+        let mut locked_for = 0;
+
+        for (kind, id, max_failures) in [
+            ("user", username, config.max_failures),
+            ("ip", ip, config.max_ip_failures),
+        ] {
+            let failures = store.increment(&failures_key(kind, id), config.window)?;
+            let lockout = config.lockout_for(failures, max_failures);
+            if lockout > 0 {
+                store.set(&lock_key(kind, id), &failures.to_string(), lockout)?;
+                //The failures have to outlive the lockout, otherwise the next one wouldn't be longer
+                store.expire(&failures_key(kind, id), lockout + config.window)?;
+            }
+            locked_for = locked_for.max(lockout);
+        }
+
+        Ok(locked_for)
+    }
+
+    /** Forgets the failures of the username and lifts its lockout */
+    pub fn reset(store: &dyn TokenStore, username: &str) -> Result<(), AppError> {
+        store.delete(&failures_key("user", username))?;
+        store.delete(&lock_key("user", username))?;
+
+        Ok(())
+    }
+
+    /** Returns the failures and remaining lockout of the username */
+    pub fn status(store: &dyn TokenStore, username: &str) -> Result<ThrottleStatus, AppError> {
+        let failures = store
+            .get(&failures_key("user", username))?
+            .map(|failures| failures.parse::<i64>())
+            .transpose()?
+            .unwrap_or(0);
+
+        Ok(ThrottleStatus {
+            failures,
+            locked_for: store.ttl(&lock_key("user", username))?.unwrap_or(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::auth::store::MemoryTokenStore;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_lockout() {
+        let store = MemoryTokenStore::new();
+        let config = ThrottleConfig::default();
+
+        for _ in 0..config.max_failures - 1 {
+            debug_assert!(
+                LoginThrottle::record_failure(&store, "user", "ip", &config).unwrap() == 0
+            );
+        }
+        debug_assert!(LoginThrottle::check(&store, "user", "ip").is_ok());
+
+        debug_assert!(LoginThrottle::record_failure(&store, "user", "ip", &config).unwrap() > 0);
+        debug_assert!(LoginThrottle::check(&store, "user", "other ip").is_err());
+        debug_assert!(LoginThrottle::status(&store, "user").unwrap().failures == 5);
+
+        LoginThrottle::reset(&store, "user").unwrap();
+        debug_assert!(LoginThrottle::check(&store, "user", "other ip").is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let mut app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        let request = |app_state: &AppState| {
+            TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .insert_header(("X-Forwarded-For", "203.0.113.7"))
+                .app_data(Data::new(app_state.clone()))
+                .to_http_request()
+        };
+
+        //Without a trusted proxy the forwarded address could be set by anyone
+        debug_assert!(LoginThrottle::client_ip(&request(&app_state)) == "127.0.0.1");
+
+        app_state.throttle_config.trusted_proxy = true;
+        debug_assert!(LoginThrottle::client_ip(&request(&app_state)) == "203.0.113.7");
+    }
+}
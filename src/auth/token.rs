@@ -1,70 +1,392 @@
-use diesel::r2d2::PooledConnection;
-use r2d2_redis::{
-    redis::{Commands, RedisError},
-    RedisConnectionManager,
-};
+use actix_web::{http::header::Header, HttpRequest};
+use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
 use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::{
+    app::{config::SessionConfig, AppError},
+    auth::{
+        audit, cookie::CookieConfig, refresh::RefreshToken, store::TokenStore,
+        throttle::LoginThrottle,
+    },
+};
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Seconds between two updates of the last use of a token, so busy sessions don't write on every request
+const TOUCH_INTERVAL: i64 = 60;
+/// Length of a login token, tokens stored under their raw value are only migrated if they have this shape
+const TOKEN_LENGTH: usize = 32;
+
+/// Opaque login tokens.
+///
+/// A session is stored under the keyed hash of its token (see [Token::key]), so read access to the store
+/// or a copy of it doesn't allow logging in. Sessions of tokens issued before, which are stored under the token itself,
+/// are moved to their hashed key the first time they're used. Functions taking a `key` expect the hashed key,
+/// as returned by [Token::key] and [Token::find_by_user]
 pub struct Token {}
 
+/** Address and user agent of the client a session is used from */
+#[derive(Debug, Clone, Default)]
+pub struct SessionClient {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl SessionClient {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        SessionClient {
+            ip: Some(LoginThrottle::client_ip(req)),
+            user_agent: audit::user_agent(req),
+        }
+    }
+}
+
+/** Administrator acting as the user of an impersonation session, see [Token::new_impersonation] */
+#[derive(Debug, Clone)]
+pub struct Impersonator {
+    pub admin_id: String,
+    /// Id of the [audit record](crate::database::models::impersonation::Impersonation) of the session
+    pub record_id: String,
+}
+
+/** Details of a session shown to its user, so unfamiliar devices can be recognized */
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionMetadata {
+    /// Unix timestamp of the login
+    pub created_at: i64,
+    /// Unix timestamp of the last request, accurate to a minute
+    pub last_used: i64,
+    /// Address and user agent of the last client which used the session
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/** Keyed hash of a secret, used instead of the secret as key in the token store */
+pub fn hash_secret(config: &SessionConfig, secret: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(config.token_hash_key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(secret.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/** Returns the redis key of the set holding the keys of all sessions of the user */
+fn user_sessions_key(user_id: &str) -> String {
+    format!("sessions:{}", user_id)
+}
+
 impl Token {
-    /** Generates a new token of aphanumeric type and length of 32 characters. Automatically inserts it into the redis database specified*/
-    pub fn new(
-        redis_conn: &mut PooledConnection<RedisConnectionManager>,
-        user_id: &String,
-    ) -> String {
-        let mut str = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    /** Returns the token sent in the `Authorization: Bearer <token>` header, if there is one */
+    pub fn from_header(req: &HttpRequest) -> Option<String> {
+        Authorization::<Bearer>::parse(req)
+            .ok()
+            .map(|auth| auth.into_scheme().token().to_string())
+    }
+
+    /** Returns the token the request was authenticated with, a bearer token takes precedence over the login token cookie */
+    pub fn from_request(req: &HttpRequest, cookies: &CookieConfig) -> Option<String> {
+        Token::from_header(req)
+            .or_else(|| req.cookie(&cookies.name()).map(|c| c.value().to_string()))
+    }
+
+    /** Returns the key the session of the token is stored under */
+    pub fn key(config: &SessionConfig, token: &str) -> String {
+        format!("session:{}", hash_secret(config, token))
+    }
+
+    /** Generates a new token of aphanumeric type and length of 32 characters. Automatically inserts its session into the token store specified,
+     * the session lives for the TTL configured in `config`
+     */
+    pub fn new(store: &dyn TokenStore, user_id: &str, config: &SessionConfig) -> String {
+        let mut str = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
         let mut iters = 0;
-        while Token::find(redis_conn, &str).is_ok() {
-            str = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        while Token::owner(store, &Token::key(config, &str)).is_ok() {
+            str = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
             if iters > 200 {
                 return "".to_string();
             }
             iters += 1;
         }
 
-        let _res = redis_conn.set_ex::<&String, &String, i32>(&str, user_id, 180);
+        let key = Token::key(config, &str);
+        let now = Utc::now().timestamp();
+        let _res = store.set_hash(
+            &key,
+            &[
+                ("user_id", user_id.to_string()),
+                ("created_at", now.to_string()),
+                ("last_used", now.to_string()),
+            ],
+            config.remaining_ttl(now, now),
+        );
+        let _res = store.add_to_set(&user_sessions_key(user_id), &key, config.max_age);
 
         str
     }
 
-    /** Deletes a token from the database. If the token does not exist, it does nothing
+    /** Creates a token of the user for an administrator acting as them. The token lives for `ttl` seconds
+     * and is never refreshed, it's listed among the sessions of the user like any other
      */
-    pub fn delete(redis_conn: &mut PooledConnection<RedisConnectionManager>, token: &String) {
-        match redis_conn.get::<String, String>(token.clone()) {
-            Ok(_) => {
-                let _res = redis_conn.del::<String, i32>(token.clone());
+    pub fn new_impersonation(
+        store: &dyn TokenStore,
+        user_id: &str,
+        config: &SessionConfig,
+        impersonator: &Impersonator,
+        ttl: i64,
+    ) -> String {
+        let token = Token::new(store, user_id, config);
+        let key = Token::key(config, &token);
+        let _res = store.set_field(&key, "impersonator", &impersonator.admin_id);
+        let _res = store.set_field(&key, "impersonation", &impersonator.record_id);
+        let _res = store.expire(&key, ttl);
+
+        token
+    }
+
+    /** Returns the administrator using the session, if it's an impersonation session */
+    pub fn impersonator(store: &dyn TokenStore, key: &str) -> Option<Impersonator> {
+        Some(Impersonator {
+            admin_id: store.get_field(key, "impersonator").ok()??,
+            record_id: store.get_field(key, "impersonation").ok()??,
+        })
+    }
+
+    /** Deletes a session from the store. If the session does not exist, it does nothing
+     */
+    pub fn delete(store: &dyn TokenStore, key: &str) {
+        if let Ok(user_id) = Token::owner(store, key) {
+            let _res = store.remove_from_set(&user_sessions_key(&user_id), key);
+        }
+        let _res = store.delete(key);
+    }
+
+    /** Returns the keys of all live sessions of the user, sessions which already expired are removed from the user's session set */
+    pub fn find_by_user(store: &dyn TokenStore, user_id: &String) -> Result<Vec<String>, AppError> {
+        let sessions_key = user_sessions_key(user_id);
+        let keys = store.set_members(&sessions_key)?;
+
+        let mut live = Vec::new();
+        for key in keys {
+            match Token::owner(store, &key) {
+                Ok(owner) if &owner == user_id => live.push(key),
+                _ => {
+                    let _res = store.remove_from_set(&sessions_key, &key);
+                }
             }
-            Err(_) => return,
         }
+
+        Ok(live)
     }
 
-    /** Returns `user_id` if found, and if not returns an error */
+    /** Deletes every session of the user, logging them out everywhere */
+    pub fn delete_by_user(store: &dyn TokenStore, user_id: &String) {
+        let sessions_key = user_sessions_key(user_id);
+        let keys = store.set_members(&sessions_key).unwrap_or_default();
+
+        for key in keys {
+            if matches!(Token::owner(store, &key), Ok(owner) if &owner == user_id) {
+                let _res = store.delete(&key);
+            }
+        }
+        let _res = store.delete(&sessions_key);
+    }
+
+    /** Returns an identifier of the session which is safe to show to the user */
+    pub fn session_id(key: &str) -> String {
+        sha256::digest(key)[..16].to_string()
+    }
+
+    /** Returns `user_id` of the session without recording a use, for lookups which aren't requests of the session */
+    fn owner(store: &dyn TokenStore, key: &str) -> Result<String, AppError> {
+        store
+            .get_field(key, "user_id")?
+            .ok_or(AppError::UnauthorizedError)
+    }
+
+    /** Moves a session stored under its raw token, as sessions were before their keys were hashed, to its hashed key.
+     * The session is renamed in one step, so of two requests migrating it at the same time only one moves it
+     */
+    fn migrate(store: &dyn TokenStore, token: &str, key: &str) {
+        //Other keys of the store are prefixed, so they can't be mistaken for a session
+        if token.len() != TOKEN_LENGTH || !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return;
+        }
+        let user_id = match (
+            store.get_field(token, "user_id"),
+            store.get_field(token, "created_at"),
+        ) {
+            (Ok(Some(user_id)), Ok(Some(_))) => user_id,
+            _ => return,
+        };
+        if !matches!(store.rename(token, key), Ok(true)) {
+            return;
+        }
+        let ttl = store.ttl(key).ok().flatten().unwrap_or(0);
+
+        //The set has to keep living as long as the longest session in it
+        let sessions_key = user_sessions_key(&user_id);
+        let sessions_ttl = store.ttl(&sessions_key).ok().flatten().unwrap_or(0);
+        let _res = store.remove_from_set(&sessions_key, token);
+        let _res = store.add_to_set(&sessions_key, key, sessions_ttl.max(ttl));
+        if let Ok(Some(family)) = store.get_field(key, "family") {
+            let _res = RefreshToken::attach(store, &family, key);
+        }
+    }
+
+    /** Returns the key and `user_id` of the session of the token, migrating the session if it's still stored under the token */
+    fn lookup(
+        store: &dyn TokenStore,
+        token: &str,
+        config: &SessionConfig,
+    ) -> Result<(String, String), AppError> {
+        let key = Token::key(config, token);
+        match Token::owner(store, &key) {
+            Ok(user_id) => Ok((key, user_id)),
+            //Another request may have migrated the session meanwhile, so the key is looked up again either way
+            Err(_) => {
+                Token::migrate(store, token, &key);
+                let user_id = Token::owner(store, &key)?;
+                Ok((key, user_id))
+            }
+        }
+    }
+
+    /** Records the client which used the session, replacing the previous one */
+    pub fn record_client(store: &dyn TokenStore, key: &str, client: &SessionClient) {
+        if let Some(ip) = &client.ip {
+            let _res = store.set_field(key, "ip", ip);
+        }
+        if let Some(user_agent) = &client.user_agent {
+            let _res = store.set_field(key, "user_agent", user_agent);
+        }
+    }
+
+    /** Records a use of the session, and of the client if known, at most once per [TOUCH_INTERVAL] */
+    fn touch(store: &dyn TokenStore, key: &str, client: Option<&SessionClient>) {
+        let now = Utc::now().timestamp();
+        let last_used = store.get_field(key, "last_used").ok().flatten();
+        let updated = match &last_used {
+            //Only one of concurrent requests updates the session
+            Some(last_used) => match last_used.parse::<i64>() {
+                Ok(time) if now - time < TOUCH_INTERVAL => false,
+                _ => store
+                    .compare_and_set_field(key, "last_used", last_used, &now.to_string())
+                    .unwrap_or(false),
+            },
+            //Sessions created before sessions kept their last use
+            None => store
+                .set_field(key, "last_used", &now.to_string())
+                .unwrap_or(false),
+        };
+
+        if let (true, Some(client)) = (updated, client) {
+            Token::record_client(store, key, client);
+        }
+    }
+
+    /** Returns `user_id` if found, and if not returns an Unauthorized error. Records the use of the token */
     pub fn find(
-        redis_conn: &mut PooledConnection<RedisConnectionManager>,
-        token: &String,
-    ) -> Result<String, RedisError> {
-        redis_conn.get::<&String, String>(token)
+        store: &dyn TokenStore,
+        token: &str,
+        config: &SessionConfig,
+    ) -> Result<String, AppError> {
+        let (key, user_id) = Token::lookup(store, token, config)?;
+        Token::touch(store, &key, None);
+
+        Ok(user_id)
+    }
+
+    /** Returns `user_id` of the token like [`Token::find`] and records the client which sent the request,
+     * when sliding sessions are enabled it also [refreshes](Token::refresh) the token and returns the seconds it will live for.
+     * `from_cookie` tells whether the token was sent in the login token cookie
+     */
+    pub fn authenticate(
+        store: &dyn TokenStore,
+        token: &str,
+        config: &SessionConfig,
+        client: &SessionClient,
+        from_cookie: bool,
+    ) -> Result<(String, Option<i64>), AppError> {
+        let (key, user_id) = Token::lookup(store, token, config)?;
+        Token::touch(store, &key, Some(client));
+        let ttl = if config.sliding {
+            Token::refresh(store, token, config, from_cookie)
+        } else {
+            None
+        };
+
+        Ok((user_id, ttl))
     }
 
-    /** Refreshes the token for 180 seconds if token is found
-     * If the token is not found or refreshed it returns `false`, if it's successfully refreshed it returns `true`
+    /** Returns the [refresh token](crate::auth::refresh::RefreshToken) family which issued the session, if any */
+    pub fn family(store: &dyn TokenStore, key: &str) -> Option<String> {
+        store.get_field(key, "family").ok().flatten()
+    }
+
+    /** Returns the unix timestamp of when the session was created */
+    pub fn created_at(store: &dyn TokenStore, key: &str) -> Result<i64, AppError> {
+        store
+            .get_field(key, "created_at")?
+            .ok_or(AppError::UnauthorizedError)?
+            .parse::<i64>()
+            .map_err(|_| AppError::InternalServerError)
+    }
+
+    /** Returns the details of the session */
+    pub fn metadata(store: &dyn TokenStore, key: &str) -> Result<SessionMetadata, AppError> {
+        let created_at = Token::created_at(store, key)?;
+        let last_used = store
+            .get_field(key, "last_used")?
+            .and_then(|time| time.parse::<i64>().ok())
+            .unwrap_or(created_at);
+
+        Ok(SessionMetadata {
+            created_at,
+            last_used,
+            ip: store.get_field(key, "ip")?,
+            user_agent: store.get_field(key, "user_agent")?,
+        })
+    }
+
+    /** Refreshes the token for the configured TTL if token is found, the token never outlives the configured maximum age
+     * If the token is not found or refreshed it returns `None`, if it's successfully refreshed it returns the seconds it will live for.
+     * Impersonation sessions are never refreshed. Sessions issued through a refresh token are only refreshed if the token
+     * was sent in the login token cookie (`from_cookie`), clients using the `Authorization` header hold the refresh token
+     * and have to present it, so a leaked bearer token stays short lived
      */
     pub fn refresh(
-        redis_conn: &mut PooledConnection<RedisConnectionManager>,
-        token: &String,
-    ) -> bool {
-        let token = Token::find(redis_conn, token);
-        if token.is_err() {
-            return false;
+        store: &dyn TokenStore,
+        token: &str,
+        config: &SessionConfig,
+        from_cookie: bool,
+    ) -> Option<i64> {
+        let (key, _user_id) = Token::lookup(store, token, config).ok()?;
+        if Token::impersonator(store, &key).is_some()
+            || (!from_cookie && Token::family(store, &key).is_some())
+        {
+            return None;
         }
-        let token = token.unwrap();
-
-        let res = redis_conn.expire::<&String, i32>(&token, 180);
-        if res.is_err() {
-            return false;
+        let created_at = Token::created_at(store, &key).ok()?;
+        let ttl = config.remaining_ttl(created_at, Utc::now().timestamp());
+        if ttl == 0 {
+            Token::delete(store, &key);
+            return None;
         }
 
-        return true;
+        match store.expire(&key, ttl) {
+            Ok(true) => {
+                Token::touch(store, &key, None);
+                Some(ttl)
+            }
+            _ => None,
+        }
     }
 }
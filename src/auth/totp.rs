@@ -0,0 +1,218 @@
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, Nonce,
+};
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+    RngCore,
+};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    app::{config::TwoFactorConfig, AppError},
+    auth::password::constant_time_eq,
+};
+
+/// Seconds each TOTP code is valid for
+const STEP: i64 = 30;
+/// Number of digits of a TOTP code
+const DIGITS: u32 = 6;
+/// Name of the site shown in authenticator apps
+const ISSUER: &str = "blogsite";
+/// Number of recovery codes handed out when 2FA is enabled
+const RECOVERY_CODES: usize = 10;
+
+const BASE32: Alphabet = Alphabet::RFC4648 { padding: false };
+/// Prefix of secrets encrypted by [seal_secret], secrets stored before they were encrypted don't have it
+const SEALED_PREFIX: &str = "v1:";
+/// Bytes of the random AES-GCM nonce stored in front of the ciphertext
+const NONCE_LENGTH: usize = 12;
+
+/** Generates a random 160 bit TOTP secret, base32 encoded */
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+
+    base32::encode(BASE32, &secret)
+}
+
+fn cipher(config: &TwoFactorConfig) -> Aes256Gcm {
+    let key = Sha256::digest(config.encryption_key.as_bytes());
+
+    <Aes256Gcm as aes_gcm::KeyInit>::new(&key)
+}
+
+/** Encrypts a secret for storing it with the user. The user id is authenticated along with it,
+ * so a secret copied to another user doesn't decrypt
+ */
+pub fn seal_secret(
+    config: &TwoFactorConfig,
+    user_id: &str,
+    secret: &str,
+) -> Result<String, AppError> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    let payload = Payload {
+        msg: secret.as_bytes(),
+        aad: user_id.as_bytes(),
+    };
+    let mut sealed = cipher(config)
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| AppError::InternalServerError)?;
+    sealed.splice(0..0, nonce);
+
+    Ok(format!("{}{}", SEALED_PREFIX, base64::encode(sealed)))
+}
+
+/** Decrypts a secret stored with the user, secrets stored before they were encrypted are returned as they are */
+pub fn open_secret(config: &TwoFactorConfig, user_id: &str, stored: &str) -> Option<String> {
+    let sealed = match stored.strip_prefix(SEALED_PREFIX) {
+        Some(sealed) => base64::decode(sealed).ok()?,
+        None => return Some(stored.to_string()),
+    };
+    if sealed.len() <= NONCE_LENGTH {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: user_id.as_bytes(),
+    };
+    let secret = cipher(config)
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .ok()?;
+
+    String::from_utf8(secret).ok()
+}
+
+/** Returns whether the stored secret is encrypted */
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(SEALED_PREFIX)
+}
+
+/** Returns the `otpauth://` URI authenticator apps use to add the secret, usually shown as a QR code */
+pub fn provisioning_uri(secret: &str, username: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER,
+        user = encode_uri_component(username),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP,
+    )
+}
+
+/** Returns the RFC 4226 HOTP code of the key for the counter */
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/** Checks the code against the secret at unix time `now`, allowing one step of clock drift in each direction.
+ * Returns the time step the code belongs to, so callers can refuse codes which were already used
+ */
+pub fn verify(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let key = base32::decode(BASE32, secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / STEP;
+    (current - 1..=current + 1)
+        .find(|step| constant_time_eq(code_for_step(&key, *step).as_bytes(), code.as_bytes()))
+}
+
+/** Returns the zero padded code for the time step */
+fn code_for_step(key: &[u8], step: i64) -> String {
+    format!(
+        "{:0width$}",
+        hotp(key, step as u64),
+        width = DIGITS as usize
+    )
+}
+
+/** Returns the code an authenticator app would show at unix time `now` */
+#[cfg(test)]
+pub fn generate_code(secret: &str, now: i64) -> Option<String> {
+    let key = base32::decode(BASE32, secret)?;
+
+    Some(code_for_step(&key, now / STEP))
+}
+
+/** Generates a fresh set of recovery codes formatted as `xxxxx-xxxxx` */
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = Alphanumeric
+                .sample_string(&mut rand::thread_rng(), 10)
+                .to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/** Percent encodes everything except unreserved characters */
+fn encode_uri_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        //The SHA1 test secret of RFC 6238 appendix B, truncated to 6 digits
+        let secret = base32::encode(BASE32, b"12345678901234567890");
+
+        debug_assert!(verify(&secret, "287082", 59) == Some(1));
+        debug_assert!(verify(&secret, "081804", 1111111109) == Some(37037036));
+        debug_assert!(verify(&secret, "005924", 1234567890) == Some(41152263));
+        debug_assert!(verify(&secret, "287082", 1234567890).is_none());
+        debug_assert!(verify(&secret, "28708", 59).is_none());
+    }
+
+    #[test]
+    fn test_sealed_secret() {
+        let config = TwoFactorConfig::default();
+        let secret = generate_secret();
+
+        let sealed = seal_secret(&config, "user", &secret).unwrap();
+        debug_assert!(is_sealed(&sealed) && !sealed.contains(&secret));
+        debug_assert!(open_secret(&config, "user", &sealed) == Some(secret.clone()));
+        debug_assert!(open_secret(&config, "other user", &sealed).is_none());
+        debug_assert!(open_secret(&TwoFactorConfig::default(), "user", &sealed).is_none());
+        //Secrets stored before encryption still work
+        debug_assert!(open_secret(&config, "user", &secret) == Some(secret.clone()));
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        let uri = provisioning_uri("ABC", "some user");
+        debug_assert!(uri.starts_with("otpauth://totp/blogsite:some%20user?secret=ABC"));
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::BufRead;
+
+use crate::{
+    app::{AppError, AppState},
+    auth::{
+        audit,
+        password::hash_password,
+        password_policy::{check_password_blocking, PasswordRejection},
+        throttle::LoginThrottle,
+    },
+    database::models::{auth_event::AuthEventKind, role::Role, user::*},
+    routes::token::revoke_user_sessions,
+};
+
+const USAGE: &str = "Usage: blogsite admin <command>
+
+Commands:
+    create <username>                 creates an administrator, the password is read from stdin
+    promote <username> [role]         gives the user a role, `admin` by default
+    demote <username>                 makes the user an author
+    reset-password <username>         replaces the password with one read from stdin and logs the user out";
+
+/** Reads the first line of the input, the password is never taken from the arguments so it doesn't end up in the shell history.
+ * The password has to follow the password policy like passwords chosen by the users
+ */
+fn read_password(
+    app_state: &AppState,
+    username: &str,
+    input: &mut dyn BufRead,
+) -> Result<String, String> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    input
+        .read_line(&mut password)
+        .map_err(|err| format!("Couldn't read the password: {}", err))?;
+    let password = password.trim().to_string();
+
+    match check_password_blocking(&app_state.password_config, &password, Some(username)) {
+        Ok(()) => Ok(password),
+        Err(AppError::PasswordRejected(rejections)) => Err(rejections
+            .iter()
+            .map(PasswordRejection::describe)
+            .collect::<Vec<_>>()
+            .join("\n")),
+        Err(_) => Err("Couldn't check the password against the breached password list".to_string()),
+    }
+}
+
+fn find_user(app_state: &AppState, username: &String) -> Result<User, String> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| "Couldn't connect to the database".to_string())?;
+
+    User::find_by_username(Some(&conn), username)
+        .ok_or_else(|| format!("No user named '{}'", username))
+}
+
+/// Runs the `blogsite admin` subcommand with the arguments following `admin`,
+/// returns the message to print or the error to print before exiting
+///
+/// # Example
+/// ```
+/// let app_state = AppState::new(Some(1));
+/// let message = run(&app_state, &["promote".to_string(), "username".to_string()], &mut stdin().lock())?;
+/// ```
+pub fn run(
+    app_state: &AppState,
+    args: &[String],
+    input: &mut dyn BufRead,
+) -> Result<String, String> {
+    let (command, username) = match args {
+        [command, username, ..] => (command.as_str(), username),
+        _ => return Err(USAGE.to_string()),
+    };
+    let db_error = |_| "The database rejected the change".to_string();
+
+    match command {
+        "create" => {
+            let password = read_password(app_state, username, input)?;
+            let conn = app_state
+                .psql_pool
+                .get()
+                .map_err(|_| "Couldn't connect to the database".to_string())?;
+            if User::find_by_username(Some(&conn), username).is_some() {
+                return Err(format!("The username '{}' is already taken", username));
+            }
+
+            let pw_hash = hash_password(&password).map_err(|_| "Couldn't hash the password")?;
+            User::new(Some(&conn), username, &pw_hash, Role::Admin).map_err(db_error)?;
+            Ok(format!("Created administrator '{}'", username))
+        }
+        "promote" | "demote" => {
+            let role = match (command, args.get(2)) {
+                ("demote", _) => Role::Author,
+                (_, Some(role)) => role
+                    .parse::<Role>()
+                    .map_err(|_| format!("Unknown role '{}'\n\n{}", role, USAGE))?,
+                (_, None) => Role::Admin,
+            };
+            let mut user = find_user(app_state, username)?;
+            let conn = app_state
+                .psql_pool
+                .get()
+                .map_err(|_| "Couldn't connect to the database".to_string())?;
+
+            user.set_role(Some(&conn), role).map_err(db_error)?;
+            Ok(format!("'{}' is now {}", username, role))
+        }
+        "reset-password" => {
+            let mut user = find_user(app_state, username)?;
+            let password = read_password(app_state, username, input)?;
+            let conn = app_state
+                .psql_pool
+                .get()
+                .map_err(|_| "Couldn't connect to the database".to_string())?;
+
+            let pw_hash = hash_password(&password).map_err(|_| "Couldn't hash the password")?;
+            user.set_password(Some(&conn), &pw_hash).map_err(db_error)?;
+            revoke_user_sessions(app_state, &user.id)
+                .map_err(|_| "The password was changed, but the sessions couldn't be revoked")?;
+            LoginThrottle::reset(app_state.token_store.as_ref(), username)
+                .map_err(|_| "The password was changed, but the login couldn't be unlocked")?;
+            audit::record(
+                app_state,
+                None,
+                AuthEventKind::PasswordChange,
+                Some(&user.id),
+                Some(&user.username),
+            );
+            Ok(format!("Changed the password of '{}'", username))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{password::verify_password, store::MemoryTokenStore};
+    use std::{io::Cursor, sync::Arc};
+
+    #[test]
+    fn test_admin_command() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let mut no_input = Cursor::new("");
+
+        let result = run(
+            &appstate,
+            &args(&["create", "Test cli admin"]),
+            &mut Cursor::new("first_password\n"),
+        );
+        debug_assert!(result.is_ok());
+        let usr = find_user(&appstate, &"Test cli admin".to_string()).unwrap();
+        debug_assert!(usr.role == "admin");
+
+        let result = run(
+            &appstate,
+            &args(&["demote", "Test cli admin"]),
+            &mut no_input,
+        );
+        debug_assert!(result.is_ok());
+        debug_assert!(find_user(&appstate, &usr.username).unwrap().role == "author");
+
+        let result = run(
+            &appstate,
+            &args(&["promote", "Test cli admin", "moderator"]),
+            &mut no_input,
+        );
+        debug_assert!(result.is_ok());
+        debug_assert!(find_user(&appstate, &usr.username).unwrap().role == "moderator");
+
+        //Too short passwords are rejected
+        let result = run(
+            &appstate,
+            &args(&["reset-password", "Test cli admin"]),
+            &mut Cursor::new("short\n"),
+        );
+        debug_assert!(result.is_err());
+        let result = run(
+            &appstate,
+            &args(&["reset-password", "Test cli admin"]),
+            &mut Cursor::new("second_password\n"),
+        );
+        debug_assert!(result.is_ok());
+        let usr = find_user(&appstate, &usr.username).unwrap();
+        debug_assert!(verify_password("second_password", &usr.pass).is_valid());
+
+        debug_assert!(run(&appstate, &args(&["unknown", "user"]), &mut no_input).is_err());
+        usr.delete(Some(&appstate.psql_pool.get().unwrap()));
+    }
+}
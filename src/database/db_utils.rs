@@ -5,6 +5,8 @@ use r2d2_redis::RedisConnectionManager;
 use std::env;
 use std::sync::Arc;
 
+use crate::auth::store::{MemoryTokenStore, RedisTokenStore, TokenStore};
+
 /// Return a pool to the hosted postgresql database.
 /// Requires a `DATABASE_URL` as a variable in enviroment.
 /// It takes argument `Option<u32>` as a number of max connections, Some(x) for x connections and None for unlimited
@@ -45,3 +47,23 @@ pub fn redis_connect_to_db(max_size: Option<u32>) -> Arc<Pool<RedisConnectionMan
         None => Arc::new(Pool::new(redis_manager).unwrap()),
     }
 }
+
+/// Return the token store selected by the `TOKEN_STORE` variable in enviroment.
+/// `redis` (the default) connects to the redis database like [redis_connect_to_db],
+/// `memory` keeps tokens inside the process and requires no database
+/// # Example
+/// ```
+/// let token_store: Arc<dyn TokenStore> = token_store_connect(None);
+/// ```
+pub fn token_store_connect(max_size: Option<u32>) -> Arc<dyn TokenStore> {
+    dotenv().ok();
+
+    match env::var("TOKEN_STORE").as_deref() {
+        Ok("memory") => Arc::new(MemoryTokenStore::new()),
+        Ok("redis") | Err(_) => Arc::new(RedisTokenStore::new(redis_connect_to_db(max_size))),
+        Ok(other) => panic!(
+            "Enviroment variable: 'TOKEN_STORE' has unknown value '{}'",
+            other
+        ),
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    app::AppError,
+    schema::{self, api_keys},
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+
+/// Every API key starts with this prefix, so the authentication layer can tell keys and login tokens apart
+pub const API_KEY_PREFIX: &str = "bsk_";
+/// Characters of the key shown in listings, so users can recognize their keys
+const SHOWN_PREFIX_LENGTH: usize = 12;
+
+/// Named key an user created for automation clients, it authenticates as the user but only for its scopes.
+/// Only the SHA256 digest of the key is stored, the key itself is shown once at creation
+#[derive(Debug, Queryable, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    pub name: String,
+    /// First characters of the key
+    pub prefix: String,
+    #[serde(skip)]
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "api_keys"]
+struct ApiKeyInsert {
+    pub user_id: String,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/** Keys are long random strings, so an unsalted digest is enough to protect them */
+fn hash_key(key: &str) -> String {
+    sha256::digest(key.to_string())
+}
+
+impl ApiKey {
+    /** Creates a key for the user, returns the stored record and the key, which can't be recovered later */
+    pub fn new(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &str,
+        key_name: &str,
+        key_scopes: &[String],
+        expires: Option<NaiveDateTime>,
+    ) -> Result<(ApiKey, String), AppError> {
+        if key_name.trim().is_empty() {
+            return Err(AppError::BadRequest);
+        }
+
+        let key = format!(
+            "{}{}",
+            API_KEY_PREFIX,
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 40)
+        );
+        let record = ApiKeyInsert {
+            user_id: user.to_string(),
+            name: key_name.trim().to_string(),
+            prefix: key[..SHOWN_PREFIX_LENGTH].to_string(),
+            key_hash: hash_key(&key),
+            scopes: key_scopes.to_vec(),
+            expires_at: expires,
+        };
+
+        let api_key = diesel::insert_into(schema::api_keys::table)
+            .values(&record)
+            .get_result::<ApiKey>(conn)?;
+
+        Ok((api_key, key))
+    }
+
+    /** Returns whether the token has the format of an API key */
+    pub fn is_api_key(token: &str) -> bool {
+        token.starts_with(API_KEY_PREFIX)
+    }
+
+    /** Returns the unexpired key matching the token and records its use */
+    pub fn authenticate(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        token: &str,
+    ) -> Result<ApiKey, AppError> {
+        use schema::api_keys::dsl::*;
+
+        let mut api_key = api_keys
+            .filter(key_hash.eq(hash_key(token)))
+            .first::<ApiKey>(conn)
+            .optional()?
+            .ok_or(AppError::UnauthorizedError)?;
+
+        let now = Utc::now().naive_utc();
+        if api_key.expires_at.is_some_and(|expires| expires <= now) {
+            return Err(AppError::UnauthorizedError);
+        }
+
+        diesel::update(api_keys.filter(id.eq(&api_key.id)))
+            .set(last_used_at.eq(now))
+            .execute(conn)?;
+        api_key.last_used_at = Some(now);
+
+        Ok(api_key)
+    }
+
+    /** Returns every key of the user, newest first */
+    pub fn find_by_user(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+    ) -> Result<Vec<ApiKey>, AppError> {
+        use schema::api_keys::dsl::*;
+
+        Ok(api_keys
+            .filter(user_id.eq(user))
+            .order(created_at.desc())
+            .load::<ApiKey>(conn)?)
+    }
+
+    /** Revokes a key of the user, returns `false` if the user has no such key */
+    pub fn delete(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+        key_id: &String,
+    ) -> Result<bool, AppError> {
+        use schema::api_keys::dsl::*;
+
+        let deleted = diesel::delete(api_keys.filter(user_id.eq(user)).filter(id.eq(key_id)))
+            .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    app::AppError,
+    schema::{self, auth_events},
+};
+use chrono::NaiveDateTime;
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+use serde::Serialize;
+use std::{fmt, str::FromStr};
+
+/// Most events returned by one query
+pub const MAX_EVENTS: i64 = 500;
+
+/// Kinds of events stored in the `event` column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthEventKind {
+    /// Correct password, and second factor if enabled
+    LoginSuccess,
+    /// Wrong password or second factor, also recorded for unknown usernames
+    LoginFailure,
+    /// The user logged out
+    Logout,
+    /// A login token was refreshed
+    Refresh,
+    /// A session was started from a remembered device after the previous one expired
+    DeviceLogin,
+    /// A session or remembered device was revoked
+    SessionRevoked,
+    /// The password was changed, reset or replaced by an administrator
+    PasswordChange,
+    AccountDeletion,
+}
+
+impl AuthEventKind {
+    /** Name of the kind in the `event` column */
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthEventKind::LoginSuccess => "login_success",
+            AuthEventKind::LoginFailure => "login_failure",
+            AuthEventKind::Logout => "logout",
+            AuthEventKind::Refresh => "refresh",
+            AuthEventKind::DeviceLogin => "device_login",
+            AuthEventKind::SessionRevoked => "session_revoked",
+            AuthEventKind::PasswordChange => "password_change",
+            AuthEventKind::AccountDeletion => "account_deletion",
+        }
+    }
+}
+
+impl fmt::Display for AuthEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuthEventKind {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "login_success" => Ok(AuthEventKind::LoginSuccess),
+            "login_failure" => Ok(AuthEventKind::LoginFailure),
+            "logout" => Ok(AuthEventKind::Logout),
+            "refresh" => Ok(AuthEventKind::Refresh),
+            "device_login" => Ok(AuthEventKind::DeviceLogin),
+            "session_revoked" => Ok(AuthEventKind::SessionRevoked),
+            "password_change" => Ok(AuthEventKind::PasswordChange),
+            "account_deletion" => Ok(AuthEventKind::AccountDeletion),
+            _ => Err(AppError::BadRequest),
+        }
+    }
+}
+
+/// Record of an authentication related action.
+/// Events aren't removed with the user, so the deletion of an account stays on record
+#[derive(Debug, Queryable, Clone, Serialize)]
+pub struct AuthEvent {
+    pub id: String,
+    pub user_id: Option<String>,
+    /// Username at the time of the event, the attempted one for failed logins of unknown users
+    pub username: Option<String>,
+    pub event: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "auth_events"]
+pub struct AuthEventInsert {
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub event: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/** Conditions of an event query, missing conditions match every event */
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub event: Option<AuthEventKind>,
+    pub ip: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+impl AuthEvent {
+    pub fn new(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        record: &AuthEventInsert,
+    ) -> Result<AuthEvent, AppError> {
+        Ok(diesel::insert_into(schema::auth_events::table)
+            .values(record)
+            .get_result::<AuthEvent>(conn)?)
+    }
+
+    /** Returns the latest events of the user, newest first */
+    pub fn find_by_user(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+        limit: i64,
+    ) -> Result<Vec<AuthEvent>, AppError> {
+        use schema::auth_events::dsl::*;
+
+        Ok(auth_events
+            .filter(user_id.eq(user))
+            .order(created_at.desc())
+            .limit(limit.clamp(1, MAX_EVENTS))
+            .load::<AuthEvent>(conn)?)
+    }
+
+    /** Returns the latest events matching the filter, newest first */
+    pub fn query(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        filter: &EventFilter,
+        limit: i64,
+    ) -> Result<Vec<AuthEvent>, AppError> {
+        use schema::auth_events::dsl::*;
+
+        let mut query = auth_events.into_boxed();
+        if let Some(filter_user) = &filter.user_id {
+            query = query.filter(user_id.eq(filter_user));
+        }
+        if let Some(filter_username) = &filter.username {
+            query = query.filter(username.eq(filter_username));
+        }
+        if let Some(kind) = filter.event {
+            query = query.filter(event.eq(kind.as_str()));
+        }
+        if let Some(filter_ip) = &filter.ip {
+            query = query.filter(ip.eq(filter_ip));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(created_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(created_at.lt(until));
+        }
+
+        Ok(query
+            .order(created_at.desc())
+            .limit(limit.clamp(1, MAX_EVENTS))
+            .load::<AuthEvent>(conn)?)
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{
+    app::AppError,
+    database::models::user::User,
+    schema::{self, impersonations},
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+use serde::Serialize;
+
+/// Audit record of an administrator acting as another user.
+/// The usernames are copied, so the record stays readable after either account is deleted
+#[derive(Debug, Queryable, Clone, Serialize)]
+pub struct Impersonation {
+    pub id: String,
+    pub admin_id: Option<String>,
+    pub admin_username: String,
+    pub user_id: Option<String>,
+    pub username: String,
+    /// Why the administrator needed to act as the user, as given by the administrator
+    pub reason: Option<String>,
+    /// Address the impersonation was started from
+    pub ip: Option<String>,
+    pub started_at: NaiveDateTime,
+    /// `None` while the session is active, and for sessions which expired instead of being ended
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "impersonations"]
+struct ImpersonationInsert {
+    pub admin_id: String,
+    pub admin_username: String,
+    pub user_id: String,
+    pub username: String,
+    pub reason: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl Impersonation {
+    pub fn new(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        admin: &User,
+        user: &User,
+        reason: Option<String>,
+        ip: Option<String>,
+    ) -> Result<Impersonation, AppError> {
+        let record = ImpersonationInsert {
+            admin_id: admin.id.clone(),
+            admin_username: admin.username.clone(),
+            user_id: user.id.clone(),
+            username: user.username.clone(),
+            reason,
+            ip,
+        };
+
+        Ok(diesel::insert_into(schema::impersonations::table)
+            .values(&record)
+            .get_result::<Impersonation>(conn)?)
+    }
+
+    /** Records the end of the impersonation, ending it twice keeps the first end */
+    pub fn end(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        record_id: &String,
+    ) -> Result<(), AppError> {
+        use schema::impersonations::dsl::*;
+
+        diesel::update(
+            impersonations
+                .filter(id.eq(record_id))
+                .filter(ended_at.is_null()),
+        )
+        .set(ended_at.eq(Utc::now().naive_utc()))
+        .execute(conn)?;
+
+        Ok(())
+    }
+
+    /** Returns the latest impersonations, newest first */
+    pub fn find_recent(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        limit: i64,
+    ) -> Result<Vec<Impersonation>, AppError> {
+        use schema::impersonations::dsl::*;
+
+        Ok(impersonations
+            .order(started_at.desc())
+            .limit(limit)
+            .load::<Impersonation>(conn)?)
+    }
+}
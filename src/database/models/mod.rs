@@ -1,4 +1,11 @@
+pub mod api_key;
+pub mod auth_event;
 pub mod blog;
 pub mod comment;
+pub mod impersonation;
 pub mod like;
+pub mod oidc_identity;
+pub mod recovery_code;
+pub mod remembered_device;
+pub mod role;
 pub mod user;
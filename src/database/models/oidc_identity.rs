@@ -0,0 +1,65 @@
+use crate::{
+    app::AppError,
+    schema::{self, oidc_identities},
+};
+use chrono::NaiveDateTime;
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+
+/// Links the subject of an external identity provider to an user
+#[derive(Debug, Queryable, Clone)]
+pub struct OidcIdentity {
+    pub id: String,
+    pub user_id: String,
+    /// Issuer url of the provider
+    pub issuer: String,
+    /// Id of the user at the provider, unique per issuer
+    pub subject: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "oidc_identities"]
+struct OidcIdentityInsert {
+    pub user_id: String,
+    pub issuer: String,
+    pub subject: String,
+}
+
+impl OidcIdentity {
+    /** Links the subject of the provider to the user */
+    pub fn new(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &str,
+        provider: &str,
+        provider_subject: &str,
+    ) -> Result<OidcIdentity, AppError> {
+        let record = OidcIdentityInsert {
+            user_id: user.to_string(),
+            issuer: provider.to_string(),
+            subject: provider_subject.to_string(),
+        };
+
+        Ok(diesel::insert_into(schema::oidc_identities::table)
+            .values(&record)
+            .get_result::<OidcIdentity>(conn)?)
+    }
+
+    /** Returns the identity linked to the subject of the provider */
+    pub fn find(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        provider: &String,
+        provider_subject: &String,
+    ) -> Result<Option<OidcIdentity>, AppError> {
+        use schema::oidc_identities::dsl::*;
+
+        Ok(oidc_identities
+            .filter(issuer.eq(provider))
+            .filter(subject.eq(provider_subject))
+            .first::<OidcIdentity>(conn)
+            .optional()?)
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{
+    app::AppError,
+    schema::{self, recovery_codes},
+};
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+
+/// One-time code which can be used instead of a TOTP code, only the SHA256 digest of the code is stored
+#[derive(Queryable, Clone)]
+pub struct RecoveryCode {
+    pub id: String,
+    pub user_id: String,
+    pub code_hash: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "recovery_codes"]
+struct RecoveryCodeInsert {
+    pub user_id: String,
+    pub code_hash: String,
+}
+
+/** Recovery codes are long random strings, so an unsalted digest is enough to protect them */
+fn hash_code(code: &str) -> String {
+    sha256::digest(code.trim().to_lowercase())
+}
+
+impl RecoveryCode {
+    /** Replaces every recovery code of the user with the codes specified */
+    pub fn replace_for_user(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+        codes: &[String],
+    ) -> Result<(), AppError> {
+        let records: Vec<RecoveryCodeInsert> = codes
+            .iter()
+            .map(|code| RecoveryCodeInsert {
+                user_id: user.clone(),
+                code_hash: hash_code(code),
+            })
+            .collect();
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            RecoveryCode::delete_by_user(conn, user)?;
+            diesel::insert_into(schema::recovery_codes::table)
+                .values(&records)
+                .execute(conn)?;
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /** Consumes the code, returns `false` if the user has no such unused code */
+    pub fn redeem(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+        code: &str,
+    ) -> bool {
+        use schema::recovery_codes::dsl::*;
+
+        let deleted = diesel::delete(
+            recovery_codes
+                .filter(user_id.eq(user))
+                .filter(code_hash.eq(hash_code(code))),
+        )
+        .execute(conn);
+
+        matches!(deleted, Ok(count) if count > 0)
+    }
+
+    /** Deletes every recovery code of the user */
+    pub fn delete_by_user(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+    ) -> Result<usize, diesel::result::Error> {
+        use schema::recovery_codes::dsl::*;
+
+        diesel::delete(recovery_codes.filter(user_id.eq(user))).execute(conn)
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    app::AppError,
+    schema::{self, remembered_devices},
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+use serde::Serialize;
+
+/// Device an user chose to stay logged in on. The device holds a `selector:validator` credential,
+/// the selector finds the record and only the SHA256 digest of the validator is stored.
+/// The validator is replaced every time the credential is used
+#[derive(Debug, Queryable, Clone, Serialize)]
+pub struct RememberedDevice {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(skip)]
+    pub selector: String,
+    #[serde(skip)]
+    pub validator_hash: String,
+    /// Address the credential was last used from
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "remembered_devices"]
+struct RememberedDeviceInsert {
+    pub user_id: String,
+    pub selector: String,
+    pub validator_hash: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub expires_at: NaiveDateTime,
+}
+
+impl RememberedDevice {
+    pub fn new(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &str,
+        device_selector: &str,
+        device_validator_hash: &str,
+        device_ip: Option<String>,
+        device_user_agent: Option<String>,
+        expires: NaiveDateTime,
+    ) -> Result<RememberedDevice, AppError> {
+        let record = RememberedDeviceInsert {
+            user_id: user.to_string(),
+            selector: device_selector.to_string(),
+            validator_hash: device_validator_hash.to_string(),
+            ip: device_ip,
+            user_agent: device_user_agent,
+            expires_at: expires,
+        };
+
+        Ok(diesel::insert_into(schema::remembered_devices::table)
+            .values(&record)
+            .get_result::<RememberedDevice>(conn)?)
+    }
+
+    pub fn find_by_selector(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        device_selector: &str,
+    ) -> Option<RememberedDevice> {
+        use schema::remembered_devices::dsl::*;
+
+        remembered_devices
+            .filter(selector.eq(device_selector))
+            .first::<RememberedDevice>(conn)
+            .ok()
+    }
+
+    /** Replaces the validator of the device and records its use. Only succeeds if the stored validator is still `old_hash`,
+     * so two concurrent uses of the same credential can't both succeed
+     */
+    pub fn rotate(
+        &self,
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        new_hash: &String,
+        device_ip: Option<String>,
+        device_user_agent: Option<String>,
+    ) -> Result<bool, AppError> {
+        use schema::remembered_devices::dsl::*;
+
+        let updated = diesel::update(
+            remembered_devices
+                .filter(id.eq(&self.id))
+                .filter(validator_hash.eq(&self.validator_hash)),
+        )
+        .set((
+            validator_hash.eq(new_hash),
+            last_used_at.eq(Utc::now().naive_utc()),
+            ip.eq(device_ip),
+            user_agent.eq(device_user_agent),
+        ))
+        .execute(conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /** Returns the unexpired devices of the user, most recently used first */
+    pub fn find_by_user(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+    ) -> Result<Vec<RememberedDevice>, AppError> {
+        use schema::remembered_devices::dsl::*;
+
+        Ok(remembered_devices
+            .filter(user_id.eq(user))
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .order(last_used_at.desc())
+            .load::<RememberedDevice>(conn)?)
+    }
+
+    /** Forgets a device of the user, returns `false` if the user has no such device */
+    pub fn delete(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+        device_id: &String,
+    ) -> Result<bool, AppError> {
+        use schema::remembered_devices::dsl::*;
+
+        let deleted = diesel::delete(
+            remembered_devices
+                .filter(user_id.eq(user))
+                .filter(id.eq(device_id)),
+        )
+        .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    /** Forgets every device of the user */
+    pub fn delete_by_user(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        user: &String,
+    ) -> Result<(), AppError> {
+        use schema::remembered_devices::dsl::*;
+
+        diesel::delete(remembered_devices.filter(user_id.eq(user))).execute(conn)?;
+
+        Ok(())
+    }
+}
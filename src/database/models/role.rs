@@ -0,0 +1,65 @@
+use crate::{app::AppError, schema};
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+use std::{fmt, str::FromStr};
+
+/// Roles seeded by the `roles` migration, the permissions of each role are stored in the `role_permissions` table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Can like blogs and comment on them
+    Reader,
+    /// Can also publish blogs and manage their own blogs
+    Author,
+    /// Can also delete blogs and comments of other users
+    Moderator,
+    /// Has every permission
+    Admin,
+}
+
+impl Role {
+    /** Name of the role in the `roles` table */
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Author => "author",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    /** Returns the names of the permissions granted to the role with the name specified */
+    pub fn permissions(
+        conn: &PooledConnection<ConnectionManager<PgConnection>>,
+        role_name: &String,
+    ) -> Result<Vec<String>, AppError> {
+        use schema::role_permissions::dsl::*;
+
+        Ok(role_permissions
+            .filter(role.eq(role_name))
+            .select(permission)
+            .load::<String>(conn)?)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "author" => Ok(Role::Author),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(AppError::BadRequest),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     app::AppError,
-    database::models::blog::Blog,
+    database::models::{blog::Blog, role::Role},
     schema::{self, users},
 };
 use diesel::{
@@ -14,7 +14,7 @@ pub trait UserTrait {
         conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
         uname: &String,
         pw: &String,
-        admin: bool,
+        role: Role,
     ) -> Result<User, AppError>;
     fn delete(&self, conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>);
     fn find_by_id(
@@ -25,15 +25,53 @@ pub trait UserTrait {
         conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
         uname: &String,
     ) -> Option<User>;
+    fn set_password(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        pw_hash: &str,
+    ) -> Result<(), AppError>;
+    fn set_totp(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        secret: Option<&String>,
+        enabled: bool,
+    ) -> Result<(), AppError>;
+    fn set_role(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        new_role: Role,
+    ) -> Result<(), AppError>;
+    fn find_by_email(
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        address: &str,
+    ) -> Option<User>;
+    fn set_email(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        address: Option<&String>,
+    ) -> Result<(), AppError>;
+    fn set_email_verified(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+    ) -> Result<(), AppError>;
 }
 
 #[derive(Debug, Queryable, Clone)]
 pub struct User {
     pub id: String,
     pub username: String,
-    ///SHA256 of the password
+    ///Argon2id PHC string of the password (legacy accounts may still hold an unsalted SHA256)
     pub pass: String,
-    pub is_admin: bool,
+    ///Base32 encoded TOTP secret, set once the user started enrolling in two-factor authentication
+    pub totp_secret: Option<String>,
+    ///Whether login requires a TOTP or recovery code, only set after the secret was confirmed
+    pub totp_enabled: bool,
+    ///Name of the [role](Role) deciding what the user is allowed to do
+    pub role: String,
+    ///Lowercase email address, unique among users
+    pub email: Option<String>,
+    ///Whether the user followed the verification link sent to the current address
+    pub email_verified: bool,
 }
 
 #[derive(Insertable)]
@@ -41,7 +79,7 @@ pub struct User {
 pub struct UserInsert {
     pub username: String,
     pub pass: String,
-    pub is_admin: bool,
+    pub role: String,
 }
 
 impl UserTrait for User {
@@ -53,22 +91,23 @@ impl UserTrait for User {
     /// let result = new_user(
     ///     &conn,
     ///     "username".to_string(),
-    ///     "SHA256 of the password".to_string());
+    ///     "Argon2id hash of the password".to_string(),
+    ///     Role::Author);
     /// ```
     fn new(
         conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
         uname: &String,
         pw: &String,
-        admin: bool,
+        role: Role,
     ) -> Result<User, AppError> {
-        if pw.len() != 64 || uname.len() == 0 {
+        if pw.is_empty() || uname.is_empty() {
             return Err(AppError::BadRequest);
         }
 
         let to_insert = UserInsert {
             username: uname.clone(),
             pass: pw.clone(),
-            is_admin: admin,
+            role: role.to_string(),
         };
 
         let ret_user: User = diesel::insert_into(schema::users::table)
@@ -148,4 +187,105 @@ impl UserTrait for User {
             Err(_msg) => None,
         }
     }
+
+    /** Replaces the stored password hash of the user */
+    fn set_password(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        pw_hash: &str,
+    ) -> Result<(), AppError> {
+        use crate::schema::users::dsl::*;
+
+        if pw_hash.is_empty() {
+            return Err(AppError::BadRequest);
+        }
+
+        diesel::update(users.filter(id.eq(&self.id)))
+            .set(pass.eq(pw_hash))
+            .execute(conn.ok_or(AppError::InternalServerError)?)?;
+        self.pass = pw_hash.to_string();
+
+        Ok(())
+    }
+
+    /** Replaces the TOTP secret of the user and whether two-factor authentication is enabled */
+    fn set_totp(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        secret: Option<&String>,
+        enabled: bool,
+    ) -> Result<(), AppError> {
+        use crate::schema::users::dsl::*;
+
+        diesel::update(users.filter(id.eq(&self.id)))
+            .set((totp_secret.eq(secret), totp_enabled.eq(enabled)))
+            .execute(conn.ok_or(AppError::InternalServerError)?)?;
+        self.totp_secret = secret.cloned();
+        self.totp_enabled = enabled;
+
+        Ok(())
+    }
+
+    /** Replaces the role of the user */
+    fn set_role(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        new_role: Role,
+    ) -> Result<(), AppError> {
+        use crate::schema::users::dsl::*;
+
+        diesel::update(users.filter(id.eq(&self.id)))
+            .set(role.eq(new_role.as_str()))
+            .execute(conn.ok_or(AppError::InternalServerError)?)?;
+        self.role = new_role.to_string();
+
+        Ok(())
+    }
+
+    /** Returns the user with the email address specified */
+    fn find_by_email(
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        address: &str,
+    ) -> Option<User> {
+        use crate::schema::users::dsl::*;
+
+        users
+            .filter(email.eq(address))
+            .first::<User>(conn?)
+            .optional()
+            .ok()
+            .flatten()
+    }
+
+    /** Replaces the email address of the user, the new address is unverified */
+    fn set_email(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+        address: Option<&String>,
+    ) -> Result<(), AppError> {
+        use crate::schema::users::dsl::*;
+
+        diesel::update(users.filter(id.eq(&self.id)))
+            .set((email.eq(address), email_verified.eq(false)))
+            .execute(conn.ok_or(AppError::InternalServerError)?)?;
+        self.email = address.cloned();
+        self.email_verified = false;
+
+        Ok(())
+    }
+
+    /** Marks the current email address of the user as verified */
+    fn set_email_verified(
+        &mut self,
+        conn: Option<&PooledConnection<ConnectionManager<PgConnection>>>,
+    ) -> Result<(), AppError> {
+        use crate::schema::users::dsl::*;
+
+        diesel::update(users.filter(id.eq(&self.id)))
+            .set(email_verified.eq(true))
+            .execute(conn.ok_or(AppError::InternalServerError)?)?;
+        self.email_verified = true;
+
+        Ok(())
+    }
 }
@@ -8,24 +8,68 @@ pub mod database;
 pub mod schema;
 
 mod auth;
+mod cli;
 mod routes;
 
 use actix_web::{App, HttpServer};
 use app::AppState;
-use routes::{blog::*, comment::*, token::*, user::*};
+use auth::{
+    csrf::CsrfProtection, impersonation::ImpersonationMarker, remember::RememberMeRestore,
+    sliding::SessionRenewal,
+};
+use routes::{
+    admin::*, api_key::*, blog::*, comment::*, device::*, email::*, magic_link::*, oidc::*,
+    password::*, token::*, two_factor::*, user::*,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    //`blogsite admin ...` manages users from the server shell instead of starting the server
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("admin") {
+        let app_state = AppState::new(Some(2));
+        match cli::run(&app_state, &args[2..], &mut std::io::stdin().lock()) {
+            Ok(message) => println!("{}", message),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     let app_state = AppState::new(None);
+    env_logger::init();
 
     println!("Server running...");
     HttpServer::new(move || {
         App::new()
             .app_data(actix_web::web::Data::new(app_state.clone()))
+            .wrap(RememberMeRestore)
+            .wrap(CsrfProtection)
+            .wrap(ImpersonationMarker)
+            .wrap(SessionRenewal)
             //User routes
             .service(login)
             .service(create_new_user)
             .service(delete_an_user)
+            .service(get_auth_events)
+            .service(change_password)
+            .service(request_password_reset)
+            .service(reset_password)
+            .service(set_email)
+            .service(verify_email)
+            .service(request_login_link)
+            .service(login_with_link)
+            .service(confirm_login_link)
+            //External identity provider routes
+            .service(oidc_login)
+            .service(oidc_callback)
+            //Two-factor authentication routes
+            .service(enroll_two_factor)
+            .service(confirm_two_factor)
+            .service(disable_two_factor)
+            .service(verify_two_factor)
             //Blog routes
             .service(create_new_blog)
             .service(edit_blogs)
@@ -41,6 +85,25 @@ async fn main() -> std::io::Result<()> {
             //Token routes
             .service(deauth_token)
             .service(refresh_token)
+            .service(get_sessions)
+            .service(revoke_session)
+            .service(revoke_all_sessions)
+            //Remembered device routes
+            .service(get_devices)
+            .service(forget_device)
+            .service(forget_all_devices)
+            //API key routes
+            .service(create_api_key)
+            .service(get_api_keys)
+            .service(revoke_api_key)
+            //Admin routes
+            .service(get_login_throttle)
+            .service(unlock_login)
+            .service(set_user_role)
+            .service(query_auth_events)
+            .service(start_impersonation)
+            .service(end_impersonation)
+            .service(get_impersonations)
     })
     .bind(("127.0.0.1", 8080))?
     .run()
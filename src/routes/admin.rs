@@ -0,0 +1,541 @@
+use actix_web::{
+    delete, get, post, put,
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+
+use crate::{
+    app::{AppError, AppState},
+    auth::{
+        extractor::{AdminUser, AuthenticatedUser},
+        impersonation::IMPERSONATION_TTL,
+        policy::{can, Action, Resource},
+        throttle::LoginThrottle,
+        token::{Impersonator, Token},
+    },
+    database::models::{
+        auth_event::{AuthEvent, AuthEventKind, EventFilter},
+        impersonation::Impersonation,
+        role::Role,
+        user::*,
+    },
+    routes::token::TokenResponse,
+};
+
+#[derive(Deserialize)]
+struct RoleRequest {
+    pub role: String,
+}
+
+#[derive(Deserialize, Default)]
+struct ImpersonationRequest {
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EventQuery {
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub event: Option<String>,
+    pub ip: Option<String>,
+    /// Unix timestamp of the oldest event
+    pub since: Option<i64>,
+    /// Unix timestamp the events have to be older than
+    pub until: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+fn parse_timestamp(timestamp: Option<i64>) -> Result<Option<NaiveDateTime>, AppError> {
+    timestamp
+        .map(|secs| NaiveDateTime::from_timestamp_opt(secs, 0).ok_or(AppError::BadRequest))
+        .transpose()
+}
+
+/// Pipe for viewing the failed logins and lockout of an username
+/// - url: `{domain}/api/admin/login_throttle/{username}`
+///
+/// # HTTP request requirements
+/// - `{username}` as parameter
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token of an administrator
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/api/admin/login_throttle/test_user")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted string containing `failures` and `locked_for` keys
+/// ## Error
+/// - Unauthorized
+/// - Forbidden
+/// - Internal server error
+#[get("/api/admin/login_throttle/{username}")]
+pub async fn get_login_throttle(
+    req: HttpRequest,
+    _admin: AdminUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let username = req.match_info().query("username").to_string();
+    let status = LoginThrottle::status(app_state.token_store.as_ref(), &username)?;
+
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Pipe for unlocking an username locked after repeated failed logins
+/// - url: `{domain}/api/admin/login_throttle/{username}`
+///
+/// # HTTP request requirements
+/// - `{username}` as parameter
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token of an administrator
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/api/admin/login_throttle/test_user")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Forbidden
+/// - Internal server error
+#[delete("/api/admin/login_throttle/{username}")]
+pub async fn unlock_login(
+    req: HttpRequest,
+    _admin: AdminUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let username = req.match_info().query("username").to_string();
+    LoginThrottle::reset(app_state.token_store.as_ref(), &username)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe for changing the role of an user
+/// - url: `{domain}/api/admin/users/{username}/role`
+///
+/// # HTTP request requirements
+/// - `{username}` as parameter
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token of an administrator
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+/// ## body
+/// - json formatted string containing `role` key, one of `reader`, `author`, `moderator` or `admin`
+///
+/// # Example
+/// ```
+/// let data = "{ role: \"moderator\" }";
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::put()
+///     .uri("localhost/api/admin/users/test_user/role")
+///     .cookie(cookie)
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Forbidden
+/// - Bad request if the user or role doesn't exist
+/// - Internal server error
+#[put("/api/admin/users/{username}/role")]
+pub async fn set_user_role(
+    req: HttpRequest,
+    _admin: AdminUser,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let username = req.match_info().query("username").to_string();
+    let request =
+        serde_json::from_str::<RoleRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+    let role = request.role.parse::<Role>()?;
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let mut user = User::find_by_username(Some(&conn), &username).ok_or(AppError::BadRequest)?;
+    user.set_role(Some(&conn), role)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe for searching the authentication events of every user
+/// - url: `{domain}/api/admin/auth_events`
+///
+/// # HTTP request requirements
+/// - optional `user_id`, `username`, `event`, `ip`, `since`, `until` and `limit` query parameters
+/// - `event` is one of `login_success`, `login_failure`, `logout`, `refresh`, `session_revoked`,
+/// `password_change` and `account_deletion`
+/// - `since` and `until` are unix timestamps, `limit` defaults to 100 and is at most 500
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token of an administrator
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/api/admin/auth_events?username=test_user&event=login_failure&since=1700000000")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted list of the matching events, newest first,
+/// containing `id`, `user_id`, `username`, `event`, `ip`, `user_agent` and `created_at` keys
+/// ## Error
+/// - Unauthorized
+/// - Forbidden
+/// - Bad request if a parameter is invalid
+/// - Internal server error
+#[get("/api/admin/auth_events")]
+pub async fn query_auth_events(
+    _admin: AdminUser,
+    query: Query<EventQuery>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let filter = EventFilter {
+        user_id: query.user_id,
+        username: query.username,
+        event: query
+            .event
+            .map(|event| event.parse::<AuthEventKind>())
+            .transpose()?,
+        ip: query.ip,
+        since: parse_timestamp(query.since)?,
+        until: parse_timestamp(query.until)?,
+    };
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let events = AuthEvent::query(&conn, &filter, query.limit.unwrap_or(100))?;
+
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Pipe for starting an impersonation session, which lets an administrator act as another user to reproduce what they see.
+/// The session can't edit or delete anything, change the account or administer other users, and every response to it carries an
+/// `X-Impersonated-By` header. The session is recorded and lives for an hour without being refreshed,
+/// it ends earlier if the administrator may no longer manage users
+/// - url: `{domain}/api/admin/impersonate/{username}`
+///
+/// # HTTP request requirements
+/// - `{username}` as parameter
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token of an administrator
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+/// ## body
+/// - optional json formatted string containing `reason` key, stored in the audit record
+///
+/// # Example
+/// ```
+/// let data = "{ reason: \"Ticket 1234, can't see own blogs\" }";
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/api/admin/impersonate/test_user")
+///     .cookie(cookie)
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted string containing `token` and `expires_in` keys, the token is sent as `Authorization: Bearer` header
+/// ## Error
+/// - Unauthorized
+/// - Forbidden, also if the user is an administrator or the request is an impersonation session itself
+/// - Bad request if the user doesn't exist
+/// - Internal server error
+#[post("/api/admin/impersonate/{username}")]
+pub async fn start_impersonation(
+    req: HttpRequest,
+    AdminUser(admin): AdminUser,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let username = req.match_info().query("username").to_string();
+    let request = match req_body.trim().is_empty() {
+        true => ImpersonationRequest::default(),
+        false => serde_json::from_str::<ImpersonationRequest>(&req_body)
+            .map_err(|_| AppError::BadRequest)?,
+    };
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let user = User::find_by_username(Some(&conn), &username).ok_or(AppError::BadRequest)?;
+    //Acting as another administrator would hand out their permissions
+    if user.id == admin.user.id || can(&conn, &user, Action::ManageUsers, Resource::None)? {
+        return Err(AppError::Forbidden);
+    }
+
+    let record = Impersonation::new(
+        &conn,
+        &admin.user,
+        &user,
+        request.reason.filter(|reason| !reason.trim().is_empty()),
+        Some(LoginThrottle::client_ip(&req)),
+    )?;
+    let impersonator = Impersonator {
+        admin_id: admin.user.id.clone(),
+        record_id: record.id,
+    };
+    let token = Token::new_impersonation(
+        app_state.token_store.as_ref(),
+        &user.id,
+        &app_state.session_config,
+        &impersonator,
+        IMPERSONATION_TTL,
+    );
+
+    Ok(HttpResponse::Ok().json(TokenResponse {
+        token,
+        expires_in: IMPERSONATION_TTL,
+        refresh_token: None,
+    }))
+}
+
+/// Pipe for ending the impersonation session the request is authenticated with
+/// - url: `{domain}/api/impersonation`
+///
+/// # HTTP request requirements
+/// ## header
+/// - `Authorization: Bearer` header containing the impersonation token
+///
+/// # Example
+/// ```
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/api/impersonation")
+///     .insert_header(("Authorization", "Bearer test_token"))
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Bad request if the token isn't an impersonation session
+/// - Internal server error
+#[delete("/api/impersonation")]
+pub async fn end_impersonation(
+    auth: AuthenticatedUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let impersonator = auth.impersonator.ok_or(AppError::BadRequest)?;
+    Token::delete(
+        app_state.token_store.as_ref(),
+        &Token::key(&app_state.session_config, &auth.token),
+    );
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    Impersonation::end(&conn, &impersonator.record_id)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe for listing the latest impersonation sessions, the audit trail of administrators acting as users
+/// - url: `{domain}/api/admin/impersonations`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token of an administrator
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/api/admin/impersonations")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted list of the latest 100 sessions, newest first, containing `id`, `admin_id`, `admin_username`,
+/// `user_id`, `username`, `reason`, `ip`, `started_at` and `ended_at` keys
+/// ## Error
+/// - Unauthorized
+/// - Forbidden
+/// - Internal server error
+#[get("/api/admin/impersonations")]
+pub async fn get_impersonations(
+    _admin: AdminUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(Impersonation::find_recent(&conn, 100)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{
+            impersonation::{ImpersonationMarker, IMPERSONATION_HEADER},
+            password::hash_password,
+            store::MemoryTokenStore,
+        },
+        database::models::blog::Blog,
+        routes::{blog::edit_blogs, email::set_email, user::delete_an_user},
+    };
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_impersonation() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(appstate.clone()))
+                .wrap(ImpersonationMarker)
+                .service(super::start_impersonation)
+                .service(super::end_impersonation)
+                .service(super::get_impersonations)
+                .service(set_email)
+                .service(edit_blogs)
+                .service(delete_an_user),
+        )
+        .await;
+
+        let conn = appstate.psql_pool.get().unwrap();
+        let mut admin = User::new(
+            Some(&conn),
+            &String::from("test_impersonating_admin"),
+            &hash_password("test_password").unwrap(),
+            Role::Admin,
+        )
+        .unwrap();
+        let usr = User::new(
+            Some(&conn),
+            &String::from("test_impersonated_user"),
+            &hash_password("test_password").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let store = appstate.token_store.as_ref();
+        let admin_token = Token::new(store, &admin.id, &appstate.session_config);
+
+        //Administrators can't be impersonated
+        let req = test::TestRequest::post()
+            .uri("/api/admin/impersonate/test_impersonating_admin")
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/api/admin/impersonate/test_impersonated_user")
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .set_payload("{ \"reason\": \"Test ticket\" }")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let token = body["token"].as_str().unwrap().to_string();
+        debug_assert!(Token::refresh(store, &token, &appstate.session_config, true).is_none());
+
+        //Destructive actions and account changes are refused
+        let req = test::TestRequest::delete()
+            .uri("/user/test_impersonated_user")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::FORBIDDEN);
+        let req = test::TestRequest::put()
+            .uri("/user/email")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_payload("{ \"email\": \"impersonated@example.com\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::FORBIDDEN);
+        let blog = Blog::new(
+            &conn,
+            &usr,
+            &String::from("Test impersonated blog"),
+            &String::from("Test body"),
+            None,
+        )
+        .unwrap();
+        let req = test::TestRequest::put()
+            .uri(&format!("/blogs/{}", blog.id))
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_payload("{ \"title\": \"Edited by the administrator\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::delete()
+            .uri("/api/impersonation")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(resp.headers().get(IMPERSONATION_HEADER).unwrap() == admin.id.as_str());
+        debug_assert!(Token::find(store, &token, &appstate.session_config).is_err());
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/impersonations")
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .to_request();
+        let records: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+        let record = records
+            .iter()
+            .find(|record| record["user_id"] == usr.id.as_str())
+            .unwrap();
+        debug_assert!(record["admin_username"] == "test_impersonating_admin");
+        debug_assert!(record["reason"] == "Test ticket");
+        debug_assert!(!record["ended_at"].is_null());
+
+        //The session doesn't outlive the administrator's permission to manage users
+        let req = test::TestRequest::post()
+            .uri("/api/admin/impersonate/test_impersonated_user")
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let token = body["token"].as_str().unwrap().to_string();
+        admin.set_role(Some(&conn), Role::Author).unwrap();
+        let req = test::TestRequest::delete()
+            .uri("/api/impersonation")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::UNAUTHORIZED);
+        debug_assert!(Token::find(store, &token, &appstate.session_config).is_err());
+        let records = Impersonation::find_recent(&conn, 100).unwrap();
+        debug_assert!(records
+            .iter()
+            .filter(|record| record.user_id.as_ref() == Some(&usr.id))
+            .all(|record| record.ended_at.is_some()));
+
+        usr.delete(Some(&conn));
+        admin.delete(Some(&conn));
+    }
+}
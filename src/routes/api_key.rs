@@ -0,0 +1,306 @@
+use actix_web::{delete, get, post, web::Data, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{AppError, AppState},
+    auth::{extractor::SessionUser, policy::SCOPES},
+    database::models::api_key::ApiKey,
+};
+
+#[derive(Deserialize)]
+struct CreateKeyRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Seconds the key is valid for, the key doesn't expire if missing
+    pub expires_in: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct CreateKeyResponse {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// Pipe for creating an API key, the key is only returned in this response
+/// - url: `{domain}/api/keys`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token, API keys can't create keys
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+/// ## body
+/// - json formatted string containing `name` and `scopes` keys
+/// - `scopes` is a non empty list of `blogs:read`, `blogs:write`, `comments:read`, `comments:write`, `users:write` and `admin`
+/// - optional `expires_in` key, the amount of seconds the key is valid for, at most the configured maximum (a year by default)
+///
+/// # Example
+/// ```
+/// let data = "{ name: \"CI\", scopes: [\"blogs:write\"], expires_in: 2592000 }";
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/api/keys")
+///     .cookie(cookie)
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted string containing `id`, `name`, `prefix`, `scopes`, `created_at`, `expires_at` and the `key`,
+/// which is sent as `Authorization: Bearer` header
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Bad request
+/// - Internal server error
+#[post("/api/keys")]
+pub async fn create_api_key(
+    SessionUser(auth): SessionUser,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request =
+        serde_json::from_str::<CreateKeyRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+    if request.scopes.is_empty()
+        || request
+            .scopes
+            .iter()
+            .any(|scope| !SCOPES.contains(&scope.as_str()))
+    {
+        return Err(AppError::BadRequest);
+    }
+    let max_ttl = app_state.session_config.api_key_max_ttl;
+    let expires_at = match request.expires_in {
+        Some(seconds) if seconds <= 0 || seconds > max_ttl => return Err(AppError::BadRequest),
+        //The configured maximum keeps the duration in range, the addition can still leave the supported dates
+        Some(seconds) => Some(
+            Utc::now()
+                .checked_add_signed(Duration::seconds(seconds))
+                .ok_or(AppError::BadRequest)?
+                .naive_utc(),
+        ),
+        None => None,
+    };
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let (api_key, key) = ApiKey::new(
+        &conn,
+        &auth.user.id,
+        &request.name,
+        &request.scopes,
+        expires_at,
+    )?;
+
+    Ok(HttpResponse::Ok().json(CreateKeyResponse { api_key, key }))
+}
+
+/// Pipe for listing the API keys of the logged in user
+/// - url: `{domain}/api/keys`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/api/keys")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted list of keys containing `id`, `name`, `prefix`, `scopes`, `created_at`, `expires_at` and `last_used_at`
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Internal server error
+#[get("/api/keys")]
+pub async fn get_api_keys(
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(ApiKey::find_by_user(&conn, &auth.user.id)?))
+}
+
+/// Pipe for revoking an API key of the logged in user
+/// - url: `{domain}/api/keys/{key_id}`
+///
+/// # HTTP request requirements
+/// - `{key_id}` as parameter
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/api/keys/key_id")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Bad request if the user has no such key
+/// - Internal server error
+#[delete("/api/keys/{key_id}")]
+pub async fn revoke_api_key(
+    req: HttpRequest,
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let key_id = req.match_info().query("key_id").to_string();
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    match ApiKey::delete(&conn, &auth.user.id, &key_id)? {
+        true => Ok(HttpResponse::Ok().finish()),
+        false => Err(AppError::BadRequest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{password::hash_password, store::MemoryTokenStore, token::Token},
+        database::models::{blog::Blog, role::Role, user::*},
+        routes::comment::create_comment,
+    };
+    use actix_web::{body, cookie::CookieBuilder, test, App};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_api_key_scopes() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(super::create_api_key)
+                .service(super::get_api_keys)
+                .service(super::revoke_api_key)
+                .service(create_comment),
+        )
+        .await;
+
+        let conn = appstate.psql_pool.get().unwrap();
+        let usr = User::new(
+            Some(&conn),
+            &String::from("Test api key user"),
+            &hash_password("asd123").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let session = Token::new(
+            appstate.token_store.as_ref(),
+            &usr.id,
+            &appstate.session_config,
+        );
+        let blog = Blog::new(
+            &conn,
+            &usr,
+            &String::from("Test title"),
+            &String::from("Test body"),
+            None,
+        )
+        .unwrap();
+
+        let mut keys = vec![];
+        for scopes in ["[\"blogs:write\"]", "[\"comments:write\"]"] {
+            let req = test::TestRequest::post()
+                .uri("/api/keys")
+                .insert_header(("Authorization", format!("Bearer {}", session)))
+                .set_payload(format!("{{ \"name\": \"CI\", \"scopes\": {} }}", scopes))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            debug_assert!(resp.status().is_success());
+            let body: Value =
+                serde_json::from_slice(&body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+            keys.push((
+                body["id"].as_str().unwrap().to_string(),
+                body["key"].as_str().unwrap().to_string(),
+            ));
+        }
+
+        //Expiries past the configured maximum are rejected instead of overflowing
+        for expires_in in [appstate.session_config.api_key_max_ttl + 1, i64::MAX] {
+            let req = test::TestRequest::post()
+                .uri("/api/keys")
+                .insert_header(("Authorization", format!("Bearer {}", session)))
+                .set_payload(format!(
+                    "{{ \"name\": \"CI\", \"scopes\": [\"blogs:read\"], \"expires_in\": {} }}",
+                    expires_in
+                ))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            debug_assert!(resp.status() == actix_web::http::StatusCode::BAD_REQUEST);
+        }
+
+        //Keys only work for the actions of their scopes
+        for ((_, key), allowed) in keys.iter().zip([false, true]) {
+            let req = test::TestRequest::post()
+                .uri(format!("/blogs/{}/comment", blog.id).as_str())
+                .insert_header(("Authorization", format!("Bearer {}", key)))
+                .set_payload("Test comment")
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            debug_assert!(resp.status().is_success() == allowed);
+        }
+
+        //Keys can't manage keys
+        let req = test::TestRequest::get()
+            .uri("/api/keys")
+            .insert_header(("Authorization", format!("Bearer {}", keys[1].1)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/api/keys")
+            .cookie(CookieBuilder::new("token", &session).finish())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let body = body::to_bytes(resp.into_body()).await.unwrap();
+        let listed: Value = serde_json::from_slice(&body).unwrap();
+        debug_assert!(listed.as_array().unwrap().len() == 2);
+        debug_assert!(!std::str::from_utf8(&body).unwrap().contains(&keys[1].1));
+
+        let req = test::TestRequest::delete()
+            .uri(format!("/api/keys/{}", keys[1].0).as_str())
+            .insert_header(("Authorization", format!("Bearer {}", session)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri(format!("/blogs/{}/comment", blog.id).as_str())
+            .insert_header(("Authorization", format!("Bearer {}", keys[1].1)))
+            .set_payload("Test comment")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+
+        usr.delete(Some(&conn));
+    }
+}
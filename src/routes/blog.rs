@@ -2,7 +2,10 @@ use std::{fs, io::Write, path::PathBuf};
 
 use crate::{
     app::{AppError, AppState},
-    auth::token::Token,
+    auth::{
+        extractor::AuthenticatedUser,
+        policy::{authorize, require_verified_email, Action, Resource},
+    },
     database::models::{blog::*, like::*, user::*},
 };
 use actix_multipart::Multipart;
@@ -117,7 +120,8 @@ async fn parse_multipart(payload: &mut Multipart) -> Result<(String, String, Str
 ///
 /// # HTTP request requirements
 /// ## header
-/// - cookie with name `token`, containing the login token
+/// - cookie with name `token` or `Authorization: Bearer` header, containing the login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
 /// ## body
 /// - file: [fs::File] (optional) - image we are uploading
 /// - title: [String] - title we wish to name our blog
@@ -129,25 +133,20 @@ async fn parse_multipart(payload: &mut Multipart) -> Result<(String, String, Str
 /// ## Error
 /// - Bad request
 /// - Unauthorized
+/// - Forbidden if the role of the user can't publish blogs, or the email address of the user
+/// isn't verified while verification is required
 /// - Internal server error
 
 #[post("/blog")]
 pub async fn create_new_blog(
-    req: HttpRequest,
+    auth: AuthenticatedUser,
     app_state: Data<AppState>,
     mut mp: Multipart,
 ) -> Result<HttpResponse, AppError> {
-    let token = req
-        .cookie("token")
-        .ok_or(AppError::UnauthorizedError)?
-        .value()
-        .to_string();
-
     let psql_conn = app_state.psql_pool.clone().get().unwrap();
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
-
-    let user_id = Token::find(&mut redis_conn, &token)?;
-    let user = User::find_by_id(Some(&psql_conn), &user_id)?;
+    authorize(&psql_conn, &auth, Action::CreateBlog, Resource::None)?;
+    require_verified_email(&app_state.email_config, &auth.user)?;
+    let user = auth.user;
     let (title, body, filename) = parse_multipart(&mut mp).await?;
 
     Blog::new(
@@ -197,14 +196,15 @@ pub async fn get_blogs_by_user(
     Ok(HttpResponse::Ok().body(serde_json::to_string(&posts).unwrap()))
 }
 
-/// Pipe for editing a certain blog parameter
+/// Pipe for editing a certain blog parameter, only the author of the blog or an administrator may edit it
 /// - url: `{domain}/blogs/{blog_id}`
 ///
 /// # HTTP request requirements
 /// - `{blog_id}` as a paremeter
 ///
 /// ## header
-/// - cookie with name `token`, containing the login token
+/// - cookie with name `token` or `Authorization: Bearer` header, containing the login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
 ///
 /// ## body
 /// - json with the specified fields we are changing: 'title' and/or 'body'
@@ -240,33 +240,26 @@ pub async fn get_blogs_by_user(
 /// ## Error
 /// - Unauthorized
 /// - Bad request
+/// - Forbidden
 /// - Internal server error
 #[put("/blogs/{blog_id}")]
 pub async fn edit_blogs(
     req: HttpRequest,
+    auth: AuthenticatedUser,
     req_body: String,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let token = req
-        .cookie("token")
-        .ok_or(AppError::UnauthorizedError)?
-        .value()
-        .to_string();
-
     //Checks for request body, if there's none, throw bad request
     let updated_blog =
         serde_json::from_str::<Value>(&req_body).map_err(|_| AppError::BadRequest)?;
 
-    //Starts a db and tries to find user from supplied id
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
     let psql_conn = app_state.psql_pool.clone().get().unwrap();
-
-    let _user_id = Token::find(&mut redis_conn, &token)?;
     let blog_id = req.match_info().query("blog_id").parse::<i32>()?;
 
     //Tries to find a blog posted by that user with the id
     //if no blog found throw bad request
     let mut blog = Blog::get_by_id(&psql_conn, blog_id).ok_or(AppError::BadRequest)?;
+    authorize(&psql_conn, &auth, Action::EditBlog, Resource::Blog(&blog))?;
     //Tries to parse the json values into normal values if they exist
     let title = updated_blog.get("title");
     let mut title_optional = String::new();
@@ -304,7 +297,8 @@ pub async fn edit_blogs(
 /// - `{blog_id}` as a paremeter
 ///
 /// ## header
-/// - cookie with name `token`, containing the login token
+/// - cookie with name `token` or `Authorization: Bearer` header, containing the login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
 ///
 /// # Example
 /// ```
@@ -319,28 +313,21 @@ pub async fn edit_blogs(
 /// ## Ok
 /// ## Error
 /// - Unauthorized
+/// - Forbidden
 /// - Internal server error
 /// - Bad request
 #[put("/blogs/{blog_id}/like")]
 pub async fn like_a_blog(
     req: HttpRequest,
+    auth: AuthenticatedUser,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let token = req
-        .cookie("token")
-        .ok_or(AppError::UnauthorizedError)?
-        .value()
-        .to_string();
-
     let psql_conn = app_state.psql_pool.clone().get().unwrap();
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
-    let blog_id = req.match_info().query("blog_id").parse().unwrap();
-
-    //Confirms user is logged in
-    Token::find(&mut redis_conn, &token)?;
-    let user_id = Token::find(&mut redis_conn, &token).unwrap();
+    let blog_id = req.match_info().query("blog_id").parse()?;
+    let user_id = auth.user.id.clone();
 
     let mut blog = Blog::get_by_id(&psql_conn, blog_id).ok_or(AppError::BadRequest)?;
+    authorize(&psql_conn, &auth, Action::LikeBlog, Resource::Blog(&blog))?;
 
     let like = Like::new(&psql_conn, &user_id, blog_id);
     if like.is_none() {
@@ -353,14 +340,16 @@ pub async fn like_a_blog(
     Ok(HttpResponse::Ok().finish())
 }
 
-/// Pipe for deleting an blog, this also deletes the image specified with the blog if it is present
+/// Pipe for deleting an blog, this also deletes the image specified with the blog if it is present.
+/// Authors may delete their own blogs, moderators and administrators any blog
 /// - url: `{domain}/blogs{blog_id}`
 ///
 /// # HTTP request requirements
 /// - `{blog_id}` as parameter
 ///
 /// ## header
-/// - token named `token` containing login token
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
 ///
 /// # Example
 /// ```
@@ -381,26 +370,15 @@ pub async fn like_a_blog(
 #[delete("/blogs/{blog_id}")]
 pub async fn delete_blog(
     req: HttpRequest,
+    auth: AuthenticatedUser,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let token = req
-        .cookie("token")
-        .ok_or(AppError::UnauthorizedError)?
-        .value()
-        .to_string();
-
     let psql_conn = app_state.psql_pool.clone().get().unwrap();
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
-
-    let user_id = Token::find(&mut redis_conn, &token)?;
-    let user = User::find_by_id(Some(&psql_conn), &user_id)?;
 
     let blog_id = req.match_info().query("blog_id").parse::<i32>()?;
     let blog = Blog::get_by_id(&psql_conn, blog_id).ok_or(AppError::BadRequest)?;
 
-    if user.id != blog.created_by && !user.is_admin {
-        return Err(AppError::Forbidden);
-    }
+    authorize(&psql_conn, &auth, Action::DeleteBlog, Resource::Blog(&blog))?;
     Blog::delete_by_id(&psql_conn, blog.id);
 
     Ok(HttpResponse::Ok().finish())
@@ -1,7 +1,10 @@
 use crate::{
     app::{AppError, AppState},
-    auth::token::Token,
-    database::models::{blog::*, comment::*, user::*},
+    auth::{
+        extractor::AuthenticatedUser,
+        policy::{authorize, require_verified_email, Action, Resource},
+    },
+    database::models::{blog::*, comment::*},
 };
 use actix_web::{delete, get, post, web::Data, HttpRequest, HttpResponse};
 
@@ -11,6 +14,10 @@ use actix_web::{delete, get, post, web::Data, HttpRequest, HttpResponse};
 /// # HTTP request requires
 /// - `{blog_id}` as a parameter
 ///
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
 /// ## body
 /// - a string of the comment text
 ///
@@ -30,28 +37,31 @@ use actix_web::{delete, get, post, web::Data, HttpRequest, HttpResponse};
 /// ## Error
 /// - Unauthorized
 /// - Bad request
+/// - Forbidden if the role of the user can't comment, or the email address of the user
+/// isn't verified while verification is required
 /// - Internal server errror
 #[post("/blogs/{blog_id}/comment")]
 pub async fn create_comment(
     req: HttpRequest,
+    auth: AuthenticatedUser,
     req_body: String,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let token = req
-        .cookie("token")
-        .ok_or(AppError::UnauthorizedError)?
-        .value()
-        .to_string();
-    let blog_id = req.match_info().query("blog_id").parse::<i32>().unwrap();
+    let blog_id = req.match_info().query("blog_id").parse::<i32>()?;
 
     let psql_conn = app_state.psql_pool.clone().get().unwrap();
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
 
     //Checks if blog exists
-    Blog::get_by_id(&psql_conn, blog_id).ok_or(AppError::BadRequest)?;
-
-    let user_id = Token::find(&mut redis_conn, &token)?;
-    let comment = Comment::new(&psql_conn, blog_id, &user_id, &req_body)
+    let blog = Blog::get_by_id(&psql_conn, blog_id).ok_or(AppError::BadRequest)?;
+    authorize(
+        &psql_conn,
+        &auth,
+        Action::CreateComment,
+        Resource::Blog(&blog),
+    )?;
+    require_verified_email(&app_state.email_config, &auth.user)?;
+
+    let comment = Comment::new(&psql_conn, blog_id, &auth.user.id, &req_body)
         .ok_or(AppError::InternalServerError)?;
 
     Ok(HttpResponse::Ok().body(comment.id))
@@ -90,14 +100,16 @@ pub async fn get_comments(
     Ok(HttpResponse::Ok().body(serde_json::to_string(&comments).unwrap()))
 }
 
-/// Pipe for deleting a comment from a certain post
+/// Pipe for deleting a comment from a certain post.
+/// Users may delete their own comments, moderators and administrators any comment
 /// - url: `{domain}/blogs/{blog_id}/comments/{comment_id}`
 ///
 /// # HTTP request requires
 /// - `{blog_id}` and `{comment_id}` as url parameters
 ///
 /// ## header
-/// - cookie named `token` containing login token
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
 ///
 /// # Example
 /// ```
@@ -118,26 +130,20 @@ pub async fn get_comments(
 #[delete("/blogs/{blog_id}/comments/{comment_id}")]
 pub async fn delete_comment(
     req: HttpRequest,
+    auth: AuthenticatedUser,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
-    let token = req
-        .cookie("token")
-        .ok_or(AppError::UnauthorizedError)?
-        .value()
-        .to_string();
-
     let psql_conn = app_state.psql_pool.clone().get().unwrap();
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
-
-    let user_id = Token::find(&mut redis_conn, &token)?;
-    let user = User::find_by_id(Some(&psql_conn), &user_id)?;
 
     let comment_id = req.match_info().query("comment_id").to_string();
     let comment = Comment::find_by_id(&psql_conn, &comment_id).ok_or(AppError::BadRequest)?;
 
-    if comment.user_id != user.id && !user.is_admin {
-        return Err(AppError::Forbidden);
-    }
+    authorize(
+        &psql_conn,
+        &auth,
+        Action::DeleteComment,
+        Resource::Comment(&comment),
+    )?;
 
     Comment::delete(&psql_conn, &comment_id);
 
@@ -146,14 +152,17 @@ pub async fn delete_comment(
 
 #[cfg(test)]
 mod tests {
-    use actix_web::{body, cookie::CookieBuilder, test, App};
-    use sha256::digest;
-
     use super::*;
+    use crate::{
+        auth::{password::hash_password, store::MemoryTokenStore, token::Token},
+        database::models::{role::Role, user::*},
+    };
+    use actix_web::{body, cookie::CookieBuilder, test, App};
+    use std::sync::Arc;
 
     #[actix_rt::test]
     async fn new_comment() {
-        let appstate = AppState::new(None);
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
 
         let app = test::init_service(
             App::new()
@@ -165,11 +174,15 @@ mod tests {
         let usr = User::new(
             Some(&appstate.psql_pool.get().unwrap()),
             &String::from("Test user123"),
-            &digest("asd123"),
-            false,
+            &hash_password("asd123").unwrap(),
+            Role::Author,
         )
         .unwrap();
-        let token = Token::new(&mut appstate.redis_pool.get().unwrap(), &usr.id);
+        let token = Token::new(
+            appstate.token_store.as_ref(),
+            &usr.id,
+            &appstate.session_config,
+        );
         let cookie = CookieBuilder::new("token", &token).path("/").finish();
         let blog = Blog::new(
             &appstate.psql_pool.get().unwrap(),
@@ -201,7 +214,7 @@ mod tests {
 
     #[actix_rt::test]
     async fn get_comments() {
-        let appstate = AppState::new(None);
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
 
         let app = test::init_service(
             App::new()
@@ -213,11 +226,15 @@ mod tests {
         let usr = User::new(
             Some(&appstate.psql_pool.get().unwrap()),
             &String::from("Test user123"),
-            &digest("asd123"),
-            false,
+            &hash_password("asd123").unwrap(),
+            Role::Author,
         )
         .unwrap();
-        Token::new(&mut appstate.redis_pool.get().unwrap(), &usr.id);
+        Token::new(
+            appstate.token_store.as_ref(),
+            &usr.id,
+            &appstate.session_config,
+        );
         let blog = Blog::new(
             &appstate.psql_pool.get().unwrap(),
             &usr,
@@ -238,14 +255,14 @@ mod tests {
             .to_request();
         let resp = test::call_service(&app, req).await;
         debug_assert!(resp.status().is_success());
-        debug_assert!(body::to_bytes(resp.into_body()).await.unwrap().len() > 0);
+        debug_assert!(!body::to_bytes(resp.into_body()).await.unwrap().is_empty());
 
         usr.delete(Some(&appstate.psql_pool.get().unwrap()));
     }
 
     #[actix_rt::test]
     async fn delete_comment() {
-        let appstate = AppState::new(None);
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
 
         let app = test::init_service(
             App::new()
@@ -257,11 +274,15 @@ mod tests {
         let usr = User::new(
             Some(&appstate.psql_pool.get().unwrap()),
             &String::from("Test user123"),
-            &digest("asd123"),
-            false,
+            &hash_password("asd123").unwrap(),
+            Role::Author,
         )
         .unwrap();
-        let token = Token::new(&mut appstate.redis_pool.get().unwrap(), &usr.id);
+        let token = Token::new(
+            appstate.token_store.as_ref(),
+            &usr.id,
+            &appstate.session_config,
+        );
         let cookie = CookieBuilder::new("token", &token).path("/").finish();
         let blog = Blog::new(
             &appstate.psql_pool.get().unwrap(),
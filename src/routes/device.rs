@@ -0,0 +1,266 @@
+use actix_web::{delete, get, web::Data, HttpRequest, HttpResponse};
+
+use crate::{
+    app::{AppError, AppState},
+    auth::{audit, extractor::SessionUser, remember::RememberMe, token::SessionClient},
+    database::models::{auth_event::AuthEventKind, remembered_device::RememberedDevice},
+};
+
+/** Remembers the device of a login which asked for it, adding the remembered device cookie to the login response */
+pub(crate) fn remember_device(
+    app_state: &AppState,
+    user_id: &str,
+    client: &SessionClient,
+    response: &mut HttpResponse,
+) -> Result<(), AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let config = &app_state.session_config;
+    let credential = RememberMe::issue(&conn, user_id, config, client)?;
+
+    response
+        .add_cookie(
+            &config
+                .cookie
+                .remember_cookie(credential, config.remember_ttl),
+        )
+        .map_err(|_| AppError::InternalServerError)
+}
+
+/// Pipe for listing the remembered devices of the logged in user
+/// - url: `{domain}/api/devices`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/api/devices")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted list of devices containing `id`, `ip`, `user_agent`, `created_at`, `last_used_at` and `expires_at`
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Internal server error
+#[get("/api/devices")]
+pub async fn get_devices(
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(RememberedDevice::find_by_user(&conn, &auth.user.id)?))
+}
+
+/// Pipe for forgetting a remembered device of the logged in user, its current session stays valid until it expires
+/// - url: `{domain}/api/devices/{device_id}`
+///
+/// # HTTP request requirements
+/// - `{device_id}` as parameter, as returned by [get_devices]
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/api/devices/device_id")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Bad request if the user has no such device
+/// - Internal server error
+#[delete("/api/devices/{device_id}")]
+pub async fn forget_device(
+    req: HttpRequest,
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let device_id = req.match_info().query("device_id").to_string();
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    if !RememberedDevice::delete(&conn, &auth.user.id, &device_id)? {
+        return Err(AppError::BadRequest);
+    }
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::SessionRevoked,
+        Some(&auth.user.id),
+        Some(&auth.user.username),
+    );
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe for forgetting every remembered device of the logged in user
+/// - url: `{domain}/api/devices`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/api/devices")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Internal server error
+#[delete("/api/devices")]
+pub async fn forget_all_devices(
+    req: HttpRequest,
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    RememberedDevice::delete_by_user(&conn, &auth.user.id)?;
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::SessionRevoked,
+        Some(&auth.user.id),
+        Some(&auth.user.username),
+    );
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{
+            cookie::{REFRESH_COOKIE, REMEMBER_COOKIE},
+            password::hash_password,
+            remember::RememberMeRestore,
+            store::MemoryTokenStore,
+            token::Token,
+        },
+        database::models::{role::Role, user::*},
+        routes::user::login,
+    };
+    use actix_web::{cookie::Cookie, dev::ServiceResponse, http::StatusCode, test, App};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    fn response_cookie(resp: &ServiceResponse, name: &str) -> Option<Cookie<'static>> {
+        resp.headers()
+            .get_all("set-cookie")
+            .map(|header| Cookie::parse(header.to_str().unwrap().to_string()).unwrap())
+            .find(|cookie| cookie.name() == name)
+    }
+
+    #[actix_rt::test]
+    async fn test_remembered_device() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(appstate.clone()))
+                .wrap(RememberMeRestore)
+                .service(login)
+                .service(super::get_devices)
+                .service(super::forget_all_devices),
+        )
+        .await;
+
+        let usr = User::new(
+            Some(&appstate.psql_pool.get().unwrap()),
+            &String::from("test_remembered_user"),
+            &hash_password("test_password").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .set_payload("{ \"username\": \"test_remembered_user\", \"password\": \"test_password\", \"remember_me\": true }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let remembered = response_cookie(&resp, REMEMBER_COOKIE).unwrap();
+
+        //The device cookie alone starts a new session and is rotated
+        let req = test::TestRequest::get()
+            .uri("/api/devices")
+            .cookie(Cookie::new(REMEMBER_COOKIE, remembered.value().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let session = response_cookie(&resp, &appstate.session_config.cookie.name()).unwrap();
+        debug_assert!(response_cookie(&resp, REFRESH_COOKIE).is_some());
+        let rotated = response_cookie(&resp, REMEMBER_COOKIE).unwrap();
+        debug_assert!(rotated.value() != remembered.value());
+        let devices: Value = test::read_body_json(resp).await;
+        debug_assert!(devices.as_array().unwrap().len() == 1);
+
+        //A second redemption of the same credential, like a request sent together with the first one,
+        //doesn't start a session but doesn't remove the rotated cookie either
+        let req = test::TestRequest::get()
+            .uri("/api/devices")
+            .cookie(Cookie::new(REMEMBER_COOKIE, remembered.value().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::UNAUTHORIZED);
+        debug_assert!(response_cookie(&resp, REMEMBER_COOKIE).is_none());
+        debug_assert!(Token::find(
+            appstate.token_store.as_ref(),
+            session.value(),
+            &appstate.session_config
+        )
+        .is_ok());
+
+        let req = test::TestRequest::delete()
+            .uri("/api/devices")
+            .insert_header(("Authorization", format!("Bearer {}", session.value())))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/api/devices")
+            .cookie(Cookie::new(REMEMBER_COOKIE, rotated.value().to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::UNAUTHORIZED);
+
+        usr.delete(Some(&appstate.psql_pool.get().unwrap()));
+    }
+}
@@ -0,0 +1,232 @@
+use actix_web::{
+    get, put,
+    web::{Data, Query},
+    HttpResponse,
+};
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::{
+    app::{mail::Mail, AppError, AppState},
+    auth::{email, extractor::SessionUser},
+    database::models::user::*,
+};
+
+#[derive(Deserialize)]
+struct EmailRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    pub user: String,
+    pub exp: i64,
+    pub sig: String,
+}
+
+/** Normalizes the address and checks that it's valid and not used by another user */
+pub(crate) fn check_new_email(
+    app_state: &AppState,
+    address: &str,
+    user_id: Option<&String>,
+) -> Result<String, AppError> {
+    let address = address.trim().to_lowercase();
+    if !email::is_valid_address(&address) {
+        return Err(AppError::BadRequest);
+    }
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    match User::find_by_email(Some(&conn), &address) {
+        Some(other) if Some(&other.id) != user_id => Err(AppError::BadRequest),
+        _ => Ok(address),
+    }
+}
+
+/** Mails the verification link for the current address of the user */
+pub(crate) fn send_verification(app_state: &AppState, user: &User) -> Result<(), AppError> {
+    let address = user.email.as_ref().ok_or(AppError::BadRequest)?;
+    let config = &app_state.email_config;
+    let link = email::verification_link(config, &user.id, address, Utc::now().timestamp());
+
+    app_state.mailer.send(&Mail {
+        to: address.clone(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Open this link within {} hours to verify the email address of {}:\n{}\n\
+            If you didn't add this address you can ignore this message.",
+            config.link_ttl / 3600,
+            user.username,
+            link
+        ),
+    })
+}
+
+/// Pipe for changing the email address of the logged in user, a verification link is mailed to the new address
+/// - url: `{domain}/user/email`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+/// ## body
+/// - json formatted string containing `email` key
+///
+/// # Example
+/// ```
+/// let data = "{ email: \"user@example.com\" }";
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::put()
+///     .uri("localhost/user/email")
+///     .cookie(cookie)
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Bad request if the address is invalid or used by another user
+/// - Internal server error
+#[put("/user/email")]
+pub async fn set_email(
+    SessionUser(auth): SessionUser,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request =
+        serde_json::from_str::<EmailRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+    let mut user = auth.user;
+    let address = check_new_email(&app_state, &request.email, Some(&user.id))?;
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    user.set_email(Some(&conn), Some(&address))?;
+    send_verification(&app_state, &user)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe opened through the link of a verification mail, marks the address as verified
+/// - url: `{domain}/user/email/verify`
+///
+/// # HTTP request requirements
+/// - `user`, `exp` and `sig` query parameters, set by the link
+///
+/// # Example
+/// ```
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/user/email/verify?user=user_id&exp=1700000000&sig=signature")
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized if the link is invalid, expired or for a previous address of the user
+/// - Internal server error
+#[get("/user/email/verify")]
+pub async fn verify_email(
+    query: Query<VerifyQuery>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let mut user =
+        User::find_by_id(Some(&conn), &query.user).map_err(|_| AppError::UnauthorizedError)?;
+    let address = user.email.clone().ok_or(AppError::UnauthorizedError)?;
+
+    if !email::verify_link(
+        &app_state.email_config,
+        &user.id,
+        &address,
+        query.exp,
+        &query.sig,
+        Utc::now().timestamp(),
+    ) {
+        return Err(AppError::UnauthorizedError);
+    }
+    user.set_email_verified(Some(&conn))?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::mail::MemoryMailer,
+        auth::{password::hash_password, store::MemoryTokenStore, token::Token},
+        database::models::role::Role,
+    };
+    use actix_web::{test, App};
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_email_verification() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let mut appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        appstate.mailer = mailer.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(appstate.clone()))
+                .service(super::set_email)
+                .service(super::verify_email),
+        )
+        .await;
+
+        let usr = User::new(
+            Some(&appstate.psql_pool.get().unwrap()),
+            &String::from("Test email user"),
+            &hash_password("asd123").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let token = Token::new(
+            appstate.token_store.as_ref(),
+            &usr.id,
+            &appstate.session_config,
+        );
+
+        let req = test::TestRequest::put()
+            .uri("/user/email")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_payload("{ \"email\": \" Test.Email.User@Example.com \" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        let mail = mailer.sent.lock().unwrap().pop().unwrap();
+        debug_assert!(mail.to == "test.email.user@example.com");
+        let link = mail
+            .body
+            .split_whitespace()
+            .find(|word| word.contains("/user/email/verify?"))
+            .unwrap();
+        let path = &link[link.find("/user/email/verify").unwrap()..];
+
+        //A tampered link is rejected
+        let req = test::TestRequest::get()
+            .uri(&path.replace("exp=", "exp=1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get().uri(path).to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        let usr = User::find_by_id(Some(&appstate.psql_pool.get().unwrap()), &usr.id).unwrap();
+        debug_assert!(usr.email_verified);
+        usr.delete(Some(&appstate.psql_pool.get().unwrap()));
+    }
+}
@@ -0,0 +1,449 @@
+use actix_web::{
+    get,
+    http::header,
+    post,
+    web::{self, Data, Form, Query},
+    HttpRequest, HttpResponse,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+
+use crate::{
+    app::{mail::Mail, AppError, AppState},
+    auth::{
+        audit, csrf::CSRF_COOKIE, password::constant_time_eq, throttle::LoginThrottle,
+        token::SessionClient,
+    },
+    database::models::{auth_event::AuthEventKind, user::*},
+    routes::{token::start_session, two_factor::start_challenge},
+};
+
+/// Seconds a login link is valid for
+const LINK_TTL: i64 = 15 * 60;
+/// Length of the token of a login link
+const LINK_TOKEN_LENGTH: usize = 48;
+
+/** Only a digest of the link token is stored, so a leaked store doesn't allow logging in */
+fn link_key(token: &str) -> String {
+    format!("login_link:{}", sha256::digest(token.to_string()))
+}
+
+/** Link requests are throttled like failed logins, under their own names so they don't lock out logins */
+fn throttle_id(id: &str) -> String {
+    format!("login_link:{}", id)
+}
+
+/** Returns whether the value has the shape of a token issued by this site, only those are put into the confirmation page */
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[derive(Deserialize)]
+struct LinkRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+struct LinkQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+struct LinkConfirmation {
+    pub token: String,
+    pub csrf_token: String,
+}
+
+/** Mails a login link to the address if it's the verified email address of an user */
+fn send_login_link(app_state: &AppState, address: &str) -> Result<(), AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let user = match User::find_by_email(Some(&conn), address) {
+        Some(user) if user.email_verified => user,
+        _ => return Ok(()),
+    };
+
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), LINK_TOKEN_LENGTH);
+    app_state
+        .token_store
+        .set(&link_key(&token), &user.id, LINK_TTL)?;
+
+    app_state.mailer.send(&Mail {
+        to: address.to_string(),
+        subject: "Your login link".to_string(),
+        body: format!(
+            "Open this link within {} minutes to log in as {}:\n{}/user/login/link?token={}\n\
+            The link works once. If you didn't request it you can ignore this message.",
+            LINK_TTL / 60,
+            user.username,
+            app_state.email_config.base_url,
+            token
+        ),
+    })
+}
+
+/// Pipe for requesting a login link, a single use link is mailed to the address if it's the verified email address of an user.
+/// The address is looked up and the mail sent after responding, so neither the response nor how long it takes
+/// shows whether such an user exists. Requests are limited per address and per IP address like failed logins
+/// - url: `{domain}/user/login/link`
+///
+/// # HTTP request requirements
+/// ## body
+/// - json formatted string containing `email` key
+///
+/// # Example
+/// ```
+/// let data = "{ email: \"user@example.com\" }";
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/user/login/link")
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Bad request
+/// - Too many requests, with a `Retry-After` header holding the seconds until links can be requested again
+/// - Internal server error
+#[post("/user/login/link")]
+pub async fn request_login_link(
+    req: HttpRequest,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request =
+        serde_json::from_str::<LinkRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+    let address = request.email.trim().to_lowercase();
+
+    //Every request counts, whether the address belongs to an user or not
+    let store = app_state.token_store.as_ref();
+    let address_id = throttle_id(&address);
+    let ip_id = throttle_id(&LoginThrottle::client_ip(&req));
+    LoginThrottle::check(store, &address_id, &ip_id)?;
+    LoginThrottle::record_failure(store, &address_id, &ip_id, &app_state.throttle_config)?;
+
+    let app_state = app_state.into_inner();
+    actix_web::rt::spawn(async move {
+        let sent = web::block(move || send_login_link(&app_state, &address))
+            .await
+            .map_err(|_| AppError::InternalServerError)
+            .and_then(|sent| sent);
+        if let Err(err) = sent {
+            log::warn!("Login link could not be sent: {}", err);
+        }
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe opened through the link of a login mail, shows a page asking to confirm the login.
+/// Opening the link doesn't use it up, so mail scanners following links don't invalidate it,
+/// the login only happens once the form of the page is sent to [confirm_login_link]
+/// - url: `{domain}/user/login/link`
+///
+/// # HTTP request requirements
+/// - `token` query parameter, set by the link
+///
+/// # Example
+/// ```
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/user/login/link?token=login_token")
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - html page with a form posting the token together with the CSRF token
+/// - set cookie header containing the CSRF token, if the client had none
+/// ## Error
+/// - Unauthorized if the link is invalid, expired or was already used
+/// - Internal server error
+#[get("/user/login/link")]
+pub async fn login_with_link(
+    req: HttpRequest,
+    query: Query<LinkQuery>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    if !is_token(&query.token) {
+        return Err(AppError::UnauthorizedError);
+    }
+    let user_id = app_state
+        .token_store
+        .get(&link_key(&query.token))?
+        .ok_or(AppError::UnauthorizedError)?;
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let user = User::find_by_id(Some(&conn), &user_id).map_err(|_| AppError::UnauthorizedError)?;
+
+    //Pages may still hold the current CSRF token, a new one is only issued if the client has none
+    let csrf = match req.cookie(CSRF_COOKIE) {
+        Some(cookie) if is_token(cookie.value()) => None,
+        _ => Some(app_state.session_config.cookie.csrf_cookie()),
+    };
+    let csrf_token = match &csrf {
+        Some(cookie) => cookie.value().to_string(),
+        None => req
+            .cookie(CSRF_COOKIE)
+            .map(|cookie| cookie.value().to_string())
+            .unwrap_or_default(),
+    };
+
+    let page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Log in</title></head>\n<body>\n\
+        <form method=\"post\" action=\"/user/login/link/confirm\">\n\
+        <p>Log in as {}?</p>\n\
+        <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+        <input type=\"hidden\" name=\"csrf_token\" value=\"{}\">\n\
+        <button type=\"submit\">Log in</button>\n\
+        </form>\n</body>\n</html>\n",
+        escape_html(&user.username),
+        query.token,
+        csrf_token
+    );
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("text/html; charset=utf-8")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header((header::REFERRER_POLICY, "no-referrer"));
+    if let Some(csrf) = csrf {
+        response.cookie(csrf);
+    }
+    Ok(response.body(page))
+}
+
+/// Pipe the confirmation page of a login link posts to, logs the user in. The link can only be used once.
+/// The form has to repeat the `csrf_token` cookie, which browsers only send along with forms of this site,
+/// so another site can't log the browser into an account of its choice
+/// - url: `{domain}/user/login/link/confirm`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `csrf_token`, set by the confirmation page
+/// ## body
+/// - url encoded form containing `token` and `csrf_token` keys
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("csrf_token", "test_csrf_token").finish();
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/user/login/link/confirm")
+///     .cookie(cookie)
+///     .set_form(&[("token", "login_token"), ("csrf_token", "test_csrf_token")])
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - the same cookies as a [login](crate::routes::user::login)
+/// - if the user enabled two-factor authentication no token is issued, instead a json formatted string containing
+/// `two_factor_required` and `challenge` keys is returned, the challenge has to be answered at
+/// [verify_two_factor](crate::routes::two_factor::verify_two_factor)
+/// ## Error
+/// - Unauthorized if the link is invalid, expired or was already used
+/// - Forbidden if the CSRF token is missing or doesn't match the cookie
+/// - Internal server error
+#[post("/user/login/link/confirm")]
+pub async fn confirm_login_link(
+    req: HttpRequest,
+    form: Form<LinkConfirmation>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let csrf = req.cookie(CSRF_COOKIE).ok_or(AppError::Forbidden)?;
+    if csrf.value().is_empty()
+        || !constant_time_eq(csrf.value().as_bytes(), form.csrf_token.as_bytes())
+    {
+        return Err(AppError::Forbidden);
+    }
+
+    //Taking the token makes it single use, only one of two concurrent confirmations gets a session
+    let store = app_state.token_store.as_ref();
+    let user_id = store
+        .take(&link_key(&form.token))?
+        .ok_or(AppError::UnauthorizedError)?;
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let user = User::find_by_id(Some(&conn), &user_id).map_err(|_| AppError::UnauthorizedError)?;
+
+    if user.totp_enabled {
+        return start_challenge(store, &user.id, false, false);
+    }
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::LoginSuccess,
+        Some(&user.id),
+        Some(&user.username),
+    );
+    start_session(
+        store,
+        &app_state.session_config,
+        &user,
+        &SessionClient::from_request(&req),
+        false,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::mail::MemoryMailer,
+        auth::{password::hash_password, store::MemoryTokenStore},
+        database::models::role::Role,
+    };
+    use actix_web::{cookie::Cookie, http::StatusCode, test, App};
+    use std::{sync::Arc, time::Duration};
+
+    /** Waits for the mail sent after responding, `None` if none arrives within a few seconds */
+    async fn sent_mail(mailer: &MemoryMailer) -> Option<Mail> {
+        for _ in 0..50 {
+            if let Some(mail) = mailer.sent.lock().unwrap().pop() {
+                return Some(mail);
+            }
+            actix_rt::time::sleep(Duration::from_millis(100)).await;
+        }
+        None
+    }
+
+    #[actix_rt::test]
+    async fn test_login_link() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let mut appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        appstate.mailer = mailer.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(appstate.clone()))
+                .service(super::request_login_link)
+                .service(super::login_with_link)
+                .service(super::confirm_login_link),
+        )
+        .await;
+
+        let conn = appstate.psql_pool.get().unwrap();
+        let mut usr = User::new(
+            Some(&conn),
+            &String::from("test_link_user"),
+            &hash_password("test_password").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        usr.set_email(Some(&conn), Some(&"link.user@example.com".to_string()))
+            .unwrap();
+
+        //Unverified addresses don't get links
+        let req = test::TestRequest::post()
+            .uri("/user/login/link")
+            .set_payload("{ \"email\": \"Link.User@example.com\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        actix_rt::time::sleep(Duration::from_millis(500)).await;
+        debug_assert!(mailer.sent.lock().unwrap().is_empty());
+
+        usr.set_email_verified(Some(&conn)).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/user/login/link")
+            .set_payload("{ \"email\": \"Link.User@example.com\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        let mail = sent_mail(&mailer).await.unwrap();
+        debug_assert!(mail.to == "link.user@example.com");
+        let link = mail
+            .body
+            .split_whitespace()
+            .find(|word| word.contains("/user/login/link?"))
+            .unwrap();
+        let path = &link[link.find("/user/login/link").unwrap()..];
+        let token = &path[path.find("token=").unwrap() + "token=".len()..];
+
+        //Opening the link only shows the confirmation, so following it twice doesn't use it up
+        let mut csrf = None;
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri(path).to_request();
+            let resp = test::call_service(&app, req).await;
+            debug_assert!(resp.status().is_success());
+            csrf = resp
+                .response()
+                .cookies()
+                .find(|cookie| cookie.name() == CSRF_COOKIE)
+                .map(|cookie| cookie.into_owned());
+            let page = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+            debug_assert!(page.contains(token));
+            debug_assert!(page.contains("test_link_user"));
+        }
+        let csrf = csrf.unwrap();
+
+        //Another site can't post the form, the browser doesn't send it the CSRF cookie
+        let req = test::TestRequest::post()
+            .uri("/user/login/link/confirm")
+            .set_form([("token", token), ("csrf_token", csrf.value())])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/user/login/link/confirm")
+            .cookie(csrf.clone())
+            .set_form([("token", token), ("csrf_token", csrf.value())])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let session = resp
+            .headers()
+            .get_all("set-cookie")
+            .map(|header| Cookie::parse(header.to_str().unwrap().to_string()).unwrap())
+            .find(|cookie| cookie.name() == appstate.session_config.cookie.name());
+        debug_assert!(session.is_some_and(|cookie| !cookie.value().is_empty()));
+
+        //Links are single use
+        let req = test::TestRequest::post()
+            .uri("/user/login/link/confirm")
+            .cookie(csrf.clone())
+            .set_form([("token", token), ("csrf_token", csrf.value())])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get().uri(path).to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::UNAUTHORIZED);
+
+        //Requests for an address are limited, whether it belongs to an user or not
+        for _ in 0..appstate.throttle_config.max_failures {
+            let req = test::TestRequest::post()
+                .uri("/user/login/link")
+                .set_payload("{ \"email\": \"test_link_nobody@example.com\" }")
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            debug_assert!(resp.status().is_success());
+        }
+        let req = test::TestRequest::post()
+            .uri("/user/login/link")
+            .set_payload("{ \"email\": \"test_link_nobody@example.com\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == StatusCode::TOO_MANY_REQUESTS);
+
+        usr.delete(Some(&conn));
+    }
+}
@@ -1,4 +1,12 @@
+pub mod admin;
+pub mod api_key;
 pub mod blog;
 pub mod comment;
+pub mod device;
+pub mod email;
+pub mod magic_link;
+pub mod oidc;
+pub mod password;
 pub mod token;
+pub mod two_factor;
 pub mod user;
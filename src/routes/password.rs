@@ -0,0 +1,380 @@
+use actix_web::{
+    post, put,
+    web::{self, Data},
+    HttpRequest, HttpResponse,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+
+use crate::{
+    app::{mail::Mail, AppError, AppState},
+    auth::{
+        audit,
+        extractor::SessionUser,
+        password::{hash_password, verify_password},
+        password_policy::check_password,
+        throttle::LoginThrottle,
+        token::{SessionClient, Token},
+    },
+    database::models::{auth_event::AuthEventKind, user::*},
+    routes::token::{revoke_user_sessions, start_session},
+};
+
+/// Seconds a password reset token is valid for
+const RESET_TTL: i64 = 60 * 60;
+
+/** Reset requests are throttled like failed logins, under their own names so they don't lock out logins */
+fn throttle_id(id: &str) -> String {
+    format!("password_reset:{}", id)
+}
+
+/** Only a digest of the reset token is stored, so a leaked store doesn't allow resetting passwords */
+fn reset_key(token: &str) -> String {
+    format!("password_reset:{}", sha256::digest(token.to_string()))
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+struct ResetRequest {
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+struct CompleteResetRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// Pipe for changing the password of the logged in user.
+/// Every session of the user is revoked and a new one is started for the client changing the password
+/// - url: `{domain}/user/password`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+/// ## body
+/// - json formatted string containing `old_password` and `new_password` keys
+/// - `new_password` must follow the [password policy](crate::auth::password_policy::check_password)
+///
+/// # Example
+/// ```
+/// let data = "{ old_password: \"Old password\", new_password: \"New password\" }";
+/// let request = actix_web::test::TestRequest::put()
+///     .uri("localhost/user/password")
+///     .cookie(cookie)
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - the same response as a [login](crate::routes::user::login), tokens are returned in the body
+/// if the request was authenticated with the `Authorization` header
+/// ## Error
+/// - Unauthorized if the old password is wrong
+/// - Bad request, with a json formatted string containing the `reasons` if the new password was rejected
+/// - Internal server error
+#[put("/user/password")]
+pub async fn change_password(
+    req: HttpRequest,
+    SessionUser(auth): SessionUser,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request = serde_json::from_str::<ChangePasswordRequest>(&req_body)
+        .map_err(|_| AppError::BadRequest)?;
+    let mut user = auth.user;
+
+    if !verify_password(&request.old_password, &user.pass).is_valid() {
+        return Err(AppError::UnauthorizedError);
+    }
+    check_password(
+        &app_state.password_config,
+        &request.new_password,
+        Some(&user.username),
+    )
+    .await?;
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    user.set_password(Some(&conn), &hash_password(request.new_password.trim())?)?;
+    revoke_user_sessions(&app_state, &user.id)?;
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::PasswordChange,
+        Some(&user.id),
+        Some(&user.username),
+    );
+
+    start_session(
+        app_state.token_store.as_ref(),
+        &app_state.session_config,
+        &user,
+        &SessionClient::from_request(&req),
+        Token::from_header(&req).is_some(),
+    )
+}
+
+/** Mails a reset token to the verified email address of the user, if there is such an user */
+fn send_reset_token(app_state: &AppState, username: &String) -> Result<(), AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let user = match User::find_by_username(Some(&conn), username) {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    let address = match (&user.email, user.email_verified) {
+        (Some(address), true) => address.clone(),
+        _ => return Ok(()),
+    };
+
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 48);
+    app_state
+        .token_store
+        .set(&reset_key(&token), &user.id, RESET_TTL)?;
+
+    app_state.mailer.send(&Mail {
+        to: address,
+        subject: "Password reset".to_string(),
+        body: format!(
+            "A password reset was requested for your account.\n\
+            Use this token within {} minutes to choose a new password: {}\n\
+            If you didn't request it you can ignore this message.",
+            RESET_TTL / 60,
+            token
+        ),
+    })
+}
+
+/// Pipe for requesting a password reset, a single use reset token is mailed to the verified email address of the user.
+/// The user is looked up and the mail sent after responding, so neither the response nor how long it takes
+/// shows whether the user exists and has a verified address. Requests are limited per username and per IP address like failed logins
+/// - url: `{domain}/user/password/reset`
+///
+/// # HTTP request requirements
+/// ## body
+/// - json formatted string containing `username` key
+///
+/// # Example
+/// ```
+/// let data = "{ username: \"Test username\" }";
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/user/password/reset")
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Bad request
+/// - Too many requests, with a `Retry-After` header holding the seconds until resets can be requested again
+/// - Internal server error
+#[post("/user/password/reset")]
+pub async fn request_password_reset(
+    req: HttpRequest,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request =
+        serde_json::from_str::<ResetRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+
+    //Every request counts, whether the user exists or not
+    let store = app_state.token_store.as_ref();
+    let username_id = throttle_id(&request.username);
+    let ip_id = throttle_id(&LoginThrottle::client_ip(&req));
+    LoginThrottle::check(store, &username_id, &ip_id)?;
+    LoginThrottle::record_failure(store, &username_id, &ip_id, &app_state.throttle_config)?;
+
+    let app_state = app_state.into_inner();
+    actix_web::rt::spawn(async move {
+        let sent = web::block(move || send_reset_token(&app_state, &request.username))
+            .await
+            .map_err(|_| AppError::InternalServerError)
+            .and_then(|sent| sent);
+        if let Err(err) = sent {
+            log::warn!("Password reset could not be sent: {}", err);
+        }
+    });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe for choosing a new password with a reset token, the token can only be used once.
+/// Every session of the user is revoked
+/// - url: `{domain}/user/password/reset`
+///
+/// # HTTP request requirements
+/// ## body
+/// - json formatted string containing `token` and `new_password` keys
+/// - `new_password` must follow the [password policy](crate::auth::password_policy::check_password)
+///
+/// # Example
+/// ```
+/// let data = "{ token: \"Reset token\", new_password: \"New password\" }";
+/// let request = actix_web::test::TestRequest::put()
+///     .uri("localhost/user/password/reset")
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized if the token is invalid, expired or was already used
+/// - Bad request, with a json formatted string containing the `reasons` if the new password was rejected
+/// - Internal server error
+#[put("/user/password/reset")]
+pub async fn reset_password(
+    req: HttpRequest,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request = serde_json::from_str::<CompleteResetRequest>(&req_body)
+        .map_err(|_| AppError::BadRequest)?;
+    let store = app_state.token_store.as_ref();
+    let key = reset_key(&request.token);
+    let user_id = store.get(&key)?.ok_or(AppError::UnauthorizedError)?;
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let mut user =
+        User::find_by_id(Some(&conn), &user_id).map_err(|_| AppError::UnauthorizedError)?;
+
+    //Checked before taking the token so a rejected password doesn't use it up
+    check_password(
+        &app_state.password_config,
+        &request.new_password,
+        Some(&user.username),
+    )
+    .await?;
+    if store.take(&key)?.as_ref() != Some(&user_id) {
+        return Err(AppError::UnauthorizedError);
+    }
+    user.set_password(Some(&conn), &hash_password(request.new_password.trim())?)?;
+
+    revoke_user_sessions(&app_state, &user.id)?;
+    LoginThrottle::reset(store, &user.username)?;
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::PasswordChange,
+        Some(&user.id),
+        Some(&user.username),
+    );
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::mail::MemoryMailer,
+        auth::{store::MemoryTokenStore, token::Token},
+        database::models::role::Role,
+    };
+    use actix_web::{test, App};
+    use std::{sync::Arc, time::Duration};
+
+    /** Waits for the mail sent after responding, `None` if none arrives within a few seconds */
+    async fn sent_mail(mailer: &MemoryMailer) -> Option<Mail> {
+        for _ in 0..50 {
+            if let Some(mail) = mailer.sent.lock().unwrap().pop() {
+                return Some(mail);
+            }
+            actix_rt::time::sleep(Duration::from_millis(100)).await;
+        }
+        None
+    }
+
+    #[actix_rt::test]
+    async fn test_password_reset() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let mut appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        appstate.mailer = mailer.clone();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(super::request_password_reset)
+                .service(super::reset_password),
+        )
+        .await;
+
+        let conn = appstate.psql_pool.get().unwrap();
+        let mut usr = User::new(
+            Some(&conn),
+            &String::from("Test reset user"),
+            &hash_password("old_password").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        usr.set_email(Some(&conn), Some(&"reset.user@example.com".to_string()))
+            .unwrap();
+        usr.set_email_verified(Some(&conn)).unwrap();
+        let store = appstate.token_store.as_ref();
+        let session = Token::new(store, &usr.id, &appstate.session_config);
+
+        let req = test::TestRequest::post()
+            .uri("/user/password/reset")
+            .set_payload("{ \"username\": \"Test reset user\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        let mail = sent_mail(&mailer).await.unwrap();
+        debug_assert!(mail.to == "reset.user@example.com");
+
+        //Unknown users get the same response
+        let req = test::TestRequest::post()
+            .uri("/user/password/reset")
+            .set_payload("{ \"username\": \"Test unknown reset user\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let token = mail
+            .body
+            .split_whitespace()
+            .find(|word| word.len() == 48)
+            .unwrap();
+
+        let payload = format!(
+            "{{ \"token\": \"{}\", \"new_password\": \"new_password\" }}",
+            token
+        );
+        let req = test::TestRequest::put()
+            .uri("/user/password/reset")
+            .set_payload(payload.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(Token::find(store, &session, &appstate.session_config).is_err());
+
+        let usr = User::find_by_id(Some(&appstate.psql_pool.get().unwrap()), &usr.id).unwrap();
+        debug_assert!(verify_password("new_password", &usr.pass).is_valid());
+
+        //Reset tokens are single use
+        let req = test::TestRequest::put()
+            .uri("/user/password/reset")
+            .set_payload(payload)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+
+        usr.delete(Some(&appstate.psql_pool.get().unwrap()));
+    }
+}
@@ -1,19 +1,159 @@
-use actix_web::{
-    cookie::{time::OffsetDateTime, Cookie, Expiration},
-    delete, put,
-    web::Data,
-    HttpRequest, HttpResponse, Responder,
-};
+use actix_web::{delete, get, put, web::Data, HttpMessage, HttpRequest, HttpResponse, Responder};
 use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    app::{
+        config::{SessionConfig, SessionMode},
+        AppError, AppState,
+    },
+    auth::{
+        audit,
+        cookie::{REFRESH_COOKIE, REMEMBER_COOKIE},
+        extractor::SessionUser,
+        refresh::RefreshToken,
+        remember::{RememberMe, RestoredSession},
+        signed::SignedToken,
+        store::TokenStore,
+        token::{SessionClient, SessionMetadata, Token},
+    },
+    database::models::{
+        auth_event::AuthEventKind, impersonation::Impersonation,
+        remembered_device::RememberedDevice, user::*,
+    },
+};
+
+/** Body returned to clients which authenticate with the `Authorization` header instead of cookies */
+#[derive(Serialize)]
+pub(crate) struct TokenResponse {
+    pub token: String,
+    /// Seconds until the token expires unless it's refreshed
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    pub id: String,
+    #[serde(flatten)]
+    pub metadata: SessionMetadata,
+    pub current: bool,
+}
+
+/** Login token issued by [issue_token] */
+pub(crate) struct IssuedToken {
+    pub token: String,
+    /// Seconds until the token expires unless it's refreshed, the `token` cookie is kept for as long
+    pub expires_in: i64,
+}
+
+/** Issues a login token of the configured [mode](SessionMode) for the user, on behalf of the refresh token family */
+pub(crate) fn issue_token(
+    store: &dyn TokenStore,
+    config: &SessionConfig,
+    user: &User,
+    family: &String,
+    client: &SessionClient,
+) -> Result<IssuedToken, AppError> {
+    let now = Utc::now().timestamp();
+
+    match config.mode {
+        SessionMode::Opaque => {
+            let token = Token::new(store, &user.id, config);
+            let key = Token::key(config, &token);
+            Token::record_client(store, &key, client);
+            RefreshToken::attach(store, family, &key)?;
+
+            Ok(IssuedToken {
+                token,
+                expires_in: config.remaining_ttl(now, now),
+            })
+        }
+        SessionMode::Signed => Ok(IssuedToken {
+            token: SignedToken::issue(user, Some(family), config)?,
+            expires_in: config.ttl,
+        }),
+    }
+}
+
+/** Starts a new refresh token family for the user and issues its first login token, returns both tokens */
+pub(crate) fn new_session(
+    store: &dyn TokenStore,
+    config: &SessionConfig,
+    user: &User,
+    client: &SessionClient,
+) -> Result<(IssuedToken, String), AppError> {
+    let (refresh, family) = RefreshToken::issue(store, &user.id, config)?;
+    let issued = issue_token(store, config, user, &family, client)?;
 
-use crate::{app::AppState, auth::token::Token};
+    Ok((issued, refresh))
+}
+
+/** Logs the user in by issuing a login token and starting a new refresh token family.
+ * The tokens are returned as cookies, or in a json body if `return_token` is set
+ */
+pub(crate) fn start_session(
+    store: &dyn TokenStore,
+    config: &SessionConfig,
+    user: &User,
+    client: &SessionClient,
+    return_token: bool,
+) -> Result<HttpResponse, AppError> {
+    let (issued, refresh) = new_session(store, config, user, client)?;
+
+    if return_token {
+        return Ok(HttpResponse::Ok().json(TokenResponse {
+            token: issued.token,
+            expires_in: issued.expires_in,
+            refresh_token: Some(refresh),
+        }));
+    }
+
+    Ok(HttpResponse::Ok()
+        .cookie(
+            config
+                .cookie
+                .session_cookie(issued.token, issued.expires_in),
+        )
+        .cookie(config.cookie.refresh_cookie(refresh, config.refresh_ttl))
+        .cookie(config.cookie.csrf_cookie())
+        .finish())
+}
+
+/** Revokes the session stored under the [key](Token::key), sessions issued through a refresh token revoke their whole family */
+pub(crate) fn revoke_token(store: &dyn TokenStore, key: &str) {
+    match Token::family(store, key) {
+        Some(family) => RefreshToken::revoke_family(store, &family),
+        None => Token::delete(store, key),
+    }
+}
+
+/** Revokes every session, refresh token family and remembered device of the user, signed tokens included */
+pub(crate) fn revoke_user_sessions(app_state: &AppState, user_id: &String) -> Result<(), AppError> {
+    let store = app_state.token_store.as_ref();
+    Token::delete_by_user(store, user_id);
+    RefreshToken::delete_by_user(store, user_id);
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    RememberedDevice::delete_by_user(&conn, user_id)?;
+
+    app_state
+        .denylist
+        .revoke_user(store, user_id, &app_state.session_config)
+}
 
 /// Pipe for deauthorizing a token and removing it from the database
 /// - url: `{domain}/api/deauth`
 ///
 /// # HTTP request requirements
 /// ## header
-/// - cookie named `token` containing login token
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
 ///
 /// # Example
 /// ```
@@ -26,89 +166,382 @@ use crate::{app::AppState, auth::token::Token};
 ///
 /// # Response
 /// ## Ok
+/// - removal cookies for the login token, `refresh_token`, `csrf_token` and `remember_me` cookies,
+/// with the same path and domain they were set with. The remembered device is forgotten
 /// ## Error
 /// - Unauthorized
 #[delete("/api/deauth")]
 pub async fn deauth_token(req: HttpRequest, app_state: Data<AppState>) -> impl Responder {
-    let token = req.cookie("token");
+    let config = &app_state.session_config;
+    let restored = req
+        .extensions()
+        .get::<RestoredSession>()
+        .map(|session| session.0.clone());
+    let token = restored.or_else(|| Token::from_request(&req, &config.cookie));
     if token.is_none() {
         return HttpResponse::Unauthorized().finish();
     }
-    let token = token.unwrap().value().to_string();
+    let token = token.unwrap();
 
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
-    if Token::find(&mut redis_conn, &token).is_err() {
+    let store = app_state.token_store.as_ref();
+    let user_id = if SignedToken::is_signed(&token) {
+        let claims = match SignedToken::authenticate(store, &app_state.denylist, &token, config) {
+            Ok(claims) => claims,
+            Err(_) => return HttpResponse::Unauthorized().finish(),
+        };
+        if let Some(family) = &claims.fam {
+            RefreshToken::revoke_family(store, family);
+        }
+        if app_state.denylist.revoke(store, &claims, config).is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+        claims.sub
+    } else if let Ok(user_id) = Token::find(store, &token, config) {
+        let key = Token::key(config, &token);
+        if let Some(impersonator) = Token::impersonator(store, &key) {
+            if let Ok(conn) = app_state.psql_pool.get() {
+                let _res = Impersonation::end(&conn, &impersonator.record_id);
+            }
+        }
+        revoke_token(store, &key);
+        user_id
+    } else {
         return HttpResponse::Unauthorized().finish();
-    }
-    Token::delete(&mut redis_conn, &token);
-
-    let mut cookie = Cookie::build("token", "0").finish();
-    cookie.make_removal();
-    let mut response = HttpResponse::Ok().finish();
+    };
 
-    let _asd = HttpResponse::add_removal_cookie(&mut response, &cookie);
+    if let Some(refresh) = req.cookie(REFRESH_COOKIE) {
+        if let Ok(family) = RefreshToken::family(store, refresh.value(), config) {
+            RefreshToken::revoke_family(store, &family);
+        }
+    }
+    if let Some(remember) = req.cookie(REMEMBER_COOKIE) {
+        if let Ok(conn) = app_state.psql_pool.get() {
+            RememberMe::forget(&conn, remember.value());
+        }
+    }
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::Logout,
+        Some(&user_id),
+        None,
+    );
 
-    response
+    let mut response = HttpResponse::Ok();
+    for cookie in config.cookie.removal_cookies() {
+        response.cookie(cookie);
+    }
+    response.finish()
 }
 
-/// Pipe for refreshing a token for a server specified duration
+/// Pipe for obtaining a new login token.
+/// With a refresh token the refresh token is rotated and a new login token is issued,
+/// reusing an already rotated refresh token revokes every token issued since the login.
+/// Without one the login token itself is refreshed for the configured session duration, up to the configured maximum age.
+/// Login tokens issued on login and sent in the `Authorization` header, as well as signed login tokens,
+/// can only be replaced through the refresh token
 /// - url: `{domain}/api/refresh`
 ///
 /// # HTTP request requirements
 /// ## header
-/// - cookie named `token` containing login token
+/// - cookie named `refresh_token` containing the refresh token, or
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with a cookie
+/// ## body
+/// - optional json formatted string containing `refresh_token` key, for clients not using cookies
 ///
 /// # Example
 /// ```
-/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let cookie = CookieBuilder::new("refresh_token", "test_refresh_token").finish();
+/// let csrf = CookieBuilder::new("csrf_token", "test_csrf_token").finish();
 /// let request = actix_web::test::TestRequest::put()
 ///     .uri("localhost/api/refresh")
 ///     .cookie(cookie)
+///     .cookie(csrf)
+///     .insert_header(("X-CSRF-Token", "test_csrf_token"))
+///     .to_request();
+///
+/// let data = "{ refresh_token: \"test_refresh_token\" }";
+/// let request = actix_web::test::TestRequest::put()
+///     .uri("localhost/api/refresh")
+///     .set_payload(data)
 ///     .to_request();
 /// ```
 ///
 /// # Response
 /// ## Ok
-/// - set cookie header containing refreshed login cookie
+/// - set cookie headers containing the new login token, refresh token and CSRF token
+/// - json formatted string containing `token`, `expires_in` and `refresh_token` keys instead,
+/// if the refresh token was sent in the body or the login token in the `Authorization` header
 /// ## Error
 /// - Unauthorized
+/// - Forbidden, if authenticated with a cookie and the CSRF token is missing
+/// - Internal server error
 #[put("/api/refresh")]
-pub async fn refresh_token(req: HttpRequest, app_state: Data<AppState>) -> impl Responder {
-    let token = req.cookie("token");
-    if token.is_none() {
-        return HttpResponse::Unauthorized().finish();
+pub async fn refresh_token(
+    req: HttpRequest,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let store = app_state.token_store.as_ref();
+    let config = &app_state.session_config;
+
+    let body_refresh = serde_json::from_str::<Value>(req_body.trim())
+        .ok()
+        .and_then(|body| Some(body.get("refresh_token")?.as_str()?.to_string()));
+    let refresh = body_refresh
+        .clone()
+        .or_else(|| req.cookie(REFRESH_COOKIE).map(|c| c.value().to_string()));
+
+    if let Some(refresh) = refresh {
+        let (new_refresh, family) = RefreshToken::rotate(store, &refresh, config)?;
+        let user_id =
+            RefreshToken::family_owner(store, &family).map_err(|_| AppError::UnauthorizedError)?;
+
+        let psql_conn = app_state
+            .psql_pool
+            .get()
+            .map_err(|_| AppError::InternalServerError)?;
+        let user = User::find_by_id(Some(&psql_conn), &user_id)
+            .map_err(|_| AppError::UnauthorizedError)?;
+
+        //The login token being replaced isn't needed anymore
+        if let Some(old_token) = Token::from_request(&req, &config.cookie) {
+            if SignedToken::is_signed(&old_token) {
+                if let Ok(claims) = SignedToken::decode(&old_token, config) {
+                    if claims.fam.as_ref() == Some(&family) {
+                        let _res = app_state.denylist.revoke(store, &claims, config);
+                    }
+                }
+            } else {
+                let old_key = Token::key(config, &old_token);
+                if Token::family(store, &old_key).as_ref() == Some(&family) {
+                    Token::delete(store, &old_key);
+                }
+            }
+        }
+        let issued = issue_token(
+            store,
+            config,
+            &user,
+            &family,
+            &SessionClient::from_request(&req),
+        )?;
+        audit::record(
+            &app_state,
+            Some(&req),
+            AuthEventKind::Refresh,
+            Some(&user.id),
+            Some(&user.username),
+        );
+
+        if body_refresh.is_some() || Token::from_header(&req).is_some() {
+            return Ok(HttpResponse::Ok().json(TokenResponse {
+                token: issued.token,
+                expires_in: issued.expires_in,
+                refresh_token: Some(new_refresh),
+            }));
+        }
+
+        return Ok(HttpResponse::Ok()
+            .cookie(
+                config
+                    .cookie
+                    .session_cookie(issued.token, issued.expires_in),
+            )
+            .cookie(
+                config
+                    .cookie
+                    .refresh_cookie(new_refresh, config.refresh_ttl),
+            )
+            .cookie(config.cookie.csrf_cookie())
+            .finish());
     }
-    let token = token.unwrap().value().to_string();
 
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
-    if Token::find(&mut redis_conn, &token).is_err() {
-        return HttpResponse::Unauthorized().finish();
+    let token = Token::from_request(&req, &config.cookie).ok_or(AppError::UnauthorizedError)?;
+    let from_cookie = Token::from_header(&req).is_none();
+    let ttl =
+        Token::refresh(store, &token, config, from_cookie).ok_or(AppError::UnauthorizedError)?;
+    let user_id = Token::find(store, &token, config).map_err(|_| AppError::UnauthorizedError)?;
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::Refresh,
+        Some(&user_id),
+        None,
+    );
+
+    if !from_cookie {
+        return Ok(HttpResponse::Ok().json(TokenResponse {
+            token,
+            expires_in: ttl,
+            refresh_token: None,
+        }));
     }
-    Token::refresh(&mut redis_conn, &token);
 
-    let cookie = Cookie::build("token", token)
-        .path("/")
-        .expires(Expiration::DateTime(
-            OffsetDateTime::from_unix_timestamp(Utc::now().timestamp() + 180).unwrap(),
-        ))
-        .finish();
+    Ok(HttpResponse::Ok()
+        .cookie(config.cookie.session_cookie(token, ttl))
+        .cookie(config.cookie.csrf_cookie())
+        .finish())
+}
 
-    HttpResponse::Ok().cookie(cookie).finish()
+/// Pipe for listing all active sessions of the logged in user
+/// - url: `{domain}/api/sessions`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/api/sessions")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted list of sessions containing `id`, `created_at`, `last_used`, `ip`, `user_agent` and `current` keys,
+/// the timestamps are unix timestamps and `last_used` is accurate to a minute
+/// ## Error
+/// - Unauthorized
+/// - Internal server error
+#[get("/api/sessions")]
+pub async fn get_sessions(
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let store = app_state.token_store.as_ref();
+    let current = Token::key(&app_state.session_config, &auth.token);
+
+    let mut sessions = Vec::new();
+    for session in Token::find_by_user(store, &auth.user.id)? {
+        //The session may have expired since it was listed
+        if let Ok(metadata) = Token::metadata(store, &session) {
+            sessions.push(SessionInfo {
+                id: Token::session_id(&session),
+                metadata,
+                current: session == current,
+            });
+        }
+    }
+
+    Ok(HttpResponse::Ok().body(serde_json::to_string(&sessions)?))
+}
+
+/// Pipe for revoking one session of the logged in user
+/// - url: `{domain}/api/sessions/{session_id}`
+///
+/// # HTTP request requirements
+/// - `{session_id}` as parameter, as returned by [get_sessions]
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/api/sessions/session_id")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized
+/// - Bad request
+/// - Internal server error
+#[delete("/api/sessions/{session_id}")]
+pub async fn revoke_session(
+    req: HttpRequest,
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let session_id = req.match_info().query("session_id").to_string();
+
+    let store = app_state.token_store.as_ref();
+    let session = Token::find_by_user(store, &auth.user.id)?
+        .into_iter()
+        .find(|session| Token::session_id(session) == session_id)
+        .ok_or(AppError::BadRequest)?;
+    revoke_token(store, &session);
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::SessionRevoked,
+        Some(&auth.user.id),
+        Some(&auth.user.username),
+    );
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe for revoking every session and remembered device of the logged in user, including the session used for the request
+/// - url: `{domain}/api/sessions`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/api/sessions")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - removal cookie for the `token` cookie
+/// ## Error
+/// - Unauthorized
+#[delete("/api/sessions")]
+pub async fn revoke_all_sessions(
+    req: HttpRequest,
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    revoke_user_sessions(&app_state, &auth.user.id)?;
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::SessionRevoked,
+        Some(&auth.user.id),
+        Some(&auth.user.username),
+    );
+
+    let mut response = HttpResponse::Ok();
+    for cookie in app_state.session_config.cookie.removal_cookies() {
+        response.cookie(cookie);
+    }
+    Ok(response.finish())
 }
 
 #[cfg(test)]
 mod tests {
     use actix_web::{
-        cookie::CookieBuilder,
+        cookie::{Cookie, CookieBuilder},
         test::{self, call_service},
         App,
     };
 
     use super::*;
+    use crate::{
+        auth::{password::hash_password, store::MemoryTokenStore},
+        database::models::role::Role,
+    };
+    use std::sync::Arc;
 
     #[actix_rt::test]
     async fn test_deauth() {
-        let app_state = AppState::new(None);
+        let app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
 
         let app = test::init_service(
             App::new()
@@ -118,8 +551,17 @@ mod tests {
         .await;
 
         let user_id = "123456677899".to_string();
-        let token = Token::new(&mut app_state.redis_pool.get().unwrap(), &user_id);
-        debug_assert!(Token::find(&mut app_state.redis_pool.get().unwrap(), &token).is_ok());
+        let token = Token::new(
+            app_state.token_store.as_ref(),
+            &user_id,
+            &app_state.session_config,
+        );
+        debug_assert!(Token::find(
+            app_state.token_store.as_ref(),
+            &token,
+            &app_state.session_config,
+        )
+        .is_ok());
 
         let cookie = CookieBuilder::new("token", &token).finish();
         let req = test::TestRequest::delete()
@@ -131,12 +573,30 @@ mod tests {
 
         let resp = call_service(&app, req).await;
         debug_assert!(resp.status().is_success());
-        debug_assert!(Token::find(&mut app_state.redis_pool.get().unwrap(), &token).is_err())
+        debug_assert!(Token::find(
+            app_state.token_store.as_ref(),
+            &token,
+            &app_state.session_config,
+        )
+        .is_err());
+
+        //Removal cookies only work with the path they were set with
+        let removals: Vec<Cookie> = resp
+            .headers()
+            .get_all("set-cookie")
+            .map(|header| Cookie::parse(header.to_str().unwrap().to_string()).unwrap())
+            .collect();
+        debug_assert!(removals.len() == 4);
+        for (removal, path) in removals.iter().zip(["/", "/api", "/", "/"]) {
+            debug_assert!(removal.value().is_empty());
+            debug_assert!(removal.path() == Some(path));
+            debug_assert!(removal.max_age().unwrap().is_zero());
+        }
     }
 
     #[actix_rt::test]
     async fn test_refresh() {
-        let app_state = AppState::new(None);
+        let app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
 
         let app = test::init_service(
             App::new()
@@ -146,8 +606,17 @@ mod tests {
         .await;
 
         let user_id = "123456677899".to_string();
-        let token = Token::new(&mut app_state.redis_pool.get().unwrap(), &user_id);
-        debug_assert!(Token::find(&mut app_state.redis_pool.get().unwrap(), &token).is_ok());
+        let token = Token::new(
+            app_state.token_store.as_ref(),
+            &user_id,
+            &app_state.session_config,
+        );
+        debug_assert!(Token::find(
+            app_state.token_store.as_ref(),
+            &token,
+            &app_state.session_config,
+        )
+        .is_ok());
 
         let cookie = CookieBuilder::new("token", &token).finish();
         let req = test::TestRequest::put()
@@ -159,7 +628,302 @@ mod tests {
 
         let resp = call_service(&app, req).await;
         debug_assert!(resp.status().is_success());
-        debug_assert!(Token::find(&mut app_state.redis_pool.get().unwrap(), &token).is_ok());
-        Token::delete(&mut app_state.redis_pool.get().unwrap(), &token)
+        debug_assert!(Token::find(
+            app_state.token_store.as_ref(),
+            &token,
+            &app_state.session_config,
+        )
+        .is_ok());
+
+        let cookie_config = &app_state.session_config.cookie;
+        let set_cookie = resp.headers().get("set-cookie").unwrap().to_str().unwrap();
+        let cookie = Cookie::parse(set_cookie).unwrap();
+        debug_assert!(cookie.name() == cookie_config.name());
+        debug_assert!(cookie.max_age().is_some());
+        debug_assert!(cookie.max_age().unwrap().whole_seconds() > 0);
+        debug_assert!(cookie.path() == Some("/"));
+        debug_assert!(cookie.http_only() == Some(cookie_config.http_only));
+        debug_assert!(cookie.secure() == Some(cookie_config.secure));
+        debug_assert!(cookie.same_site() == Some(cookie_config.same_site));
+        Token::delete(
+            app_state.token_store.as_ref(),
+            &Token::key(&app_state.session_config, &token),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn test_revoke_all_sessions() {
+        let app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(app_state.clone()))
+                .service(super::get_sessions)
+                .service(super::revoke_all_sessions),
+        )
+        .await;
+
+        let usr = User::new(
+            Some(&app_state.psql_pool.get().unwrap()),
+            &String::from("Test sessions user"),
+            &hash_password("asd123").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let store = app_state.token_store.as_ref();
+        let first = Token::new(store, &usr.id, &app_state.session_config);
+        let second = Token::new(store, &usr.id, &app_state.session_config);
+
+        let req = test::TestRequest::get()
+            .uri("/api/sessions")
+            .cookie(CookieBuilder::new("token", &first).finish())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let sessions: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        debug_assert!(sessions.as_array().unwrap().len() >= 2);
+
+        let req = test::TestRequest::delete()
+            .uri("/api/sessions")
+            .cookie(CookieBuilder::new("token", &first).finish())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(Token::find(store, &first, &app_state.session_config).is_err());
+        debug_assert!(Token::find(store, &second, &app_state.session_config).is_err());
+
+        usr.delete(Some(&app_state.psql_pool.get().unwrap()));
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_bearer() {
+        let app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(app_state.clone()))
+                .service(super::refresh_token),
+        )
+        .await;
+
+        let user_id = "123456677899".to_string();
+        let store = app_state.token_store.as_ref();
+        let token = Token::new(store, &user_id, &app_state.session_config);
+
+        let req = test::TestRequest::put()
+            .uri("/api/refresh")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(resp.headers().get("set-cookie").is_none());
+        Token::delete(store, &Token::key(&app_state.session_config, &token));
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_token_reuse() {
+        let app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(app_state.clone()))
+                .service(super::refresh_token),
+        )
+        .await;
+
+        let usr = User::new(
+            Some(&app_state.psql_pool.get().unwrap()),
+            &String::from("Test refresh user"),
+            &hash_password("asd123").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let store = app_state.token_store.as_ref();
+        let (refresh, _family) =
+            RefreshToken::issue(store, &usr.id, &app_state.session_config).unwrap();
+
+        let req = test::TestRequest::put()
+            .uri("/api/refresh")
+            .set_payload(format!("{{ \"refresh_token\": \"{}\" }}", refresh))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let data: Value = serde_json::from_slice(&body).unwrap();
+        let rotated = data
+            .get("refresh_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+        let token = data.get("token").unwrap().as_str().unwrap().to_string();
+        debug_assert!(Token::find(store, &token, &app_state.session_config).is_ok());
+
+        //The login token alone can't extend itself
+        let req = test::TestRequest::put()
+            .uri("/api/refresh")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+
+        //Presenting the replaced refresh token again revokes the whole family
+        let req = test::TestRequest::put()
+            .uri("/api/refresh")
+            .set_payload(format!("{{ \"refresh_token\": \"{}\" }}", refresh))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+        debug_assert!(Token::find(store, &token, &app_state.session_config).is_err());
+
+        let req = test::TestRequest::put()
+            .uri("/api/refresh")
+            .set_payload(format!("{{ \"refresh_token\": \"{}\" }}", rotated))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+
+        usr.delete(Some(&app_state.psql_pool.get().unwrap()));
+    }
+
+    #[actix_rt::test]
+    async fn test_deauth_signed() {
+        let mut app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        app_state.session_config.mode = SessionMode::Signed;
+        app_state.session_config.signing_key =
+            Some("test signing key which is long enough".to_string());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(app_state.clone()))
+                .service(super::deauth_token),
+        )
+        .await;
+
+        let usr = User {
+            id: "123456677899".to_string(),
+            username: "Test signed user".to_string(),
+            pass: String::new(),
+            totp_secret: None,
+            totp_enabled: false,
+            role: Role::Author.to_string(),
+            email: None,
+            email_verified: false,
+        };
+        let store = app_state.token_store.as_ref();
+        let config = &app_state.session_config;
+        let (_refresh, family) = RefreshToken::issue(store, &usr.id, config).unwrap();
+        let token = issue_token(store, config, &usr, &family, &SessionClient::default())
+            .unwrap()
+            .token;
+        debug_assert!(
+            SignedToken::authenticate(store, &app_state.denylist, &token, config).is_ok()
+        );
+
+        let req = test::TestRequest::delete()
+            .uri("/api/deauth")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(
+            SignedToken::authenticate(store, &app_state.denylist, &token, config).is_err()
+        );
+        debug_assert!(RefreshToken::family_owner(store, &family).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_session_metadata() {
+        let app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(app_state.clone()))
+                .service(super::get_sessions),
+        )
+        .await;
+
+        let usr = User::new(
+            Some(&app_state.psql_pool.get().unwrap()),
+            &String::from("Test session metadata user"),
+            &hash_password("asd123").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let store = app_state.token_store.as_ref();
+        let config = &app_state.session_config;
+        let (_refresh, family) = RefreshToken::issue(store, &usr.id, config).unwrap();
+        let client = SessionClient {
+            ip: Some("192.0.2.1".to_string()),
+            user_agent: Some("metadata-test".to_string()),
+        };
+        let token = issue_token(store, config, &usr, &family, &client)
+            .unwrap()
+            .token;
+        let key = Token::key(config, &token);
+
+        //Uses within a minute aren't recorded
+        let metadata = Token::metadata(store, &key).unwrap();
+        store
+            .set_field(&key, "last_used", &(metadata.created_at - 30).to_string())
+            .unwrap();
+        Token::find(store, &token, config).unwrap();
+        debug_assert!(Token::metadata(store, &key).unwrap().last_used == metadata.created_at - 30);
+
+        store
+            .set_field(&key, "last_used", &(metadata.created_at - 120).to_string())
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri("/api/sessions")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .insert_header(("User-Agent", "metadata-test-2"))
+            .to_request();
+        let sessions: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let session = &sessions.as_array().unwrap()[0];
+        debug_assert!(session["current"] == true);
+        debug_assert!(session["last_used"].as_i64().unwrap() >= metadata.created_at);
+        debug_assert!(session["user_agent"] == "metadata-test-2");
+        debug_assert!(session["ip"].is_string());
+
+        revoke_user_sessions(&app_state, &usr.id).unwrap();
+        usr.delete(Some(&app_state.psql_pool.get().unwrap()));
+    }
+
+    #[actix_rt::test]
+    async fn test_legacy_token_migration() {
+        let app_state = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+        let store = app_state.token_store.as_ref();
+        let config = &app_state.session_config;
+
+        //Sessions used to be stored under the token itself
+        let user_id = "123456677899".to_string();
+        let token = "LegacyToken0123456789abcdefghijk".to_string();
+        let now = Utc::now().timestamp();
+        store
+            .set_hash(
+                &token,
+                &[
+                    ("user_id", user_id.clone()),
+                    ("created_at", now.to_string()),
+                ],
+                config.ttl,
+            )
+            .unwrap();
+        store
+            .add_to_set(&format!("sessions:{}", user_id), &token, config.max_age)
+            .unwrap();
+
+        debug_assert!(Token::find(store, &token, config).unwrap() == user_id);
+        let key = Token::key(config, &token);
+        debug_assert!(store.get_field(&token, "user_id").unwrap().is_none());
+        //The session is renamed, so it keeps its expiry
+        debug_assert!(store.ttl(&key).unwrap().unwrap() <= config.ttl);
+        debug_assert!(Token::find_by_user(store, &user_id).unwrap() == vec![key.clone()]);
+        debug_assert!(Token::find(store, &token, config).is_ok());
+
+        Token::delete(store, &key);
+        debug_assert!(Token::find(store, &token, config).is_err());
     }
 }
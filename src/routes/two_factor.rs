@@ -0,0 +1,530 @@
+use actix_web::{delete, post, web::Data, HttpRequest, HttpResponse};
+use chrono::Utc;
+use diesel::{
+    r2d2::{ConnectionManager, PooledConnection},
+    PgConnection,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{AppError, AppState},
+    auth::{
+        audit, extractor::SessionUser, store::TokenStore, throttle::LoginThrottle,
+        token::SessionClient, totp,
+    },
+    database::models::{auth_event::AuthEventKind, recovery_code::RecoveryCode, user::*},
+    routes::{device::remember_device, token::start_session},
+};
+
+/// Seconds a login challenge can be answered for
+const CHALLENGE_TTL: i64 = 300;
+/// Wrong codes after which a login challenge is dropped and the user has to log in again
+const CHALLENGE_ATTEMPTS: i64 = 5;
+
+fn challenge_key(challenge: &str) -> String {
+    format!("2fa_challenge:{}", challenge)
+}
+/** Holds the last time step a TOTP code of the user was accepted for, so a code can't be used twice */
+fn last_step_key(user_id: &str) -> String {
+    format!("totp_used:{}", user_id)
+}
+
+#[derive(Deserialize)]
+struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyRequest {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Serialize)]
+struct EnrollResponse {
+    pub secret: String,
+    pub uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ChallengeResponse {
+    pub two_factor_required: bool,
+    pub challenge: String,
+}
+
+/** Answers a login of an user with 2FA enabled, the session is only started once the challenge is answered with a code */
+pub(crate) fn start_challenge(
+    store: &dyn TokenStore,
+    user_id: &str,
+    return_token: bool,
+    remember_me: bool,
+) -> Result<HttpResponse, AppError> {
+    let challenge = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    store.set_hash(
+        &challenge_key(&challenge),
+        &[
+            ("user_id", user_id.to_string()),
+            ("return_token", return_token.to_string()),
+            ("remember_me", remember_me.to_string()),
+            ("attempts", "0".to_string()),
+        ],
+        CHALLENGE_TTL,
+    )?;
+
+    Ok(HttpResponse::Ok().json(ChallengeResponse {
+        two_factor_required: true,
+        challenge,
+    }))
+}
+
+/** Checks a TOTP code of the user, and if `allow_recovery` is set a recovery code, which is consumed.
+ * A secret stored before secrets were encrypted is encrypted once a code of it is accepted
+ */
+fn verify_second_factor(
+    conn: &PooledConnection<ConnectionManager<PgConnection>>,
+    app_state: &AppState,
+    user: &mut User,
+    code: &str,
+    allow_recovery: bool,
+) -> bool {
+    let store = app_state.token_store.as_ref();
+    let config = &app_state.two_factor_config;
+    let secret = user
+        .totp_secret
+        .as_ref()
+        .and_then(|stored| totp::open_secret(config, &user.id, stored));
+    let step = secret
+        .as_ref()
+        .and_then(|secret| totp::verify(secret, code, Utc::now().timestamp()));
+
+    if let Some(step) = step {
+        //Codes are valid for at most three steps, checking and marking the step in one go
+        //keeps concurrent requests from using the same code
+        if !store
+            .set_if_greater(&last_step_key(&user.id), step, 90)
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        if let (Some(secret), Some(stored)) = (&secret, &user.totp_secret) {
+            if !totp::is_sealed(stored) {
+                let enabled = user.totp_enabled;
+                if let Ok(sealed) = totp::seal_secret(config, &user.id, secret) {
+                    let _res = user.set_totp(Some(conn), Some(&sealed), enabled);
+                }
+            }
+        }
+        return true;
+    }
+
+    allow_recovery && RecoveryCode::redeem(conn, &user.id, code)
+}
+
+/// Pipe for starting the enrollment in two-factor authentication.
+/// The returned secret only has to be entered when 2FA is [confirmed](confirm_two_factor)
+/// - url: `{domain}/user/2fa/enroll`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/user/2fa/enroll")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted string containing the base32 `secret` and its `otpauth://` `uri`
+/// ## Error
+/// - Unauthorized
+/// - Bad request if 2FA is already enabled
+/// - Internal server error
+#[post("/user/2fa/enroll")]
+pub async fn enroll_two_factor(
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let mut user = auth.user;
+    if user.totp_enabled {
+        return Err(AppError::BadRequest);
+    }
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let secret = totp::generate_secret();
+    let sealed = totp::seal_secret(&app_state.two_factor_config, &user.id, &secret)?;
+    user.set_totp(Some(&conn), Some(&sealed), false)?;
+
+    Ok(HttpResponse::Ok().json(EnrollResponse {
+        uri: totp::provisioning_uri(&secret, &user.username),
+        secret,
+    }))
+}
+
+/// Pipe for enabling two-factor authentication with a code generated from the enrolled secret
+/// - url: `{domain}/user/2fa/confirm`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+/// ## body
+/// - json formatted string containing `code` key
+///
+/// # Example
+/// ```
+/// let data = "{ code: \"123456\" }";
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/user/2fa/confirm")
+///     .cookie(cookie)
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted string containing `recovery_codes` key, the codes are only shown once
+/// ## Error
+/// - Unauthorized if the code is wrong
+/// - Bad request if the user didn't enroll or 2FA is already enabled
+/// - Internal server error
+#[post("/user/2fa/confirm")]
+pub async fn confirm_two_factor(
+    SessionUser(auth): SessionUser,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request =
+        serde_json::from_str::<CodeRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+    let mut user = auth.user;
+    if user.totp_secret.is_none() || user.totp_enabled {
+        return Err(AppError::BadRequest);
+    }
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    if !verify_second_factor(&conn, &app_state, &mut user, &request.code, false) {
+        return Err(AppError::UnauthorizedError);
+    }
+
+    let recovery_codes = totp::generate_recovery_codes();
+    RecoveryCode::replace_for_user(&conn, &user.id, &recovery_codes)?;
+    let secret = user.totp_secret.clone();
+    user.set_totp(Some(&conn), secret.as_ref(), true)?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }))
+}
+
+/// Pipe for disabling two-factor authentication, requires a current TOTP or recovery code
+/// - url: `{domain}/user/2fa`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
+/// ## body
+/// - json formatted string containing `code` key
+///
+/// # Example
+/// ```
+/// let data = "{ code: \"123456\" }";
+/// let request = actix_web::test::TestRequest::delete()
+///     .uri("localhost/user/2fa")
+///     .cookie(cookie)
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// ## Error
+/// - Unauthorized if the code is wrong
+/// - Bad request if 2FA isn't enabled
+/// - Internal server error
+#[delete("/user/2fa")]
+pub async fn disable_two_factor(
+    SessionUser(auth): SessionUser,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request =
+        serde_json::from_str::<CodeRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+    let mut user = auth.user;
+    if !user.totp_enabled {
+        return Err(AppError::BadRequest);
+    }
+
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    if !verify_second_factor(&conn, &app_state, &mut user, &request.code, true) {
+        return Err(AppError::UnauthorizedError);
+    }
+
+    user.set_totp(Some(&conn), None, false)?;
+    RecoveryCode::delete_by_user(&conn, &user.id)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Pipe for finishing a login of an user with two-factor authentication enabled
+/// - url: `{domain}/user/2fa/verify`
+///
+/// # HTTP request requirements
+/// ## body
+/// - json formatted string containing the `challenge` returned by [login](crate::routes::user::login)
+/// and `code` keys, `code` is either a TOTP code or an unused recovery code
+///
+/// # Example
+/// ```
+/// let data = "{ challenge: \"test_challenge\", code: \"123456\" }";
+/// let request = actix_web::test::TestRequest::post()
+///     .uri("localhost/user/2fa/verify")
+///     .set_payload(data)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - the same response as a [login](crate::routes::user::login) without 2FA
+/// ## Error
+/// - Unauthorized if the challenge expired or the code is wrong,
+/// after 5 wrong codes the challenge is dropped
+/// - Too many requests with a `Retry-After` header, wrong codes count as failed logins of the user
+/// - Bad request
+/// - Internal server error
+#[post("/user/2fa/verify")]
+pub async fn verify_two_factor(
+    req: HttpRequest,
+    req_body: String,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let request =
+        serde_json::from_str::<VerifyRequest>(&req_body).map_err(|_| AppError::BadRequest)?;
+    let store = app_state.token_store.as_ref();
+    let key = challenge_key(&request.challenge);
+
+    let user_id = store
+        .get_field(&key, "user_id")?
+        .ok_or(AppError::UnauthorizedError)?;
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+    let mut user =
+        User::find_by_id(Some(&conn), &user_id).map_err(|_| AppError::UnauthorizedError)?;
+    let ip = LoginThrottle::client_ip(&req);
+    LoginThrottle::check(store, &user.username, &ip)?;
+
+    //Every answer is counted before it's checked, so concurrent guesses can't exceed the attempts
+    let attempts = store
+        .increment_field(&key, "attempts")?
+        .ok_or(AppError::UnauthorizedError)?;
+    if attempts > CHALLENGE_ATTEMPTS {
+        store.delete(&key)?;
+        return Err(AppError::UnauthorizedError);
+    }
+
+    if !verify_second_factor(&conn, &app_state, &mut user, &request.code, true) {
+        if attempts == CHALLENGE_ATTEMPTS {
+            store.delete(&key)?;
+        }
+        audit::record(
+            &app_state,
+            Some(&req),
+            AuthEventKind::LoginFailure,
+            Some(&user.id),
+            Some(&user.username),
+        );
+        //Wrong codes count like wrong passwords, new challenges don't get a fresh budget
+        let locked_for =
+            LoginThrottle::record_failure(store, &user.username, &ip, &app_state.throttle_config)?;
+        return match locked_for {
+            0 => Err(AppError::UnauthorizedError),
+            locked_for => Err(AppError::TooManyRequests(locked_for)),
+        };
+    }
+
+    //Deleting the challenge makes it single use, only one of two concurrent answers gets a session
+    let return_token = store.get_field(&key, "return_token")?.as_deref() == Some("true");
+    let remember_me = store.get_field(&key, "remember_me")?.as_deref() == Some("true");
+    if !store.delete(&key)? {
+        return Err(AppError::UnauthorizedError);
+    }
+    LoginThrottle::reset(store, &user.username)?;
+
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::LoginSuccess,
+        Some(&user.id),
+        Some(&user.username),
+    );
+    let client = SessionClient::from_request(&req);
+    let mut response = start_session(
+        store,
+        &app_state.session_config,
+        &user,
+        &client,
+        return_token,
+    )?;
+    if remember_me && !return_token {
+        remember_device(&app_state, &user.id, &client, &mut response)?;
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{password::hash_password, store::MemoryTokenStore, token::Token},
+        database::models::role::Role,
+        routes::user::login,
+    };
+    use actix_web::{body, cookie::CookieBuilder, test, App};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_two_factor_login() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(login)
+                .service(super::enroll_two_factor)
+                .service(super::confirm_two_factor)
+                .service(super::verify_two_factor),
+        )
+        .await;
+
+        let usr = User::new(
+            Some(&appstate.psql_pool.get().unwrap()),
+            &String::from("Test 2fa user"),
+            &hash_password("test_password").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let token = Token::new(
+            appstate.token_store.as_ref(),
+            &usr.id,
+            &appstate.session_config,
+        );
+        let cookie = CookieBuilder::new("token", &token).path("/").finish();
+
+        let req = test::TestRequest::post()
+            .uri("/user/2fa/enroll")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let data: Value =
+            serde_json::from_slice(&body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+        let secret = data.get("secret").unwrap().as_str().unwrap().to_string();
+        let stored = User::find_by_id(Some(&appstate.psql_pool.get().unwrap()), &usr.id)
+            .unwrap()
+            .totp_secret
+            .unwrap();
+        debug_assert!(totp::is_sealed(&stored) && !stored.contains(&secret));
+        debug_assert!(data
+            .get("uri")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/"));
+
+        let code = totp::generate_code(&secret, Utc::now().timestamp()).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/user/2fa/confirm")
+            .cookie(cookie)
+            .set_payload(format!("{{ \"code\": \"{}\" }}", code))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        let data: Value =
+            serde_json::from_slice(&body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+        let recovery_codes = data.get("recovery_codes").unwrap().as_array().unwrap();
+        debug_assert!(recovery_codes.len() == 10);
+        let recovery_code = recovery_codes[0].as_str().unwrap().to_string();
+
+        //The password alone only yields a challenge
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .set_payload("{ \"username\": \"Test 2fa user\", \"password\": \"test_password\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(resp.headers().get("set-cookie").is_none());
+        let data: Value =
+            serde_json::from_slice(&body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+        let challenge = data.get("challenge").unwrap().as_str().unwrap().to_string();
+
+        //Wrong codes count against the login throttle of the user, answering the challenge resets it
+        let req = test::TestRequest::post()
+            .uri("/user/2fa/verify")
+            .set_payload(format!(
+                "{{ \"challenge\": \"{}\", \"code\": \"000000-0\" }}",
+                challenge
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+        let store = appstate.token_store.as_ref();
+        debug_assert!(
+            LoginThrottle::status(store, "Test 2fa user")
+                .unwrap()
+                .failures
+                == 1
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/user/2fa/verify")
+            .set_payload(format!(
+                "{{ \"challenge\": \"{}\", \"code\": \"{}\" }}",
+                challenge, recovery_code
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(resp.headers().get("set-cookie").is_some());
+        debug_assert!(
+            LoginThrottle::status(store, "Test 2fa user")
+                .unwrap()
+                .failures
+                == 0
+        );
+
+        //Recovery codes and challenges can only be used once
+        let req = test::TestRequest::post()
+            .uri("/user/2fa/verify")
+            .set_payload(format!(
+                "{{ \"challenge\": \"{}\", \"code\": \"{}\" }}",
+                challenge, recovery_code
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+
+        usr.delete(Some(&appstate.psql_pool.get().unwrap()));
+    }
+}
@@ -1,24 +1,38 @@
-use actix_web::{
-    cookie::{time::OffsetDateTime, Cookie, Expiration},
-    delete, get, post,
-    web::Data,
-    HttpRequest, HttpResponse,
-};
-use chrono::Utc;
+use actix_web::{delete, get, post, web::Data, HttpRequest, HttpResponse};
+use diesel::Connection;
 use serde::Deserialize;
 use serde_json::Value;
-use sha256::digest;
 
 use crate::{
     app::{AppError, AppState},
-    auth::token::Token,
-    database::models::user::*,
+    auth::{
+        audit,
+        extractor::{AuthenticatedUser, SessionUser},
+        password::{hash_password, verify_dummy, verify_password, Verification},
+        password_policy::check_password,
+        policy::{authorize, Action, Resource},
+        throttle::LoginThrottle,
+        token::SessionClient,
+    },
+    database::models::{
+        auth_event::{AuthEvent, AuthEventKind},
+        role::Role,
+        user::*,
+    },
+    routes::{
+        device::remember_device,
+        email::{check_new_email, send_verification},
+        token::{revoke_user_sessions, start_session},
+        two_factor::start_challenge,
+    },
 };
 
 #[derive(Deserialize)]
 struct DummyUser {
     pub username: String,
     pub password: String,
+    /// Only read on registration
+    pub email: Option<String>,
 }
 
 /// Pipe for logging in as user
@@ -27,6 +41,9 @@ struct DummyUser {
 /// # HTTP request requirements
 /// ## body
 /// - json formatted string containing `username` and `password` keys
+/// - optional `return_token` key, if `true` the tokens are returned in the body instead of cookies
+/// - optional `remember_me` key, if `true` the device is remembered and a new session is started
+/// whenever the previous one expired, ignored if `return_token` is set
 ///
 /// # Example
 /// ```
@@ -40,13 +57,23 @@ struct DummyUser {
 /// # Response
 /// ## Ok
 /// - set cookie header containing login token
+/// - set cookie header containing refresh token
+/// - set cookie header containing CSRF token, cookie authenticated POST, PUT and DELETE requests
+/// have to repeat it in the `X-CSRF-Token` header
+/// - set cookie header containing the remembered device credential if `remember_me` was requested
+/// - json formatted string containing `token`, `expires_in` and `refresh_token` keys if `return_token` was requested
+/// - if the user enabled two-factor authentication no token is issued, instead a json formatted string containing
+/// `two_factor_required` and `challenge` keys is returned, the challenge has to be answered at
+/// [verify_two_factor](crate::routes::two_factor::verify_two_factor)
 /// ## Error
 /// - Bad request
 /// - Unauthorized
+/// - Too many requests with a `Retry-After` header, after repeated failed logins
+/// for the username or from the same address
 /// - Internal server error
 #[get("/user")]
 pub async fn login(
-    _req: HttpRequest,
+    req: HttpRequest,
     req_body: String,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
@@ -56,47 +83,98 @@ pub async fn login(
     }
 
     let psql_conn = app_state.psql_pool.clone().get().unwrap();
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
+    let store = app_state.token_store.as_ref();
     let username = credentials
         .get("username")
         .unwrap()
         .as_str()
         .unwrap()
         .to_string();
-    let pw = digest(
-        credentials
-            .get("password")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .to_string(),
-    );
+    let pw = credentials
+        .get("password")
+        .unwrap()
+        .as_str()
+        .unwrap()
+        .to_string();
 
-    let user =
-        User::find_by_username(Some(&psql_conn), &username).ok_or(AppError::UnauthorizedError)?;
+    let ip = LoginThrottle::client_ip(&req);
+    LoginThrottle::check(store, &username, &ip)?;
+
+    let found = User::find_by_username(Some(&psql_conn), &username);
+    let verification = match &found {
+        Some(user) => verify_password(&pw, &user.pass),
+        None => {
+            verify_dummy(&pw);
+            Verification::Invalid
+        }
+    };
+    let found_id = found.as_ref().map(|user| user.id.clone());
+    let mut user = match found {
+        Some(user) if verification.is_valid() => user,
+        _ => {
+            audit::record(
+                &app_state,
+                Some(&req),
+                AuthEventKind::LoginFailure,
+                found_id.as_ref(),
+                Some(&username),
+            );
+            let locked_for =
+                LoginThrottle::record_failure(store, &username, &ip, &app_state.throttle_config)?;
+            return match locked_for {
+                0 => Err(AppError::UnauthorizedError),
+                locked_for => Err(AppError::TooManyRequests(locked_for)),
+            };
+        }
+    };
+    if verification == Verification::ValidNeedsRehash {
+        //Upgrades legacy SHA256 accounts, a failure here shouldn't block the login
+        if let Ok(new_hash) = hash_password(&pw) {
+            let _res = user.set_password(Some(&psql_conn), &new_hash);
+        }
+    }
 
-    if user.pass != pw {
-        return Err(AppError::UnauthorizedError);
+    let return_token = credentials.get("return_token").and_then(Value::as_bool) == Some(true);
+    let remember_me = credentials.get("remember_me").and_then(Value::as_bool) == Some(true);
+    //The failures are only forgotten once the second factor was answered too
+    if user.totp_enabled {
+        return start_challenge(store, &user.id, return_token, remember_me);
     }
+    LoginThrottle::reset(store, &username)?;
 
-    let token = Token::new(&mut redis_conn, &user.id);
-    let cookie = Cookie::build("token", token)
-        .path("/")
-        .expires(Expiration::DateTime(
-            OffsetDateTime::from_unix_timestamp(Utc::now().timestamp() + 180).unwrap(),
-        ))
-        .finish();
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::LoginSuccess,
+        Some(&user.id),
+        Some(&user.username),
+    );
+    let client = SessionClient::from_request(&req);
+    let mut response = start_session(
+        store,
+        &app_state.session_config,
+        &user,
+        &client,
+        return_token,
+    )?;
+    //Bearer clients keep their own refresh token, remembering the device only works with cookies
+    if remember_me && !return_token {
+        remember_device(&app_state, &user.id, &client, &mut response)?;
+    }
 
-    Ok(HttpResponse::Ok().cookie(cookie).finish())
+    Ok(response)
 }
 
-/// Pipe for creating an user
+/// Pipe for creating an user, registered users are [authors](Role::Author).
+/// Administrators are created and promoted with the `blogsite admin` command
 /// - url: `{domain}/user`
 ///
 /// # HTTP request requirements
 /// ## body
 /// - json formatted string containing `username` and `password` keys
-/// - `password` must be at least 10 characters long
+/// - `password` must follow the [password policy](crate::auth::password_policy::check_password)
+/// - optional `email` key, a verification link is mailed to the address.
+/// The user is still created if the mail can't be sent, a new link can be requested by setting the address again
 ///
 /// # Example
 /// ```
@@ -110,7 +188,7 @@ pub async fn login(
 /// # Response
 /// ## Ok
 /// ## Error
-/// - Bad request
+/// - Bad request, with a json formatted string containing the `reasons` if the password was rejected
 #[post("/user")]
 pub async fn create_new_user(
     req_body: String,
@@ -122,25 +200,52 @@ pub async fn create_new_user(
 
     user.password = user.password.trim().to_string();
 
-    if user.password.len() < 10 {
-        return Err(AppError::BadRequest);
-    }
+    check_password(
+        &app_state.password_config,
+        &user.password,
+        Some(&user.username),
+    )
+    .await?;
     if User::find_by_username(Some(&conn), &user.username).is_some() {
         return Err(AppError::BadRequest);
     }
+    let email = match &user.email {
+        Some(email) => Some(check_new_email(&app_state, email, None)?),
+        None => None,
+    };
 
-    let _final_user = User::new(Some(&conn), &user.username, &digest(user.password), true);
+    let pw_hash = hash_password(&user.password)?;
+    //Either the user is created with the address or not at all, e.g. if another user took the address meanwhile
+    let new_user = conn.transaction::<_, AppError, _>(|| {
+        let mut new_user = User::new(Some(&conn), &user.username, &pw_hash, Role::Author)?;
+        if let Some(email) = &email {
+            new_user.set_email(Some(&conn), Some(email))?;
+        }
+        Ok(new_user)
+    })?;
+
+    //The account exists at this point, a failed mail is logged and the user can request another link
+    if new_user.email.is_some() {
+        if let Err(err) = send_verification(&app_state, &new_user) {
+            log::warn!(
+                "Verification mail for user '{}' could not be sent: {}",
+                new_user.id,
+                err
+            );
+        }
+    }
 
     Ok(HttpResponse::Ok().finish())
 }
 
-/// Pipe for deleting an user
+/// Pipe for deleting an user, users may delete their own account and administrators any account
 /// - url: `{domain}/user/{username}`
 ///
 /// # HTTP request requirements
 /// - `{username}` value as parameter
 /// ## header
-/// - cookie named `token` containing login token
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+/// - `X-CSRF-Token` header repeating the `csrf_token` cookie, if authenticated with the cookie
 ///
 /// # Example
 /// ```
@@ -154,42 +259,85 @@ pub async fn create_new_user(
 /// # Response
 /// ## Ok
 /// ## Error
-/// - Bad request
 /// - Unauthorized
+/// - Forbidden
+/// - Bad request if the user doesn't exist
 #[delete("/user/{username}")]
 pub async fn delete_an_user(
     req: HttpRequest,
+    auth: AuthenticatedUser,
     app_state: Data<AppState>,
 ) -> Result<HttpResponse, AppError> {
     let username = req.match_info().query("username").to_string();
-    let token = req
-        .cookie("token")
-        .ok_or(AppError::BadRequest)?
-        .value()
-        .to_string();
-    let mut redis_conn = app_state.redis_pool.clone().get().unwrap();
-    let user_id = Token::find(&mut redis_conn, &token)?;
 
     let conn = app_state.psql_pool.clone().get().unwrap();
-    let user = User::find_by_id(Some(&conn), &user_id)?;
-    if user.username != username {
-        return Err(AppError::Forbidden);
-    }
+    let user = match auth.user.username == username {
+        true => auth.user.clone(),
+        false => User::find_by_username(Some(&conn), &username).ok_or(AppError::BadRequest)?,
+    };
+    authorize(&conn, &auth, Action::DeleteUser, Resource::User(&user))?;
 
-    Token::delete(&mut redis_conn, &token);
+    revoke_user_sessions(&app_state, &user.id)?;
     user.delete(Some(&conn));
+    audit::record(
+        &app_state,
+        Some(&req),
+        AuthEventKind::AccountDeletion,
+        Some(&user.id),
+        Some(&user.username),
+    );
 
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Pipe for listing the latest authentication events of the logged in user,
+/// like logins, failed logins, logouts and password changes
+/// - url: `{domain}/user/events`
+///
+/// # HTTP request requirements
+/// ## header
+/// - cookie named `token` or `Authorization: Bearer` header containing login token
+///
+/// # Example
+/// ```
+/// let cookie = CookieBuilder::new("token", "test_token").finish();
+/// let request = actix_web::test::TestRequest::get()
+///     .uri("localhost/user/events")
+///     .cookie(cookie)
+///     .to_request();
+/// ```
+///
+/// # Response
+/// ## Ok
+/// - json formatted list of the latest 50 events, newest first,
+/// containing `id`, `user_id`, `username`, `event`, `ip`, `user_agent` and `created_at` keys
+/// ## Error
+/// - Unauthorized
+/// - Forbidden if authenticated with an API key
+/// - Internal server error
+#[get("/user/events")]
+pub async fn get_auth_events(
+    SessionUser(auth): SessionUser,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AppError> {
+    let conn = app_state
+        .psql_pool
+        .get()
+        .map_err(|_| AppError::InternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(AuthEvent::find_by_user(&conn, &auth.user.id, 50)?))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use actix_web::{test, test::call_service, App};
+    use crate::auth::{store::MemoryTokenStore, token::Token};
+    use actix_web::{cookie::Cookie, test, test::call_service, App};
+    use std::sync::Arc;
 
     #[actix_rt::test]
     async fn test_user_login() {
-        let appstate = AppState::new(None);
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
 
         let app = test::init_service(
             App::new()
@@ -210,18 +358,231 @@ mod tests {
         debug_assert!(resp.status().is_success());
 
         let cookie = resp.headers().get("set-cookie");
-        debug_assert!(cookie != None);
+        debug_assert!(cookie.is_some());
 
         let token = std::str::from_utf8(cookie.unwrap().as_bytes()).unwrap();
         Token::delete(
-            &mut appstate.redis_pool.get().unwrap(),
-            &String::from(Cookie::parse(token).unwrap().value()),
+            appstate.token_store.as_ref(),
+            &Token::key(
+                &appstate.session_config,
+                Cookie::parse(token).unwrap().value(),
+            ),
         );
     }
 
+    #[actix_rt::test]
+    async fn test_user_login_bearer() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(super::login),
+        )
+        .await;
+
+        let payload =
+            "{ \"username\": \"marko13\", \"password\": \"gaser_marko\", \"return_token\": true }";
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .insert_header(actix_web::http::header::ContentType::json())
+            .set_payload(payload)
+            .to_request();
+
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+        debug_assert!(resp.headers().get("set-cookie").is_none());
+
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let data: Value = serde_json::from_slice(&body).unwrap();
+        let token = data.get("token").unwrap().as_str().unwrap().to_string();
+
+        let store = appstate.token_store.as_ref();
+        let config = &appstate.session_config;
+        debug_assert!(Token::find(store, &token, config).is_ok());
+        Token::delete(store, &Token::key(config, &token));
+    }
+
+    #[actix_rt::test]
+    async fn test_login_throttle() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(super::login),
+        )
+        .await;
+
+        let payload = "{ \"username\": \"Test throttled user\", \"password\": \"wrong_password\"}";
+        for _ in 0..appstate.throttle_config.max_failures - 1 {
+            let req = test::TestRequest::get()
+                .uri("/user")
+                .set_payload(payload)
+                .to_request();
+            let resp = call_service(&app, req).await;
+            debug_assert!(resp.status() == actix_web::http::StatusCode::UNAUTHORIZED);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .set_payload(payload)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        debug_assert!(resp.headers().get("retry-after").is_some());
+
+        LoginThrottle::reset(appstate.token_store.as_ref(), "Test throttled user").unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_registration_role() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(super::create_new_user),
+        )
+        .await;
+
+        let payload = "{ \"username\": \"Test registered user\", \"password\": \"test_password\"}";
+        let req = test::TestRequest::post()
+            .uri("/user")
+            .set_payload(payload)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status().is_success());
+
+        //Self-registered accounts are never administrators
+        let conn = appstate.psql_pool.get().unwrap();
+        let user =
+            User::find_by_username(Some(&conn), &"Test registered user".to_string()).unwrap();
+        debug_assert!(user.role == Role::Author.as_str());
+        user.delete(Some(&conn));
+    }
+
+    #[actix_rt::test]
+    async fn test_registration_password_policy() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(super::create_new_user),
+        )
+        .await;
+
+        let payload = "{ \"username\": \"test_policy_user\", \"password\": \"Test_Policy_User\"}";
+        let req = test::TestRequest::post()
+            .uri("/user")
+            .set_payload(payload)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let data: Value = serde_json::from_slice(&body).unwrap();
+        debug_assert!(data["error"] == "password_rejected");
+        debug_assert!(data["reasons"][0]["reason"] == "contains_username");
+
+        let conn = appstate.psql_pool.get().unwrap();
+        debug_assert!(
+            User::find_by_username(Some(&conn), &"test_policy_user".to_string()).is_none()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_auth_events() {
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(appstate.clone()))
+                .service(super::login)
+                .service(super::get_auth_events)
+                .service(crate::routes::admin::query_auth_events),
+        )
+        .await;
+
+        let conn = appstate.psql_pool.get().unwrap();
+        let usr = User::new(
+            Some(&conn),
+            &String::from("Test audited user"),
+            &hash_password("test_password").unwrap(),
+            Role::Author,
+        )
+        .unwrap();
+        let admin = User::new(
+            Some(&conn),
+            &String::from("Test audit admin"),
+            &hash_password("test_password").unwrap(),
+            Role::Admin,
+        )
+        .unwrap();
+
+        for password in ["wrong_password", "test_password"] {
+            let req = test::TestRequest::get()
+                .uri("/user")
+                .insert_header(("User-Agent", "audit-test"))
+                .set_payload(format!(
+                    "{{ \"username\": \"Test audited user\", \"password\": \"{}\", \"return_token\": true }}",
+                    password
+                ))
+                .to_request();
+            call_service(&app, req).await;
+        }
+
+        let token = Token::new(
+            appstate.token_store.as_ref(),
+            &usr.id,
+            &appstate.session_config,
+        );
+        let req = test::TestRequest::get()
+            .uri("/user/events")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let events: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        debug_assert!(kinds == ["login_success", "login_failure"]);
+        debug_assert!(events[0]["user_agent"] == "audit-test");
+
+        //Only administrators can search every event
+        let uri = format!(
+            "/api/admin/auth_events?user_id={}&event=login_failure",
+            usr.id
+        );
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        debug_assert!(resp.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let admin_token = Token::new(
+            appstate.token_store.as_ref(),
+            &admin.id,
+            &appstate.session_config,
+        );
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+            .to_request();
+        let events: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+        debug_assert!(events.len() == 1);
+        debug_assert!(events[0]["username"] == "Test audited user");
+
+        Token::delete_by_user(appstate.token_store.as_ref(), &usr.id);
+        usr.delete(Some(&conn));
+        admin.delete(Some(&conn));
+    }
+
     //#[actix_rt::test]
     async fn test_user_create() {
-        let appstate = AppState::new(None);
+        let appstate = AppState::with_token_store(None, Arc::new(MemoryTokenStore::new()));
 
         let app = test::init_service(
             App::new()
@@ -231,7 +592,7 @@ mod tests {
         )
         .await;
 
-        let payload = "{ \"username\": \"Test_user123\", \"password\": \"test_user123\"}";
+        let payload = "{ \"username\": \"Test_user123\", \"password\": \"secret_pass_123\"}";
         let req = test::TestRequest::post()
             .uri("/user")
             .insert_header(actix_web::http::header::ContentType::json())
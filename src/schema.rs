@@ -1,3 +1,29 @@
+table! {
+    api_keys (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        name -> Varchar,
+        prefix -> Varchar,
+        key_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    auth_events (id) {
+        id -> Varchar,
+        user_id -> Nullable<Varchar>,
+        username -> Nullable<Varchar>,
+        event -> Varchar,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     blogs (id) {
         id -> Int4,
@@ -21,6 +47,20 @@ table! {
     }
 }
 
+table! {
+    impersonations (id) {
+        id -> Varchar,
+        admin_id -> Nullable<Varchar>,
+        admin_username -> Varchar,
+        user_id -> Nullable<Varchar>,
+        username -> Varchar,
+        reason -> Nullable<Text>,
+        ip -> Nullable<Varchar>,
+        started_at -> Timestamptz,
+        ended_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     likes (user_id, blog_id) {
         user_id -> Varchar,
@@ -28,19 +68,95 @@ table! {
     }
 }
 
+table! {
+    recovery_codes (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        code_hash -> Varchar,
+    }
+}
+
+table! {
+    oidc_identities (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        issuer -> Varchar,
+        subject -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    permissions (name) {
+        name -> Varchar,
+    }
+}
+
+table! {
+    remembered_devices (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        selector -> Varchar,
+        validator_hash -> Varchar,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        last_used_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    role_permissions (role, permission) {
+        role -> Varchar,
+        permission -> Varchar,
+    }
+}
+
+table! {
+    roles (name) {
+        name -> Varchar,
+    }
+}
+
 table! {
     users (id) {
         id -> Varchar,
         username -> Varchar,
         pass -> Varchar,
-        is_admin -> Bool,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        role -> Varchar,
+        email -> Nullable<Varchar>,
+        email_verified -> Bool,
     }
 }
 
+joinable!(api_keys -> users (user_id));
 joinable!(blogs -> users (created_by));
 joinable!(comments -> blogs (blog_id));
 joinable!(comments -> users (user_id));
 joinable!(likes -> blogs (blog_id));
 joinable!(likes -> users (user_id));
+joinable!(oidc_identities -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(remembered_devices -> users (user_id));
+joinable!(role_permissions -> permissions (permission));
+joinable!(role_permissions -> roles (role));
+joinable!(users -> roles (role));
 
-allow_tables_to_appear_in_same_query!(blogs, comments, likes, users,);
+allow_tables_to_appear_in_same_query!(
+    api_keys,
+    auth_events,
+    blogs,
+    comments,
+    impersonations,
+    likes,
+    oidc_identities,
+    permissions,
+    recovery_codes,
+    remembered_devices,
+    role_permissions,
+    roles,
+    users,
+);